@@ -0,0 +1,593 @@
+use std::{env, fs};
+
+use atm_raytracer::{generator::params::Params, terrain::Terrain};
+use atm_refraction::air::{atmosphere::vertical_profile::FunctionDef, Atmosphere, AtmosphereDef};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rayon::prelude::*;
+use serde_yaml::{Mapping, Value};
+
+use crate::line_of_sight::find_ray_elev;
+
+pub const SUBCOMMAND: &str = "fit-atmosphere";
+
+const GRID_POINTS: usize = 41;
+const GRADIENT_EPSILON: f64 = 1e-6;
+const DEFAULT_AZIMUTH_TOLERANCE: f64 = 1.0;
+
+/// The observed direction of a known summit. The observations file is a YAML list of them:
+///
+/// ```yaml
+/// - azimuth: 152.3
+///   elevation: 0.41
+///   latitude: 49.179
+///   longitude: 20.088
+///   altitude: 2499.0 # optional
+/// ```
+///
+/// The azimuth is only used to check that the observation matches the summit at the given
+/// coordinates, which determine the distance to it.
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct Observation {
+    /// The observed azimuth in degrees.
+    azimuth: f64,
+    /// The observed elevation angle in degrees.
+    elevation: f64,
+    latitude: f64,
+    longitude: f64,
+    /// The altitude of the summit; taken from the terrain if not given.
+    #[serde(default)]
+    altitude: Option<f64>,
+}
+
+/// The position of an observed summit relative to the observer.
+struct Summit {
+    distance: f64,
+    altitude: f64,
+}
+
+struct Fit {
+    gradient: f64,
+    atmosphere: AtmosphereDef,
+    computed: Vec<f64>,
+}
+
+/// Builds an atmosphere with the given temperature gradient below `layer_top` and the temperature
+/// profile of `base` above it. Unless the upper profile fixes the temperatures on its own, the
+/// temperature at the observer's altitude is kept as in `base`.
+fn atmosphere_with_gradient(
+    base: &AtmosphereDef,
+    gradient: f64,
+    layer_top: f64,
+    observer_alt: f64,
+) -> Result<AtmosphereDef, String> {
+    let temperature = Atmosphere::from_def(base.clone()).temperature(observer_alt);
+
+    let mut def = serde_yaml::to_value(base).map_err(|err| err.to_string())?;
+    let next_functions = field(&def, "next_functions")?
+        .as_sequence()
+        .ok_or_else(|| unexpected_def("`next_functions` is not a list"))?
+        .iter()
+        .map(|function| {
+            let altitude = field(function, "altitude")?
+                .as_f64()
+                .ok_or_else(|| unexpected_def("the `altitude` of a function is not a number"))?;
+            Ok((altitude, field(function, "function")?.clone()))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    // the function in effect at the top of the layer continues above it
+    let function_at_top = match next_functions
+        .iter()
+        .rev()
+        .find(|(altitude, _)| *altitude <= layer_top)
+    {
+        Some((_, function)) => function.clone(),
+        None => field(&def, "first_temperature_function")?.clone(),
+    };
+
+    let upper_functions: Vec<_> = std::iter::once((layer_top, function_at_top))
+        .chain(
+            next_functions
+                .into_iter()
+                .filter(|(altitude, _)| *altitude > layer_top),
+        )
+        .collect();
+    let has_spline = upper_functions
+        .iter()
+        .any(|(_, function)| function.get("Spline").is_some());
+    let upper_functions = upper_functions
+        .into_iter()
+        .map(|(altitude, function)| {
+            let mut mapping = Mapping::new();
+            mapping.insert(key("altitude"), Value::from(altitude));
+            mapping.insert(key("function"), function);
+            Value::Mapping(mapping)
+        })
+        .collect();
+
+    let first_function = serde_yaml::to_value(FunctionDef::Linear { gradient })
+        .map_err(|err| format!("failed to build the atmosphere definition: {}", err))?;
+    let fixed_point = if has_spline {
+        Value::Null
+    } else {
+        let mut fixed_point = Mapping::new();
+        fixed_point.insert(key("altitude"), Value::from(observer_alt));
+        fixed_point.insert(key("temperature"), Value::from(temperature));
+        Value::Mapping(fixed_point)
+    };
+    let mapping = def
+        .as_mapping_mut()
+        .ok_or_else(|| unexpected_def("it is not a mapping"))?;
+    mapping.insert(key("first_temperature_function"), first_function);
+    mapping.insert(key("next_functions"), Value::Sequence(upper_functions));
+    mapping.insert(key("temperature_fixed_point"), fixed_point);
+
+    serde_yaml::from_value(def)
+        .map_err(|err| format!("failed to build the atmosphere definition: {}", err))
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_owned())
+}
+
+fn unexpected_def(reason: &str) -> String {
+    format!("unexpected form of the atmosphere definition: {}", reason)
+}
+
+/// Returns the field `name` of a YAML mapping, or an error if there is no such field.
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+    value
+        .as_mapping()
+        .and_then(|mapping| mapping.get(&key(name)))
+        .ok_or_else(|| unexpected_def(&format!("no `{}`", name)))
+}
+
+/// Finds the summit of an observation, checking that its azimuth is within `azimuth_tolerance`
+/// (in degrees) of the observed one.
+fn locate_summit(
+    params: &Params,
+    terrain: &Terrain,
+    obs: &Observation,
+    azimuth_tolerance: f64,
+) -> Result<Summit, String> {
+    let (azimuth, distance) = params
+        .model
+        .direction_to(
+            (
+                params.view.position.latitude,
+                params.view.position.longitude,
+            ),
+            (obs.latitude, obs.longitude),
+        )
+        .ok_or_else(|| {
+            format!(
+                "couldn't find the direction to the summit at {}, {}",
+                obs.latitude, obs.longitude
+            )
+        })?;
+    let azimuth_diff = (obs.azimuth - azimuth + 180.0).rem_euclid(360.0) - 180.0;
+    if azimuth_diff.abs() > azimuth_tolerance {
+        return Err(format!(
+            "the summit at {}, {} lies at the azimuth {:.2}°, but it was observed at {}°",
+            obs.latitude, obs.longitude, azimuth, obs.azimuth
+        ));
+    }
+    let altitude = obs
+        .altitude
+        .unwrap_or_else(|| terrain.get_elev(obs.latitude, obs.longitude).unwrap_or(0.0));
+    Ok(Summit { distance, altitude })
+}
+
+/// Calculates the apparent elevation angles of the summits in the given atmosphere.
+fn compute_elevations(
+    params: &Params,
+    terrain: &Terrain,
+    observations: &[Observation],
+    summits: &[Summit],
+    atmosphere: &AtmosphereDef,
+) -> Result<Vec<f64>, String> {
+    let mut params = params.clone();
+    params.env.atmosphere = Atmosphere::from_def(atmosphere.clone());
+
+    observations
+        .par_iter()
+        .zip(summits)
+        .map(|(obs, summit)| {
+            let mut params = params.clone();
+            params.view.frame.max_distance = summit.distance + 2.0 * params.simulation_step;
+            find_ray_elev(&params, terrain, summit.distance, summit.altitude).ok_or_else(|| {
+                format!(
+                    "no ray from the observer reaches the summit at {}, {}",
+                    obs.latitude, obs.longitude
+                )
+            })
+        })
+        .collect()
+}
+
+fn sum_of_squares(observations: &[Observation], computed: &[f64]) -> f64 {
+    observations
+        .iter()
+        .zip(computed)
+        .map(|(obs, elev)| (obs.elevation - elev).powi(2))
+        .sum()
+}
+
+/// Evaluates `error` at `GRID_POINTS` gradients `grid_step` apart, starting from `min_gradient`,
+/// and returns the index of the one with the smallest error. The gradients at which `error` fails
+/// are skipped; an error is only returned if it fails at all of them.
+fn best_grid_point<F>(min_gradient: f64, grid_step: f64, error: F) -> Result<usize, String>
+where
+    F: Fn(f64) -> Result<f64, String>,
+{
+    let mut best: Option<(usize, f64)> = None;
+    let mut last_err = None;
+    for index in 0..GRID_POINTS {
+        match error(min_gradient + index as f64 * grid_step) {
+            Ok(error) if best.is_none_or(|(_, best_error)| error < best_error) => {
+                best = Some((index, error));
+            }
+            Ok(_) => (),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    best.map(|(index, _)| index).ok_or_else(|| {
+        format!(
+            "the rays couldn't be traced with any of the gradients: {}",
+            last_err.unwrap_or_default()
+        )
+    })
+}
+
+fn fit(
+    params: &Params,
+    terrain: &Terrain,
+    base: &AtmosphereDef,
+    observations: &[Observation],
+    gradient_range: (f64, f64),
+    layer_top: f64,
+    azimuth_tolerance: f64,
+) -> Result<Fit, String> {
+    let observer_alt = params.view.position.abs_altitude(terrain);
+
+    let summits = observations
+        .iter()
+        .map(|obs| locate_summit(params, terrain, obs, azimuth_tolerance))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let eval = |gradient: f64| -> Result<(f64, Fit), String> {
+        let atmosphere = atmosphere_with_gradient(base, gradient, layer_top, observer_alt)?;
+        let computed = compute_elevations(params, terrain, observations, &summits, &atmosphere)
+            .map_err(|err| format!("{} (with the gradient {} K/m)", err, gradient))?;
+        let error = sum_of_squares(observations, &computed);
+        Ok((
+            error,
+            Fit {
+                gradient,
+                atmosphere,
+                computed,
+            },
+        ))
+    };
+
+    // a coarse scan first, as the error doesn't need to have a single minimum in the whole range
+    let (min_gradient, max_gradient) = gradient_range;
+    let grid_step = (max_gradient - min_gradient) / (GRID_POINTS - 1) as f64;
+    let best_index = best_grid_point(min_gradient, grid_step, |gradient| {
+        eval(gradient).map(|(error, _)| error)
+    })?;
+
+    // then a golden section search around the best grid point; the gradients at which the rays
+    // can't be traced are treated as the worst fit
+    let error_at = |gradient| eval(gradient).map_or(f64::INFINITY, |(error, _)| error);
+    let inv_phi = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let mut a = min_gradient + best_index.saturating_sub(1) as f64 * grid_step;
+    let mut b = min_gradient + (best_index + 1).min(GRID_POINTS - 1) as f64 * grid_step;
+    let mut c = b - (b - a) * inv_phi;
+    let mut d = a + (b - a) * inv_phi;
+    let mut error_c = error_at(c);
+    let mut error_d = error_at(d);
+    while b - a > GRADIENT_EPSILON {
+        if error_c < error_d {
+            b = d;
+            d = c;
+            error_d = error_c;
+            c = b - (b - a) * inv_phi;
+            error_c = error_at(c);
+        } else {
+            a = c;
+            c = d;
+            error_c = error_d;
+            d = a + (b - a) * inv_phi;
+            error_d = error_at(d);
+        }
+    }
+
+    eval((a + b) / 2.0).map(|(_, fit)| fit)
+}
+
+pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
+    let filename = matches
+        .value_of("input")
+        .expect("please provide an input file");
+
+    let observations_file = matches
+        .value_of("observations")
+        .expect("please provide a file with observations");
+
+    let min_gradient: f64 = matches
+        .value_of("min_gradient")
+        .unwrap_or("-0.05")
+        .parse()
+        .map_err(|_| "please provide a valid minimum gradient")?;
+
+    let max_gradient: f64 = matches
+        .value_of("max_gradient")
+        .unwrap_or("0.2")
+        .parse()
+        .map_err(|_| "please provide a valid maximum gradient")?;
+
+    if min_gradient.is_nan() || max_gradient.is_nan() || min_gradient >= max_gradient {
+        return Err("the minimum gradient must be smaller than the maximum one".to_owned());
+    }
+
+    let azimuth_tolerance: f64 = match matches.value_of("azimuth_tolerance") {
+        Some(tolerance) => tolerance
+            .parse()
+            .ok()
+            .filter(|tolerance: &f64| *tolerance >= 0.0)
+            .ok_or("please provide a valid azimuth tolerance")?,
+        None => DEFAULT_AZIMUTH_TOLERANCE,
+    };
+
+    let observations: Vec<Observation> = serde_yaml::from_str(
+        &fs::read_to_string(observations_file)
+            .map_err(|err| format!("couldn't read {:?}: {}", observations_file, err))?,
+    )
+    .map_err(|err| format!("couldn't parse {:?}: {}", observations_file, err))?;
+
+    if observations.is_empty() {
+        return Err("no observations to fit to".to_owned());
+    }
+
+    let config = atm_raytracer::generator::params::parse_config(filename);
+    let base = config.atmosphere.clone();
+
+    let mut terrain_folder = env::current_dir().unwrap();
+    terrain_folder.push(config.terrain_folder());
+
+    let terrain = Terrain::from_folder(terrain_folder);
+
+    let params = config.into_params(&terrain)?;
+
+    let layer_top: f64 = match matches.value_of("layer_top") {
+        Some(top) => top
+            .parse()
+            .map_err(|_| "please provide a valid layer top altitude")?,
+        None => params.view.position.abs_altitude(&terrain) + 500.0,
+    };
+
+    let result = fit(
+        &params,
+        &terrain,
+        &base,
+        &observations,
+        (min_gradient, max_gradient),
+        layer_top,
+        azimuth_tolerance,
+    )?;
+
+    let mut output = format!(
+        "# best fit: gradient {} K/m below {} m\n",
+        result.gradient, layer_top
+    );
+    output.push_str(&serde_yaml::to_string(&result.atmosphere).map_err(|err| err.to_string())?);
+    output.push_str("\n# azimuth\tlatitude\tlongitude\tobserved\tcomputed\tresidual\n");
+    for (obs, elev) in observations.iter().zip(&result.computed) {
+        output.push_str(&format!(
+            "# {}\t{}\t{}\t{}\t{}\t{}\n",
+            obs.azimuth,
+            obs.latitude,
+            obs.longitude,
+            obs.elevation,
+            elev,
+            obs.elevation - elev
+        ));
+    }
+    let rms = (sum_of_squares(&observations, &result.computed) / observations.len() as f64).sqrt();
+    output.push_str(&format!("# RMS residual: {}\n", rms));
+
+    if let Some(output_file) = matches.value_of("output") {
+        fs::write(output_file, &output)
+            .map_err(|err| format!("couldn't write {:?}: {}", output_file, err))?;
+    }
+    print!("{}", output);
+
+    Ok(())
+}
+
+pub fn subcommand_def() -> App<'static, 'static> {
+    SubCommand::with_name(SUBCOMMAND)
+        .about(
+            "Find the temperature gradient that best reproduces the observed elevation angles of \
+            known summits",
+        )
+        .setting(AppSettings::AllowLeadingHyphen)
+        .arg(
+            Arg::with_name("input")
+                .help("Path to the input file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("observations")
+                .short("o")
+                .long("observations")
+                .value_name("FILE")
+                .help(
+                    "Path to a YAML file with a list of observations, each with the observed \
+                    `azimuth` and `elevation` angle of a summit in degrees, the `latitude` and \
+                    `longitude` of the summit, and optionally its `altitude` (default: from the \
+                    terrain)",
+                )
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("azimuth_tolerance")
+                .long("azimuth-tolerance")
+                .value_name("DEGREES")
+                .help(
+                    "Maximum difference between the observed azimuth of a summit and the one \
+                    calculated from its coordinates (default: 1)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min_gradient")
+                .short("a")
+                .long("min-gradient")
+                .value_name("K/M")
+                .help("Lower boundary of the searched temperature gradients (default: -0.05)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_gradient")
+                .short("b")
+                .long("max-gradient")
+                .value_name("K/M")
+                .help("Upper boundary of the searched temperature gradients (default: 0.2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("layer_top")
+                .short("l")
+                .long("layer-top")
+                .value_name("METERS")
+                .help(
+                    "Altitude up to which the fitted gradient applies; the temperature profile \
+                    from the input file is used above it (default: 500 m above the observer)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("Additionally save the best-fit atmosphere definition to a file")
+                .takes_value(true),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use atm_raytracer::generator::params::{Altitude, Config, Position};
+
+    #[test]
+    fn test_fit_gradient() {
+        let terrain = Terrain::new();
+        let mut config = Config::default();
+        config.view.position = Position {
+            latitude: 50.0,
+            longitude: 20.0,
+            altitude: Altitude::Absolute(10.0),
+        };
+        let params = config.into_params(&terrain).unwrap();
+        let base = AtmosphereDef::us_76();
+        let layer_top = 510.0;
+
+        // the elevation angles of the summits in an atmosphere with an inversion
+        let gradient = 0.02;
+        let mut observations: Vec<_> = [(50.1, 50.0), (50.2, 150.0), (50.35, 300.0)]
+            .iter()
+            .map(|&(latitude, altitude)| Observation {
+                azimuth: 0.0,
+                latitude,
+                longitude: 20.0,
+                altitude: Some(altitude),
+                elevation: 0.0,
+            })
+            .collect();
+        let summits = observations
+            .iter()
+            .map(|obs| locate_summit(&params, &terrain, obs, DEFAULT_AZIMUTH_TOLERANCE))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let atmosphere = atmosphere_with_gradient(&base, gradient, layer_top, 10.0).unwrap();
+        let elevations =
+            compute_elevations(&params, &terrain, &observations, &summits, &atmosphere).unwrap();
+        for (obs, elevation) in observations.iter_mut().zip(elevations) {
+            obs.elevation = elevation;
+        }
+
+        let result = fit(
+            &params,
+            &terrain,
+            &base,
+            &observations,
+            (-0.05, 0.2),
+            layer_top,
+            DEFAULT_AZIMUTH_TOLERANCE,
+        )
+        .unwrap();
+        assert!(
+            (result.gradient - gradient).abs() < 1e-3,
+            "{}",
+            result.gradient
+        );
+        assert!(sum_of_squares(&observations, &result.computed) < 1e-8);
+    }
+
+    /// The observed azimuths should be checked against the directions to the summits.
+    #[test]
+    fn test_observed_azimuth() {
+        let terrain = Terrain::new();
+        let mut config = Config::default();
+        config.view.position = Position {
+            latitude: 50.0,
+            longitude: 20.0,
+            altitude: Altitude::Absolute(10.0),
+        };
+        let params = config.into_params(&terrain).unwrap();
+
+        let observation = |azimuth| Observation {
+            azimuth,
+            elevation: 0.0,
+            latitude: 50.1,
+            longitude: 20.0,
+            altitude: Some(100.0),
+        };
+        for azimuth in [0.0, 0.5, 359.5] {
+            let summit = locate_summit(&params, &terrain, &observation(azimuth), 1.0).unwrap();
+            assert!(
+                (summit.distance - 11_120.0).abs() < 10.0,
+                "{}",
+                summit.distance
+            );
+        }
+        for azimuth in [1.5, 180.0, 358.0] {
+            assert!(locate_summit(&params, &terrain, &observation(azimuth), 1.0).is_err());
+        }
+        assert!(locate_summit(&params, &terrain, &observation(1.5), 2.0).is_ok());
+    }
+
+    /// The grid points at which the rays can't be traced should be skipped.
+    #[test]
+    fn test_best_grid_point() {
+        // the error has its minimum at 0.3, but fails at the low end of the range
+        let error = |gradient: f64| {
+            if gradient < 0.1 {
+                Err(format!("failed at {}", gradient))
+            } else {
+                Ok((gradient - 0.3).powi(2))
+            }
+        };
+        let best = best_grid_point(-0.4, 0.025, error).unwrap();
+        assert!((-0.4 + best as f64 * 0.025 - 0.3).abs() < 1e-9);
+
+        let err = best_grid_point(-0.4, 0.01, error).unwrap_err();
+        assert!(err.contains("failed at"), "{}", err);
+    }
+}
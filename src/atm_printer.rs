@@ -28,7 +28,7 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
 
     let celsius = matches.is_present("celsius");
 
-    let config = crate::generator::params::parse_config(filename);
+    let config = atm_raytracer::generator::params::parse_config(filename);
 
     let atmosphere = Atmosphere::from_def(config.atmosphere.clone());
 
@@ -0,0 +1,238 @@
+use image::Rgb;
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use super::{ColoringMethod, Legend};
+
+use crate::{
+    generator::TracePoint,
+    utils::{decode_srgb, srgb_to_linear, vec3_to_rgb, EarthModel},
+};
+
+/// The number of colors in the legend bar of a false color scale.
+const LEGEND_COLORS: usize = 256;
+/// The width of the grid lines, as a fraction of the grid spacing.
+const GRID_LINE_WIDTH: f64 = 0.04;
+
+const LATITUDE_COLOR: [u8; 3] = [220, 40, 40];
+const LONGITUDE_COLOR: [u8; 3] = [40, 90, 230];
+
+/// A quantity mapped onto a color scale.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FalseColorQuantity {
+    /// The distance from the observer in kilometers.
+    Distance,
+    /// The slope of the terrain in degrees.
+    Slope,
+    /// The difference between the length of the light path and the distance, in meters.
+    RefractionExcess,
+}
+
+impl FalseColorQuantity {
+    fn title(&self) -> &'static str {
+        match self {
+            FalseColorQuantity::Distance => "Distance [km]",
+            FalseColorQuantity::Slope => "Slope [°]",
+            FalseColorQuantity::RefractionExcess => "Path length - distance [m]",
+        }
+    }
+
+    fn value(&self, model: &EarthModel, pixel: &TracePoint) -> f64 {
+        match self {
+            FalseColorQuantity::Distance => pixel.distance / 1000.0,
+            FalseColorQuantity::Slope => {
+                let (_, _, dir_up) = model.world_directions(pixel.lat, pixel.lon);
+                pixel
+                    .normal
+                    .dot(&dir_up)
+                    .clamp(-1.0, 1.0)
+                    .acos()
+                    .to_degrees()
+            }
+            FalseColorQuantity::RefractionExcess => pixel.path_length - pixel.distance,
+        }
+    }
+}
+
+/// A polynomial approximation of the viridis color map (in sRGB), for `t` between 0 and 1.
+fn color_scale(t: f64) -> Vector3<f64> {
+    const COEFFS: [[f64; 3]; 7] = [
+        [0.277_727_327, 0.005_407_345, 0.334_099_805],
+        [0.105_093_043, 1.404_613_530, 1.384_590_163],
+        [-0.330_861_829, 0.214_847_559, 0.095_095_163],
+        [-4.634_230_499, -5.799_100_973, -19.332_440_956],
+        [6.228_269_936, 14.179_933_367, 56.690_552_601],
+        [4.776_384_998, -13.745_145_378, -65.353_032_633],
+        [-5.435_455_856, 4.645_852_612, 26.312_435_250],
+    ];
+    let t = t.clamp(0.0, 1.0);
+    COEFFS
+        .iter()
+        .rev()
+        .fold(Vector3::zeros(), |acc, c| {
+            acc * t + Vector3::new(c[0], c[1], c[2])
+        })
+        .map(|c| c.clamp(0.0, 1.0))
+}
+
+/// Colors the pixels by the value of a quantity between `min` and `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct FalseColors {
+    quantity: FalseColorQuantity,
+    min: f64,
+    max: f64,
+    model: EarthModel,
+}
+
+impl FalseColors {
+    pub fn new(quantity: FalseColorQuantity, min: f64, max: f64, model: EarthModel) -> Self {
+        Self {
+            quantity,
+            min,
+            max,
+            model,
+        }
+    }
+}
+
+impl ColoringMethod for FalseColors {
+    fn color_for_pixel(&self, pixel: &TracePoint) -> Vector3<f64> {
+        let value = self.quantity.value(&self.model, pixel);
+        color_scale((value - self.min) / (self.max - self.min)).map(srgb_to_linear)
+    }
+
+    fn sky_color(&self) -> Vector3<f64> {
+        decode_srgb([28, 28, 28])
+    }
+
+    fn fog_color(&self) -> Vector3<f64> {
+        decode_srgb([160, 160, 160])
+    }
+
+    fn legend(&self) -> Option<Legend> {
+        Some(Legend::Scale {
+            title: self.quantity.title().to_owned(),
+            min: self.min,
+            max: self.max,
+            colors: (0..LEGEND_COLORS)
+                .map(|i| vec3_to_rgb(color_scale(i as f64 / (LEGEND_COLORS - 1) as f64)))
+                .collect(),
+        })
+    }
+}
+
+/// Draws the lines of constant latitude and longitude every `spacing` degrees over the terrain,
+/// which gets darker with the distance.
+#[derive(Debug, Clone, Copy)]
+pub struct GridColors {
+    spacing: f64,
+    max_distance: f64,
+}
+
+impl GridColors {
+    pub fn new(spacing: f64, max_distance: f64) -> Self {
+        Self {
+            spacing,
+            max_distance,
+        }
+    }
+
+    /// Checks whether the coordinate is within a grid line.
+    fn on_line(&self, coord: f64) -> bool {
+        let frac = (coord / self.spacing).rem_euclid(1.0);
+        frac.min(1.0 - frac) < GRID_LINE_WIDTH / 2.0
+    }
+}
+
+impl ColoringMethod for GridColors {
+    fn color_for_pixel(&self, pixel: &TracePoint) -> Vector3<f64> {
+        if self.on_line(pixel.lat) {
+            decode_srgb(LATITUDE_COLOR)
+        } else if self.on_line(pixel.lon) {
+            decode_srgb(LONGITUDE_COLOR)
+        } else {
+            let brightness = 0.9 - 0.5 * (pixel.distance / self.max_distance).min(1.0);
+            Vector3::repeat(srgb_to_linear(brightness))
+        }
+    }
+
+    fn sky_color(&self) -> Vector3<f64> {
+        decode_srgb([28, 28, 28])
+    }
+
+    fn fog_color(&self) -> Vector3<f64> {
+        decode_srgb([160, 160, 160])
+    }
+
+    fn legend(&self) -> Option<Legend> {
+        Some(Legend::Swatches(vec![
+            (
+                Rgb(LATITUDE_COLOR),
+                format!("Latitude, every {}°", self.spacing),
+            ),
+            (
+                Rgb(LONGITUDE_COLOR),
+                format!("Longitude, every {}°", self.spacing),
+            ),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::encode_srgb;
+
+    #[test]
+    fn test_quantity_values() {
+        let model = EarthModel::SimpleSphere;
+        let (dir_north, _, dir_up) = model.world_directions(50.0, 20.0);
+        let flat = TracePoint {
+            path_length: 12_502.5,
+            ..TracePoint::test_point(50.0, 20.0, 12_500.0, dir_up)
+        };
+        let steep = TracePoint::test_point(50.0, 20.0, 12_500.0, (dir_up + dir_north).normalize());
+
+        assert_eq!(FalseColorQuantity::Distance.value(&model, &flat), 12.5);
+        assert!(FalseColorQuantity::Slope.value(&model, &flat).abs() < 1e-6);
+        assert!((FalseColorQuantity::Slope.value(&model, &steep) - 45.0).abs() < 1e-6);
+        assert_eq!(
+            FalseColorQuantity::RefractionExcess.value(&model, &flat),
+            2.5
+        );
+    }
+
+    #[test]
+    fn test_false_colors() {
+        let model = EarthModel::SimpleSphere;
+        let (_, _, dir_up) = model.world_directions(50.0, 20.0);
+        let colors = FalseColors::new(FalseColorQuantity::Distance, 10.0, 20.0, model);
+        let color_at = |distance| {
+            encode_srgb(
+                colors.color_for_pixel(&TracePoint::test_point(50.0, 20.0, distance, dir_up)),
+                0.0,
+            )
+        };
+
+        // the values outside of the range are clamped to its ends
+        let (low, high) = (color_at(10_000.0), color_at(20_000.0));
+        assert_eq!(color_at(5_000.0), low);
+        assert_eq!(color_at(30_000.0), high);
+        assert_eq!(color_at(15_000.0), vec3_to_rgb(color_scale(0.5)));
+
+        // the ends of the approximated viridis color map
+        let close = |a: Rgb<u8>, b: [u8; 3]| a.0.iter().zip(&b).all(|(a, b)| a.abs_diff(*b) <= 4);
+        assert!(close(low, [68, 1, 84]), "{:?}", low);
+        assert!(close(high, [253, 231, 37]), "{:?}", high);
+    }
+
+    #[test]
+    fn test_grid_lines() {
+        let grid = GridColors::new(0.5, 10_000.0);
+        assert!(grid.on_line(50.0));
+        assert!(grid.on_line(50.505));
+        assert!(grid.on_line(-20.495));
+        assert!(!grid.on_line(50.25));
+        assert!(!grid.on_line(50.02));
+    }
+}
@@ -1,17 +1,49 @@
+mod false_color;
+mod palette;
 mod shading;
 mod simple;
+mod sky;
+mod snow;
 
 use crate::generator::TracePoint;
 
 use image::Rgb;
+use nalgebra::Vector3;
 
 pub use self::{
-    shading::{ColorPalette, Shading},
+    false_color::{FalseColorQuantity, FalseColors, GridColors},
+    palette::ColorPalette,
+    shading::Shading,
     simple::SimpleColors,
+    sky::Sky,
+    snow::Snow,
 };
 
+/// A way of coloring the image. All the colors are RGB in linear light, so that they can be
+/// blended and shaded correctly; the renderer encodes them for the output.
 pub trait ColoringMethod {
-    fn color_for_pixel(&self, pixel: &TracePoint) -> Rgb<u8>;
-    fn sky_color(&self) -> Rgb<u8>;
-    fn fog_color(&self) -> Rgb<u8>;
+    fn color_for_pixel(&self, pixel: &TracePoint) -> Vector3<f64>;
+    fn sky_color(&self) -> Vector3<f64>;
+    /// Returns the color of the sky in the given direction (azimuth and elevation in degrees).
+    fn sky_color_at(&self, _azimuth: f64, _elevation: f64) -> Vector3<f64> {
+        self.sky_color()
+    }
+    fn fog_color(&self) -> Vector3<f64>;
+    /// Returns the legend explaining the colors, if the method needs one.
+    fn legend(&self) -> Option<Legend> {
+        None
+    }
+}
+
+/// A legend drawn on the image by the renderer; its colors are sRGB.
+pub enum Legend {
+    /// A color bar for the values from `min` to `max`.
+    Scale {
+        title: String,
+        min: f64,
+        max: f64,
+        colors: Vec<Rgb<u8>>,
+    },
+    /// Colors with their descriptions.
+    Swatches(Vec<(Rgb<u8>, String)>),
 }
@@ -0,0 +1,185 @@
+use image::Rgb;
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{decode_srgb, rgb_to_vec3, srgb_to_linear};
+
+/// A point of a custom color gradient: the color at the given elevation in meters.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ColorStop {
+    pub elevation: f64,
+    pub color: [u8; 3],
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum ColorPalette {
+    Legacy,
+    #[default]
+    Improved,
+    /// A gradient interpolated linearly between the stops, which have to be sorted by elevation.
+    Custom {
+        stops: Vec<ColorStop>,
+        sky: [u8; 3],
+        water: [u8; 3],
+        #[serde(default = "default_fog")]
+        fog: [u8; 3],
+    },
+}
+
+fn default_fog() -> [u8; 3] {
+    [160, 160, 160]
+}
+
+impl ColorPalette {
+    /// Returns an error if the palette can't be used.
+    pub fn validate(&self) -> Result<(), String> {
+        if let ColorPalette::Custom { stops, .. } = self {
+            if stops.is_empty() {
+                return Err("a custom palette needs at least one stop".to_owned());
+            }
+            if !stops.windows(2).all(|w| w[0].elevation <= w[1].elevation) {
+                return Err(
+                    "the stops of a custom palette have to be sorted by elevation".to_owned(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The color of the sky, in linear light (like the other colors returned by the palette).
+    pub fn sky_color(&self) -> Vector3<f64> {
+        match self {
+            ColorPalette::Legacy => Vector3::new(0.11, 0.11, 0.11).map(srgb_to_linear),
+            ColorPalette::Improved => Vector3::new(0.23, 0.41, 0.55).map(srgb_to_linear),
+            ColorPalette::Custom { sky, .. } => decode_srgb(*sky),
+        }
+    }
+
+    pub fn water_color(&self) -> Vector3<f64> {
+        match self {
+            ColorPalette::Legacy => Vector3::new(0.0, 0.5, 1.0).map(srgb_to_linear),
+            ColorPalette::Improved => Vector3::new(0.23, 0.41, 0.55).map(srgb_to_linear),
+            ColorPalette::Custom { water, .. } => decode_srgb(*water),
+        }
+    }
+
+    pub fn fog_color(&self) -> Vector3<f64> {
+        match self {
+            ColorPalette::Legacy | ColorPalette::Improved => decode_srgb(default_fog()),
+            ColorPalette::Custom { fog, .. } => decode_srgb(*fog),
+        }
+    }
+
+    pub fn elev_to_color(&self, elev: f64) -> Vector3<f64> {
+        self.elev_to_srgb(elev).map(srgb_to_linear)
+    }
+
+    /// The gradients are interpolated in the sRGB encoding.
+    fn elev_to_srgb(&self, elev: f64) -> Vector3<f64> {
+        match self {
+            ColorPalette::Legacy => {
+                const THR1: f64 = 300.0;
+                const THR2: f64 = 1200.0;
+                const THR3: f64 = 1800.0;
+                const THR4: f64 = 3000.0;
+                let green = Vector3::new(0.0, 1.0, 0.0);
+                let green_yellow = Vector3::new(0.6, 1.0, 0.0);
+                let grey = Vector3::new(0.5, 0.5, 0.5);
+                let white = Vector3::new(1.0, 1.0, 1.0);
+                if elev < THR1 {
+                    green
+                } else if elev < THR2 {
+                    let prop = (elev - THR1) / (THR2 - THR1);
+                    green_yellow * prop + green * (1.0 - prop)
+                } else if elev < THR3 {
+                    let prop = (elev - THR2) / (THR3 - THR2);
+                    grey * prop + green_yellow * (1.0 - prop)
+                } else if elev < THR4 {
+                    let prop = (elev - THR3) / (THR4 - THR3);
+                    white * prop + grey * (1.0 - prop)
+                } else {
+                    white
+                }
+            }
+            ColorPalette::Improved => {
+                const THR1: f64 = 300.0;
+                const THR2: f64 = 1000.0;
+                const THR3: f64 = 1800.0;
+                const THR4: f64 = 3000.0;
+                let green = Vector3::new(0.4, 0.8, 0.3);
+                let base = Vector3::new(0.77, 0.84, 0.4);
+                let mid = Vector3::new(0.41, 0.52, 0.4);
+                let top = Vector3::new(0.85, 0.92, 0.95);
+                if elev < THR1 {
+                    green
+                } else if elev < THR2 {
+                    let prop = (elev - THR1) / (THR2 - THR1);
+                    base * prop + green * (1.0 - prop)
+                } else if elev < THR3 {
+                    let prop = (elev - THR2) / (THR3 - THR2);
+                    mid * prop + base * (1.0 - prop)
+                } else if elev < THR4 {
+                    let prop = (elev - THR3) / (THR4 - THR3);
+                    top * prop + mid * (1.0 - prop)
+                } else {
+                    top
+                }
+            }
+            ColorPalette::Custom { stops, .. } => {
+                let upper = stops.partition_point(|stop| stop.elevation <= elev);
+                let color = |stop: ColorStop| rgb_to_vec3(Rgb(stop.color));
+                if upper == 0 {
+                    color(stops[0])
+                } else if upper == stops.len() {
+                    color(stops[upper - 1])
+                } else {
+                    let (low, high) = (stops[upper - 1], stops[upper]);
+                    let prop = (elev - low.elevation) / (high.elevation - low.elevation);
+                    color(high) * prop + color(low) * (1.0 - prop)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_palette() {
+        let stop = |elevation, color| ColorStop { elevation, color };
+        let palette = ColorPalette::Custom {
+            stops: vec![
+                stop(0.0, [0, 0, 0]),
+                stop(1000.0, [255, 0, 0]),
+                stop(2000.0, [255, 255, 0]),
+            ],
+            sky: [0, 0, 255],
+            water: [0, 0, 128],
+            fog: default_fog(),
+        };
+        assert!(palette.validate().is_ok());
+        assert_eq!(palette.elev_to_srgb(-100.0), Vector3::new(0.0, 0.0, 0.0));
+        assert_eq!(palette.elev_to_srgb(500.0), Vector3::new(0.5, 0.0, 0.0));
+        assert_eq!(palette.elev_to_srgb(1500.0), Vector3::new(1.0, 0.5, 0.0));
+        assert_eq!(palette.elev_to_srgb(3000.0), Vector3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_invalid_custom_palette() {
+        let stop = |elevation| ColorStop {
+            elevation,
+            color: [0, 0, 0],
+        };
+        let palette = |stops| ColorPalette::Custom {
+            stops,
+            sky: [0, 0, 255],
+            water: [0, 0, 128],
+            fog: default_fog(),
+        };
+        assert!(palette(vec![]).validate().is_err());
+        assert!(palette(vec![stop(1000.0), stop(0.0)]).validate().is_err());
+        assert!(palette(vec![stop(0.0), stop(0.0)]).validate().is_ok());
+    }
+}
@@ -1,28 +1,51 @@
-use super::ColoringMethod;
+use super::{ColorPalette, ColoringMethod, Sky};
 
-use crate::generator::TracePoint;
+use crate::{
+    generator::TracePoint,
+    utils::{decode_srgb, rgb_to_vec3, srgb_to_linear},
+};
 
 use image::Rgb;
+use nalgebra::Vector3;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct SimpleColors {
     max_distance: f64,
     water_level: f64,
+    /// Replaces the default colors by elevation, if set.
+    palette: Option<ColorPalette>,
+    sky: Option<Sky>,
 }
 
 impl SimpleColors {
-    pub fn new(max_distance: f64, water_level: f64) -> Self {
+    pub fn new(
+        max_distance: f64,
+        water_level: f64,
+        palette: Option<ColorPalette>,
+        sky: Option<Sky>,
+    ) -> Self {
         Self {
             max_distance,
             water_level,
+            palette,
+            sky,
         }
     }
 }
 
 impl ColoringMethod for SimpleColors {
-    fn color_for_pixel(&self, pixel: &TracePoint) -> Rgb<u8> {
+    fn color_for_pixel(&self, pixel: &TracePoint) -> Vector3<f64> {
         let dist_ratio = pixel.distance / self.max_distance;
-        if pixel.elevation <= self.water_level {
+        if let Some(ref palette) = self.palette {
+            let color = if pixel.elevation <= self.water_level {
+                palette.water_color()
+            } else {
+                palette.elev_to_color(pixel.elevation)
+            };
+            return color * (1.0 - dist_ratio * 0.6);
+        }
+        // the default colors are defined in sRGB
+        let color = if pixel.elevation <= self.water_level {
             let mul = 1.0 - dist_ratio * 0.6;
             Rgb([0, (128.0 * mul) as u8, (255.0 * mul) as u8])
         } else {
@@ -41,15 +64,29 @@ impl ColoringMethod for SimpleColors {
             } * (1.0 - dist_ratio * 0.6);
             let s = 1.0 - dist_ratio * 0.9;
             hsv(h, s, v)
+        };
+        rgb_to_vec3(color).map(srgb_to_linear)
+    }
+
+    fn sky_color(&self) -> Vector3<f64> {
+        match self.palette {
+            Some(ref palette) => palette.sky_color(),
+            None => decode_srgb([28, 28, 28]),
         }
     }
 
-    fn sky_color(&self) -> Rgb<u8> {
-        Rgb([28, 28, 28])
+    fn sky_color_at(&self, azimuth: f64, elevation: f64) -> Vector3<f64> {
+        match self.sky {
+            Some(sky) => sky.color(azimuth, elevation),
+            None => self.sky_color(),
+        }
     }
 
-    fn fog_color(&self) -> Rgb<u8> {
-        Rgb([160, 160, 160])
+    fn fog_color(&self) -> Vector3<f64> {
+        match self.palette {
+            Some(ref palette) => palette.fog_color(),
+            None => decode_srgb([160, 160, 160]),
+        }
     }
 }
 
@@ -0,0 +1,198 @@
+use std::f64::consts::PI;
+
+use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Sky {
+    /// The analytic daylight model by Preetham, Shirley and Smits (1999). The sun's position is
+    /// given in degrees (it is replaced by the calculated one if the view has a date and time);
+    /// the model is only valid for the sun above the horizon, so lower elevations are treated
+    /// as 0.
+    Preetham {
+        #[serde(default)]
+        sun_azimuth: f64,
+        #[serde(default)]
+        sun_elevation: f64,
+        #[serde(default = "default_turbidity")]
+        turbidity: f64,
+        /// The multiplier of the luminance (in kcd/m^2) before tone mapping.
+        #[serde(default = "default_exposure")]
+        exposure: f64,
+    },
+}
+
+fn default_turbidity() -> f64 {
+    3.0
+}
+
+fn default_exposure() -> f64 {
+    0.1
+}
+
+/// Coefficients of the Perez luminance distribution function.
+struct Perez([f64; 5]);
+
+impl Perez {
+    fn eval(&self, cos_theta: f64, gamma: f64) -> f64 {
+        let [a, b, c, d, e] = self.0;
+        (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+    }
+}
+
+fn direction(azimuth: f64, elevation: f64) -> Vector3<f64> {
+    let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+    Vector3::new(
+        elevation.cos() * azimuth.cos(),
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+    )
+}
+
+fn zenith_chromaticity(coeffs: [[f64; 4]; 3], turbidity: f64, theta_s: f64) -> f64 {
+    let thetas = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+    let dot = |row: [f64; 4]| row.iter().zip(&thetas).map(|(a, b)| a * b).sum::<f64>();
+    turbidity * turbidity * dot(coeffs[0]) + turbidity * dot(coeffs[1]) + dot(coeffs[2])
+}
+
+impl Sky {
+    /// Returns the same sky with the sun moved to the given position (in degrees).
+    pub fn with_sun(self, azimuth: f64, elevation: f64) -> Sky {
+        match self {
+            Sky::Preetham {
+                turbidity,
+                exposure,
+                ..
+            } => Sky::Preetham {
+                sun_azimuth: azimuth,
+                sun_elevation: elevation,
+                turbidity,
+                exposure,
+            },
+        }
+    }
+
+    /// Returns the color of the sky in the given direction (azimuth and elevation in degrees), in
+    /// linear light.
+    pub fn color(&self, azimuth: f64, elevation: f64) -> Vector3<f64> {
+        match *self {
+            Sky::Preetham {
+                sun_azimuth,
+                sun_elevation,
+                turbidity: t,
+                exposure,
+            } => {
+                let sun_elevation = sun_elevation.max(0.0);
+                let theta_s = (90.0 - sun_elevation).to_radians();
+                // directions below the horizon get the color of the horizon
+                let cos_theta = elevation.to_radians().sin().max(0.01);
+                let gamma = direction(azimuth, elevation.max(0.0))
+                    .dot(&direction(sun_azimuth, sun_elevation))
+                    .clamp(-1.0, 1.0)
+                    .acos();
+
+                let perez_y = Perez([
+                    0.1787 * t - 1.4630,
+                    -0.3554 * t + 0.4275,
+                    -0.0227 * t + 5.3251,
+                    0.1206 * t - 2.5771,
+                    -0.0670 * t + 0.3703,
+                ]);
+                let perez_x = Perez([
+                    -0.0193 * t - 0.2592,
+                    -0.0665 * t + 0.0008,
+                    -0.0004 * t + 0.2125,
+                    -0.0641 * t - 0.8989,
+                    -0.0033 * t + 0.0452,
+                ]);
+                let perez_yc = Perez([
+                    -0.0167 * t - 0.2608,
+                    -0.0950 * t + 0.0092,
+                    -0.0079 * t + 0.2102,
+                    -0.0441 * t - 1.6537,
+                    -0.0109 * t + 0.0529,
+                ]);
+
+                let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+                let zenith_lum = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+                let zenith_x = zenith_chromaticity(
+                    [
+                        [0.00166, -0.00375, 0.00209, 0.0],
+                        [-0.02903, 0.06377, -0.03202, 0.00394],
+                        [0.11693, -0.21196, 0.06052, 0.25886],
+                    ],
+                    t,
+                    theta_s,
+                );
+                let zenith_y = zenith_chromaticity(
+                    [
+                        [0.00275, -0.00610, 0.00317, 0.0],
+                        [-0.04214, 0.08970, -0.04153, 0.00516],
+                        [0.15346, -0.26756, 0.06670, 0.26688],
+                    ],
+                    t,
+                    theta_s,
+                );
+
+                let relative =
+                    |perez: &Perez| perez.eval(cos_theta, gamma) / perez.eval(1.0, theta_s);
+                // luminance in kcd/m^2
+                let lum = zenith_lum * relative(&perez_y);
+                let x = zenith_x * relative(&perez_x);
+                let y = zenith_y * relative(&perez_yc);
+
+                let xyz = Vector3::new(x / y * lum, lum, (1.0 - x - y) / y * lum);
+                let xyz_to_rgb = Matrix3::new(
+                    3.2406, -1.5372, -0.4986, -0.9689, 1.8758, 0.0415, 0.0557, -0.2040, 1.0570,
+                );
+                // tone mapping; the gamma is applied when the image is encoded
+                (xyz_to_rgb * xyz).map(|c| 1.0 - (-exposure * c.max(0.0)).exp())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn luminance(color: Vector3<f64>) -> f64 {
+        color.dot(&Vector3::new(0.2126, 0.7152, 0.0722))
+    }
+
+    #[test]
+    fn test_preetham_sky() {
+        let sky = Sky::Preetham {
+            sun_azimuth: 0.0,
+            sun_elevation: 0.0,
+            turbidity: default_turbidity(),
+            exposure: default_exposure(),
+        };
+
+        for sun_elevation in [-10.0, 0.0, 10.0, 30.0, 60.0, 90.0] {
+            let sky = sky.with_sun(90.0, sun_elevation);
+            for azimuth in (0..360).step_by(30) {
+                for elevation in [-10, 0, 1, 5, 20, 45, 70, 90] {
+                    let color = sky.color(azimuth as f64, elevation as f64);
+                    assert!(
+                        color.iter().all(|c| c.is_finite() && *c >= 0.0),
+                        "{} {} {} {:?}",
+                        sun_elevation,
+                        azimuth,
+                        elevation,
+                        color
+                    );
+                }
+            }
+        }
+
+        let sky = sky.with_sun(90.0, 30.0);
+        let lum = |azimuth, elevation| luminance(sky.color(azimuth, elevation));
+        // brighter towards the sun
+        assert!(lum(90.0, 35.0) > lum(270.0, 35.0));
+        assert!(lum(90.0, 35.0) > lum(0.0, 35.0));
+        // and towards the horizon, away from the sun
+        assert!(lum(0.0, 5.0) > lum(0.0, 60.0));
+        assert!(lum(270.0, 5.0) > lum(270.0, 45.0));
+    }
+}
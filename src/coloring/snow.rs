@@ -0,0 +1,154 @@
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::EarthModel;
+
+/// The snow cover of the terrain: the snow lies above the snow line, except on slopes too steep
+/// to hold it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Snow {
+    /// The elevation of the snow line in meters.
+    pub snow_line: f64,
+    /// The elevation range over which the snow cover fades in, in meters; 0 makes the snow line
+    /// sharp.
+    #[serde(default = "default_transition")]
+    pub transition: f64,
+    /// The slope angle in degrees above which the snow doesn't stay.
+    #[serde(default = "default_max_slope")]
+    pub max_slope: f64,
+    /// How much higher (in meters) the snow line is on the slopes facing `aspect_azimuth`, and
+    /// how much lower on the opposite ones.
+    #[serde(default)]
+    pub aspect_shift: f64,
+    #[serde(default = "default_aspect_azimuth")]
+    pub aspect_azimuth: f64,
+    #[serde(default = "default_snow_color")]
+    pub color: [u8; 3],
+}
+
+fn default_transition() -> f64 {
+    100.0
+}
+
+fn default_max_slope() -> f64 {
+    40.0
+}
+
+fn default_aspect_azimuth() -> f64 {
+    180.0
+}
+
+fn default_snow_color() -> [u8; 3] {
+    [245, 248, 255]
+}
+
+/// The range of slope angles over which the snow cover disappears, in degrees.
+const SLOPE_TRANSITION: f64 = 5.0;
+
+impl Snow {
+    /// Returns the fraction of the surface covered by snow at a point with the given elevation
+    /// and normal vector.
+    pub fn coverage(
+        &self,
+        model: &EarthModel,
+        lat: f64,
+        lon: f64,
+        elevation: f64,
+        normal: &Vector3<f64>,
+    ) -> f64 {
+        let (dir_north, dir_east, dir_up) = model.world_directions(lat, lon);
+        let up = normal.dot(&dir_up).clamp(-1.0, 1.0);
+        let slope = up.acos().to_degrees();
+        // the aspect only matters as much as the surface is inclined
+        let aspect = normal.dot(&dir_east).atan2(normal.dot(&dir_north));
+        let aspect_factor =
+            (aspect - self.aspect_azimuth.to_radians()).cos() * (1.0 - up * up).sqrt();
+        let snow_line = self.snow_line + self.aspect_shift * aspect_factor;
+
+        let elev_coverage = if self.transition > 0.0 {
+            ((elevation - snow_line) / self.transition + 0.5).clamp(0.0, 1.0)
+        } else if elevation >= snow_line {
+            1.0
+        } else {
+            0.0
+        };
+        let slope_coverage = ((self.max_slope - slope) / SLOPE_TRANSITION + 0.5).clamp(0.0, 1.0);
+        elev_coverage * slope_coverage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sharp_snow(aspect_shift: f64) -> Snow {
+        Snow {
+            snow_line: 1000.0,
+            transition: 0.0,
+            max_slope: default_max_slope(),
+            aspect_shift,
+            aspect_azimuth: default_aspect_azimuth(),
+            color: default_snow_color(),
+        }
+    }
+
+    /// The normal of a surface inclined by `slope` degrees, facing `azimuth`.
+    fn tilted_normal(model: &EarthModel, slope: f64, azimuth: f64) -> Vector3<f64> {
+        let (north, east, up) = model.world_directions(50.0, 20.0);
+        let (slope, azimuth) = (slope.to_radians(), azimuth.to_radians());
+        up * slope.cos() + (north * azimuth.cos() + east * azimuth.sin()) * slope.sin()
+    }
+
+    #[test]
+    fn test_sharp_snow_line() {
+        let snow = sharp_snow(0.0);
+        let model = EarthModel::SimpleSphere;
+        let (_, _, up) = model.world_directions(50.0, 20.0);
+        let coverage = |elevation| snow.coverage(&model, 50.0, 20.0, elevation, &up);
+        assert_eq!(coverage(999.0), 0.0);
+        assert_eq!(coverage(1000.0), 1.0);
+        assert_eq!(coverage(1001.0), 1.0);
+    }
+
+    /// The snow should only stay on the slopes less steep than `max_slope`, fading out over
+    /// `SLOPE_TRANSITION`.
+    #[test]
+    fn test_max_slope() {
+        let snow = sharp_snow(0.0);
+        let model = EarthModel::SimpleSphere;
+        let coverage = |slope| {
+            let normal = tilted_normal(&model, slope, 90.0);
+            snow.coverage(&model, 50.0, 20.0, 2000.0, &normal)
+        };
+        assert_eq!(coverage(0.0), 1.0);
+        assert_eq!(coverage(30.0), 1.0);
+        assert!((coverage(40.0) - 0.5).abs() < 1e-9);
+        assert_eq!(coverage(45.0), 0.0);
+        assert_eq!(coverage(60.0), 0.0);
+    }
+
+    /// The snow line should be `aspect_shift` higher on the slopes facing `aspect_azimuth`
+    /// (south by default) and lower on the opposite ones, scaled by the sine of the slope.
+    #[test]
+    fn test_aspect_shift() {
+        let snow = sharp_snow(200.0);
+        let model = EarthModel::SimpleSphere;
+        let coverage = |elevation, azimuth| {
+            let normal = tilted_normal(&model, 30.0, azimuth);
+            snow.coverage(&model, 50.0, 20.0, elevation, &normal)
+        };
+        // the snow line is at 1100 m on the southern slopes, 900 m on the northern ones and
+        // unchanged on the eastern ones
+        assert_eq!(coverage(1099.0, 180.0), 0.0);
+        assert_eq!(coverage(1101.0, 180.0), 1.0);
+        assert_eq!(coverage(899.0, 0.0), 0.0);
+        assert_eq!(coverage(901.0, 0.0), 1.0);
+        assert_eq!(coverage(999.0, 90.0), 0.0);
+        assert_eq!(coverage(1001.0, 90.0), 1.0);
+
+        // a flat surface has no aspect
+        let (_, _, up) = model.world_directions(50.0, 20.0);
+        assert_eq!(snow.coverage(&model, 50.0, 20.0, 999.0, &up), 0.0);
+        assert_eq!(snow.coverage(&model, 50.0, 20.0, 1001.0, &up), 1.0);
+    }
+}
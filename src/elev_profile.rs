@@ -1,9 +1,8 @@
 use std::env;
 
+use atm_raytracer::{terrain::Terrain, utils::DirectionalCalc};
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-use crate::terrain::Terrain;
-
 pub const SUBCOMMAND: &str = "output-elev-profile";
 
 pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
@@ -31,14 +30,14 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
 
     assert!(step > 0.0, "step must be positive");
 
-    let config = crate::generator::params::parse_config(filename);
+    let config = atm_raytracer::generator::params::parse_config(filename);
 
     let mut terrain_folder = env::current_dir().unwrap();
     terrain_folder.push(config.terrain_folder());
 
     let terrain = Terrain::from_folder(terrain_folder);
 
-    let params = config.into_params(&terrain);
+    let params = config.into_params(&terrain)?;
 
     let dist_calc = params.model.coords_at_dist_calc(
         (
@@ -48,6 +47,19 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
         azim,
     );
 
+    for point in elev_profile(&terrain, &*dist_calc, step, cutoff) {
+        println!("{}\t{}", point.0, point.1);
+    }
+
+    Ok(())
+}
+
+pub fn elev_profile(
+    terrain: &Terrain,
+    dist_calc: &dyn DirectionalCalc,
+    step: f64,
+    cutoff: f64,
+) -> Vec<(f64, f64)> {
     let mut points = vec![];
 
     let mut x = 0.0;
@@ -59,11 +71,7 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
         x += step;
     }
 
-    for point in points {
-        println!("{}\t{}", point.0, point.1);
-    }
-
-    Ok(())
+    points
 }
 
 pub fn subcommand_def() -> App<'static, 'static> {
@@ -0,0 +1,355 @@
+use std::{env, time::SystemTime};
+
+use atm_raytracer::{
+    generator::{
+        self,
+        params::{parse_config, Altitude, Config},
+    },
+    terrain::Terrain,
+    utils::EarthModel,
+};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+pub const SUBCOMMAND: &str = "gen";
+
+pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
+    if let Some(address) = matches.value_of("worker") {
+        let paths = generator::LocalPaths {
+            terrain_folder: matches.value_of("terrain").map(str::to_owned),
+            land_cover: matches.value_of("land-cover").map(str::to_owned),
+            land_cover_classes: matches.value_of("land-cover-classes").map(str::to_owned),
+            texture: matches.value_of("texture").map(str::to_owned),
+        };
+        return generator::run_worker(address, &paths);
+    }
+
+    let config = match read_config(matches) {
+        Ok(config) => config,
+        Err(()) => {
+            // this indicates that 'output-atm-data' was chosen and data was printed, nothing more
+            // to do
+            return Ok(());
+        }
+    };
+
+    let mut terrain_folder = env::current_dir().unwrap();
+    terrain_folder.push(config.terrain_folder());
+
+    let start = SystemTime::now();
+
+    println!(
+        "{:.3}: Using terrain data directory: {:?}",
+        start.elapsed().unwrap().as_secs_f64(),
+        terrain_folder
+    );
+
+    let terrain = Terrain::from_folder(terrain_folder);
+
+    let params = config.into_params(&terrain)?;
+
+    generator::generate(&params, &terrain, matches.value_of("coordinator"), start)
+}
+
+pub fn subcommand_def() -> App<'static, 'static> {
+    SubCommand::with_name(SUBCOMMAND).about("Render a panorama")
+        .setting(AppSettings::AllowLeadingHyphen)
+        .arg(
+            Arg::with_name("terrain")
+                .short("t")
+                .long("terrain")
+                .value_name("PATH")
+                .help("Path to the folder with terrain files (./terrain assumed if none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("latitude")
+                .short("l")
+                .long("lat")
+                .value_name("DEG")
+                .help("Viewpoint latitude in degrees (default: 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("longitude")
+                .short("g")
+                .long("lon")
+                .value_name("DEG")
+                .help("Viewpoint longitude in degrees (default: 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("altitude")
+                .short("a")
+                .long("alt")
+                .value_name("ALT")
+                .conflicts_with("elevation")
+                .help("Viewpoint altitude in meters")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("elevation")
+                .short("e")
+                .long("elev")
+                .value_name("ELEV")
+                .conflicts_with("altitude")
+                .help("Viewpoint elevation in meters (above the terrain)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("direction")
+                .short("d")
+                .long("dir")
+                .value_name("DEG")
+                .help(
+                    "Viewing azimuth in degrees (ie. 0 = north, 90 = east, 180 = south, 270 = west)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fov")
+                .short("f")
+                .long("fov")
+                .value_name("DEG")
+                .help("Horizontal field of view in degrees (default: 30)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tilt")
+                .short("i")
+                .long("tilt")
+                .value_name("DEG")
+                .help("Observer tilt relative to the horizon in degrees (default: 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-dist")
+                .short("m")
+                .long("maxdist")
+                .value_name("DIST")
+                .help("Cutoff distance in km (default: 150)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("step")
+                .long("step")
+                .value_name("STEP")
+                .help("Light ray propagation step in meters (default: 50)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("radius")
+                .short("R")
+                .long("radius")
+                .value_name("RADIUS")
+                .help("Calculate assuming the given value as the Earth's radius, in km (default: 6371) (conflicts with --flat)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flat")
+                .long("flat")
+                .help("Simulate a flat Earth using the FlatDistorted model (light paths like on a flat Earth, but with distances distorted for southern hemisphere to yield reasonable results) (conflicts with --radius)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("straight")
+                .short("s")
+                .long("straight")
+                .help("Ignore the atmosphere (use straight-line light rays)")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("File name to save the output image as (default: output.png)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output-meta")
+                .long("output-meta")
+                .value_name("FILE")
+                .help("File name to save the output metadata as")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output-layers")
+                .long("output-layers")
+                .value_name("FILE")
+                .help("File name to export the per-pixel data layers to (.tif or .npy)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Continue an interrupted generation from the metadata file")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("coordinator")
+                .long("coordinator")
+                .value_name("ADDRESS")
+                .help("Distribute the calculation among workers connecting to this address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("worker-timeout")
+                .long("worker-timeout")
+                .value_name("SECONDS")
+                .help(
+                    "How long to wait for a worker to calculate a tile before giving it to \
+                    another one (default: 3600)",
+                )
+                .requires("coordinator")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("worker")
+                .long("worker")
+                .value_name("ADDRESS")
+                .help("Calculate the tiles for the coordinator at this address")
+                .conflicts_with("coordinator")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("land-cover")
+                .long("land-cover")
+                .value_name("PATH")
+                .help("Path to the land cover raster on the worker's machine")
+                .requires("worker")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("land-cover-classes")
+                .long("land-cover-classes")
+                .value_name("PATH")
+                .help("Path to the land cover class colors on the worker's machine")
+                .requires("worker")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("texture")
+                .long("texture")
+                .value_name("PATH")
+                .help("Path to the texture image on the worker's machine")
+                .requires("worker")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("width")
+                .short("w")
+                .long("width")
+                .value_name("PIXELS")
+                .help("Output image width in pixels (default: 640)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("height")
+                .short("h")
+                .long("height")
+                .value_name("PIXELS")
+                .help("Output image height in pixels (default: 480)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Path to a config file with alternative defaults")
+                .takes_value(true),
+        )
+}
+
+pub fn read_config(matches: &ArgMatches<'_>) -> Result<Config, ()> {
+    let mut config = if let Some(config_path) = matches.value_of("config") {
+        parse_config(config_path)
+    } else {
+        Default::default()
+    };
+
+    if let Some(terrain) = matches.value_of("terrain") {
+        config.scene.terrain_folder = terrain.to_owned();
+    }
+    if let Some(output) = matches.value_of("output") {
+        config.output.file = output.to_owned();
+    }
+    if let Some(output_metadata) = matches.value_of("output-meta") {
+        config.output.file_metadata = Some(output_metadata.to_owned());
+    }
+    if let Some(output_layers) = matches.value_of("output-layers") {
+        config.output.file_layers = Some(output_layers.to_owned());
+    }
+    if matches.is_present("resume") {
+        config.output.resume = true;
+    }
+    if let Some(timeout) = matches.value_of("worker-timeout") {
+        config.output.worker_timeout = timeout.parse().expect("Invalid worker timeout");
+    }
+
+    if let Some(pic_width) = matches.value_of("width") {
+        config.output.width = pic_width.parse().expect("Invalid output width");
+    }
+
+    if let Some(pic_height) = matches.value_of("height") {
+        config.output.height = pic_height.parse().expect("Invalid output height");
+    }
+
+    if let Some(lat) = matches.value_of("latitude") {
+        config.view.position.latitude = lat.parse().expect("Invalid viewpoint latitude");
+    }
+
+    if let Some(lon) = matches.value_of("longitude") {
+        config.view.position.longitude = lon.parse().expect("Invalid viewpoint longitude");
+    }
+
+    match (matches.value_of("altitude"), matches.value_of("elevation")) {
+        (Some(a), None) => {
+            config.view.position.altitude =
+                Altitude::Absolute(a.parse().expect("Invalid viewpoint altitude"));
+        }
+        (None, Some(e)) => {
+            config.view.position.altitude =
+                Altitude::Relative(e.parse().expect("Invalid viewpoint elevation"));
+        }
+        _ => (),
+    };
+
+    if let Some(dir) = matches.value_of("direction") {
+        config.view.frame.direction = dir.parse().expect("Invalid viewing azimuth");
+    }
+
+    if let Some(fov) = matches.value_of("fov") {
+        config.view.frame.fov = fov.parse().expect("Invalid field of view");
+    }
+
+    if let Some(tilt) = matches.value_of("tilt") {
+        config.view.frame.tilt = tilt.parse().expect("Invalid view tilt");
+    }
+
+    if let Some(max_dist) = matches.value_of("max-dist") {
+        config.view.frame.max_distance =
+            max_dist.parse::<f64>().expect("Invalid cutoff distance") * 1e3;
+    }
+
+    if let Some(step) = matches.value_of("step") {
+        config.simulation_step = step.parse().expect("Invalid step value");
+    }
+
+    match (matches.is_present("flat"), matches.value_of("radius")) {
+        (true, None) => {
+            config.earth_shape = EarthModel::FlatDistorted;
+        }
+        (false, Some(radius)) => {
+            let r: f64 = radius.parse().expect("Invalid radius passed");
+            config.earth_shape = EarthModel::Spherical { radius: r * 1e3 };
+        }
+        (true, Some(_)) => panic!("Conflicting Earth shape options chosen!"),
+        _ => (),
+    };
+
+    if matches.is_present("straight") {
+        config.straight_rays = true;
+    }
+
+    Ok(config)
+}
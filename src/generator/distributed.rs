@@ -0,0 +1,593 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    env,
+    io::{BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    ops::Range,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    create_generator, generate_tile,
+    params::{Params, Scene},
+    Progress, ResultPixel, SurfaceColors,
+};
+use crate::terrain::Terrain;
+
+/// The limit of the size of the messages received by the workers, so that a corrupted message
+/// can't make them allocate an arbitrary amount of memory.
+const MAX_REQUEST_SIZE: u64 = 64 << 20;
+/// The limit of the average size of a pixel in a tile received by the coordinator, which leaves
+/// room for about 100 trace points per pixel.
+const MAX_PIXEL_SIZE: u64 = 16 << 10;
+/// How often the coordinator reports that it is still waiting for the tiles when none arrive.
+const WAITING_NOTICE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The messages sent by the coordinator to the workers. The first one is always `Start`, preceded
+/// by the version of atm-raytracer, which has to be the same on both sides.
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Start(Box<Params>),
+    Tile { first_row: u16, end_row: u16 },
+    Finish,
+}
+
+/// The pixels of a tile, sent back by a worker.
+#[derive(Serialize, Deserialize)]
+struct Tile {
+    first_row: u16,
+    rows: Vec<Vec<ResultPixel>>,
+}
+
+fn send<T: Serialize>(writer: &mut BufWriter<TcpStream>, message: &T) -> Result<(), String> {
+    bincode::serialize_into(&mut *writer, message)
+        .map_err(|err| err.to_string())
+        .and_then(|_| writer.flush().map_err(|err| err.to_string()))
+}
+
+/// Receives a message of at most `limit` bytes, encoded like the ones written by `send`.
+fn receive<T: DeserializeOwned>(
+    reader: &mut BufReader<TcpStream>,
+    limit: u64,
+) -> Result<T, String> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(limit)
+        .deserialize_from(reader)
+        .map_err(|err| format!("failed to receive a message: {}", err))
+}
+
+fn split_stream(stream: TcpStream) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>), String> {
+    let reader = stream.try_clone().map_err(|err| err.to_string())?;
+    Ok((BufReader::new(reader), BufWriter::new(stream)))
+}
+
+/// The paths on the machine of a worker, overriding the ones in the settings sent by the
+/// coordinator, which are relative to its working directory.
+#[derive(Default)]
+pub struct LocalPaths {
+    pub terrain_folder: Option<String>,
+    pub land_cover: Option<String>,
+    pub land_cover_classes: Option<String>,
+    pub texture: Option<String>,
+}
+
+impl LocalPaths {
+    fn apply(&self, scene: &mut Scene) -> Result<(), String> {
+        if let Some(ref terrain_folder) = self.terrain_folder {
+            scene.terrain_folder = terrain_folder.clone();
+        }
+        if self.land_cover.is_some() || self.land_cover_classes.is_some() {
+            let land_cover = scene
+                .land_cover
+                .as_mut()
+                .ok_or("the coordinator doesn't use a land cover raster")?;
+            if let Some(ref raster) = self.land_cover {
+                land_cover.raster = raster.clone();
+            }
+            if let Some(ref classes) = self.land_cover_classes {
+                land_cover.classes = classes.clone();
+            }
+        }
+        if let Some(ref image) = self.texture {
+            scene
+                .texture
+                .as_mut()
+                .ok_or("the coordinator doesn't use a texture")?
+                .image = image.clone();
+        }
+        Ok(())
+    }
+}
+
+/// Connects to a coordinator and calculates the tiles it requests, until it has no more.
+pub fn run_worker(address: &str, paths: &LocalPaths) -> Result<(), String> {
+    let start = SystemTime::now();
+
+    let (mut reader, mut writer, mut params) = connect(address, start)?;
+    paths.apply(&mut params.scene)?;
+
+    let mut terrain_path =
+        env::current_dir().map_err(|err| format!("invalid working directory: {}", err))?;
+    terrain_path.push(&params.scene.terrain_folder);
+    if !terrain_path.is_dir() {
+        return Err(format!(
+            "the terrain data directory {:?} doesn't exist (it can be set with --terrain)",
+            terrain_path
+        ));
+    }
+    println!(
+        "{:.3}: Using terrain data directory: {:?}",
+        start.elapsed().unwrap().as_secs_f64(),
+        terrain_path
+    );
+    let terrain = Terrain::from_folder(terrain_path);
+
+    calculate_tiles(&mut reader, &mut writer, &params, &terrain, start)?;
+
+    println!("{:.3}: Done.", start.elapsed().unwrap().as_secs_f64());
+
+    Ok(())
+}
+
+/// Connects to the coordinator and receives the settings of the generation.
+fn connect(
+    address: &str,
+    start: SystemTime,
+) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>, Params), String> {
+    let stream = TcpStream::connect(address).map_err(|err| {
+        format!(
+            "couldn't connect to the coordinator at {}: {}",
+            address, err
+        )
+    })?;
+    let (mut reader, writer) = split_stream(stream)?;
+    println!(
+        "{:.3}: Connected to the coordinator at {}",
+        start.elapsed().unwrap().as_secs_f64(),
+        address
+    );
+
+    let version: String = receive(&mut reader, MAX_REQUEST_SIZE)?;
+    if version != env!("CARGO_PKG_VERSION") {
+        return Err(format!(
+            "the coordinator runs atm-raytracer {}, but the worker runs {}",
+            version,
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    match receive(&mut reader, MAX_REQUEST_SIZE)? {
+        Request::Start(params) => Ok((reader, writer, *params)),
+        _ => Err("unexpected message from the coordinator".to_owned()),
+    }
+}
+
+/// Calculates the tiles requested by the coordinator until it has no more.
+fn calculate_tiles(
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut BufWriter<TcpStream>,
+    params: &Params,
+    terrain: &Terrain,
+    start: SystemTime,
+) -> Result<(), String> {
+    let generator = create_generator(params, terrain, start);
+    let surface_colors = SurfaceColors::load(params, start)?;
+    // the percentages are of the whole image, as it's not known how much of it this worker will
+    // calculate
+    let progress = Progress::new(
+        start,
+        params.output.width as usize * params.output.height as usize,
+    );
+
+    loop {
+        match receive(reader, MAX_REQUEST_SIZE)? {
+            Request::Tile { first_row, end_row } => {
+                println!(
+                    "{:.3}: Calculating rows {} to {}...",
+                    start.elapsed().unwrap().as_secs_f64(),
+                    first_row,
+                    end_row - 1
+                );
+                let rows = generate_tile(
+                    &*generator,
+                    params,
+                    terrain,
+                    &surface_colors,
+                    first_row..end_row,
+                    &progress,
+                );
+                send(writer, &Tile { first_row, rows })?;
+            }
+            Request::Finish => return Ok(()),
+            Request::Start(_) => return Err("unexpected message from the coordinator".to_owned()),
+        }
+    }
+}
+
+struct QueueState {
+    pending: VecDeque<Range<u16>>,
+    in_progress: usize,
+    workers: usize,
+}
+
+/// The tiles waiting to be calculated, shared between the threads serving the workers.
+struct TileQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl TileQueue {
+    fn new(tiles: Vec<Range<u16>>) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                pending: tiles.into(),
+                in_progress: 0,
+                workers: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Takes the next tile to calculate. If there are none left, but some are still being
+    /// calculated, waits in case they are given back. Returns `None` when all the tiles are done.
+    fn take(&self) -> Option<Range<u16>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(tile) = state.pending.pop_front() {
+                state.in_progress += 1;
+                return Some(tile);
+            }
+            if state.in_progress == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn finished(&self) {
+        self.state.lock().unwrap().in_progress -= 1;
+        self.changed.notify_all();
+    }
+
+    /// Returns a tile that a worker failed to calculate, so that another one can take it.
+    fn give_back(&self, tile: Range<u16>) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.push_front(tile);
+        state.in_progress -= 1;
+        self.changed.notify_all();
+    }
+
+    fn worker_connected(&self) {
+        self.state.lock().unwrap().workers += 1;
+    }
+
+    fn workers(&self) -> usize {
+        self.state.lock().unwrap().workers
+    }
+
+    fn worker_disconnected(&self) {
+        self.state.lock().unwrap().workers -= 1;
+        self.changed.notify_all();
+    }
+
+    /// Waits until the workers have been told that there are no more tiles.
+    fn wait_for_workers(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.workers > 0 {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+/// What the coordinator needs to serve the workers.
+struct WorkerSettings {
+    /// The version and the `Start` request, sent to every worker first.
+    start_message: Vec<u8>,
+    width: usize,
+    timeout: Duration,
+}
+
+impl WorkerSettings {
+    fn new(params: &Params) -> Self {
+        let mut start_message = bincode::serialize(env!("CARGO_PKG_VERSION")).unwrap();
+        start_message
+            .extend(bincode::serialize(&Request::Start(Box::new(params.clone()))).unwrap());
+        Self {
+            start_message,
+            width: params.output.width as usize,
+            timeout: Duration::from_secs(params.output.worker_timeout),
+        }
+    }
+
+    /// Checks that a worker sent the rows of the requested tile.
+    fn check_tile(&self, tile: &Range<u16>, result: &Tile) -> Result<(), String> {
+        if result.first_row != tile.start || result.rows.len() != tile.len() {
+            return Err(format!(
+                "the worker sent rows {} to {} instead of {} to {}",
+                result.first_row,
+                result.first_row as usize + result.rows.len(),
+                tile.start,
+                tile.end
+            ));
+        }
+        if result.rows.iter().any(|row| row.len() != self.width) {
+            return Err("the worker sent rows of a wrong width".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Sends the tiles to a worker until there are no more, passing the results to `results`.
+fn serve_worker(
+    stream: TcpStream,
+    queue: &TileQueue,
+    results: &Sender<Tile>,
+    settings: &WorkerSettings,
+) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(settings.timeout))
+        .and_then(|_| stream.set_write_timeout(Some(settings.timeout)))
+        .map_err(|err| err.to_string())?;
+    let (mut reader, mut writer) = split_stream(stream)?;
+    writer
+        .write_all(&settings.start_message)
+        .and_then(|_| writer.flush())
+        .map_err(|err| err.to_string())?;
+    while let Some(tile) = queue.take() {
+        let request = Request::Tile {
+            first_row: tile.start,
+            end_row: tile.end,
+        };
+        let limit = tile.len() as u64 * settings.width as u64 * MAX_PIXEL_SIZE;
+        let result = send(&mut writer, &request)
+            .and_then(|_| receive::<Tile>(&mut reader, limit))
+            .and_then(|result| settings.check_tile(&tile, &result).map(|_| result));
+        match result {
+            Ok(result) => {
+                results
+                    .send(result)
+                    .map_err(|_| "the coordinator stopped".to_owned())?;
+                queue.finished();
+            }
+            Err(err) => {
+                queue.give_back(tile);
+                return Err(err);
+            }
+        }
+    }
+    send(&mut writer, &Request::Finish)
+}
+
+/// Has the tiles calculated by the workers connecting to `address`, and passes the rows to
+/// `output` in the order of the tiles, like when they are calculated locally. Stops at the first
+/// error returned by `output`.
+pub fn coordinate<F>(
+    address: &str,
+    params: &Params,
+    tiles: Vec<Range<u16>>,
+    start: SystemTime,
+    output: F,
+) -> Result<(), String>
+where
+    F: FnMut(&[Vec<ResultPixel>]) -> Result<(), String>,
+{
+    if tiles.is_empty() {
+        return Ok(());
+    }
+    if params.output.worker_timeout == 0 {
+        return Err("the worker timeout has to be positive".to_owned());
+    }
+
+    let listener = TcpListener::bind(address)
+        .map_err(|err| format!("couldn't listen for workers at {}: {}", address, err))?;
+    println!(
+        "{:.3}: Waiting for workers at {}...",
+        start.elapsed().unwrap().as_secs_f64(),
+        address
+    );
+    serve_workers(listener, params, tiles, start, output)
+}
+
+/// Hands out the tiles to the workers accepted by `listener`, like `coordinate`.
+fn serve_workers<F>(
+    listener: TcpListener,
+    params: &Params,
+    tiles: Vec<Range<u16>>,
+    start: SystemTime,
+    mut output: F,
+) -> Result<(), String>
+where
+    F: FnMut(&[Vec<ResultPixel>]) -> Result<(), String>,
+{
+    let (mut next_row, num_tiles) = match tiles.first() {
+        Some(tile) => (tile.start, tiles.len()),
+        None => return Ok(()),
+    };
+
+    let queue = Arc::new(TileQueue::new(tiles));
+    let (sender, receiver) = mpsc::channel();
+    let settings = Arc::new(WorkerSettings::new(params));
+
+    // the listener keeps accepting workers until the process ends, so that the workers that
+    // were lost can be replaced
+    let listener_queue = queue.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("Failed to accept a worker: {}", err);
+                    continue;
+                }
+            };
+            let worker = stream
+                .peer_addr()
+                .map_or_else(|_| "<unknown>".to_owned(), |addr| addr.to_string());
+            let (queue, sender, settings) =
+                (listener_queue.clone(), sender.clone(), settings.clone());
+            queue.worker_connected();
+            thread::spawn(move || {
+                println!(
+                    "{:.3}: Worker {} connected",
+                    start.elapsed().unwrap().as_secs_f64(),
+                    worker
+                );
+                if let Err(err) = serve_worker(stream, &queue, &sender, &settings) {
+                    println!(
+                        "{:.3}: Lost worker {}: {}",
+                        start.elapsed().unwrap().as_secs_f64(),
+                        worker,
+                        err
+                    );
+                }
+                queue.worker_disconnected();
+            });
+        }
+    });
+
+    // the tiles come back in any order, so the ones that can't be output yet are kept until the
+    // tiles above them are done
+    let mut done = BTreeMap::new();
+    for tiles_done in 1..=num_tiles {
+        // if all the workers are lost, the coordinator waits for new ones to connect, so it
+        // reports that it's waiting instead of blocking silently
+        let tile = loop {
+            match receiver.recv_timeout(WAITING_NOTICE_INTERVAL) {
+                Ok(tile) => break tile,
+                Err(RecvTimeoutError::Timeout) => println!(
+                    "{:.3}: Waiting for workers: {} connected, {} of {} tiles done",
+                    start.elapsed().unwrap().as_secs_f64(),
+                    queue.workers(),
+                    tiles_done - 1,
+                    num_tiles
+                ),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("stopped accepting workers before all the tiles were done".into())
+                }
+            }
+        };
+        done.insert(tile.first_row, tile.rows);
+        while let Some(rows) = done.remove(&next_row) {
+            next_row += rows.len() as u16;
+            output(&rows)?;
+        }
+        println!(
+            "{:.3}: {} of {} tiles done",
+            start.elapsed().unwrap().as_secs_f64(),
+            tiles_done,
+            num_tiles
+        );
+    }
+    queue.wait_for_workers();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generator::{
+            generate_pixels,
+            params::{Altitude, Config, Position},
+            row_tiles,
+        },
+        terrain::FnTile,
+    };
+
+    #[test]
+    fn test_distributed_generation() {
+        let mut terrain = Terrain::new();
+        terrain.add_tile(FnTile {
+            lat: 49.0,
+            lon: 20.0,
+            elev: |lat: f64, lon: f64| 1000.0 * ((lat * 50.0).sin() + (lon * 30.0).cos()),
+        });
+        let mut config = Config::default();
+        config.view.position = Position {
+            latitude: 49.5,
+            longitude: 20.5,
+            altitude: Altitude::Relative(100.0),
+        };
+        config.output.width = 24;
+        config.output.height = 16;
+        config.output.tile_height = 5;
+        let params = config.into_params(&terrain).unwrap();
+        let start = SystemTime::now();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let terrain = Arc::new(terrain);
+        let worker_terrain = terrain.clone();
+        let worker = thread::spawn(move || {
+            let (mut reader, mut writer, params) = connect(&address, start)?;
+            calculate_tiles(&mut reader, &mut writer, &params, &worker_terrain, start)
+        });
+
+        let mut rows = vec![];
+        let tiles = row_tiles(0..params.output.height, params.output.tile_height).collect();
+        serve_workers(listener, &params, tiles, start, |tile| {
+            rows.extend_from_slice(tile);
+            Ok(())
+        })
+        .unwrap();
+        worker.join().unwrap().unwrap();
+
+        let expected = generate_pixels(&params, &terrain, start).unwrap();
+        assert_eq!(rows.len(), expected.len());
+        for (row, expected) in rows.iter().zip(&expected) {
+            for (pixel, expected) in row.iter().zip(expected) {
+                assert_eq!(pixel.elevation_angle, expected.elevation_angle);
+                assert_eq!(pixel.trace_points.len(), expected.trace_points.len());
+                for (point, expected) in pixel.trace_points.iter().zip(&expected.trace_points) {
+                    assert_eq!(point.distance, expected.distance);
+                    assert_eq!(point.elevation, expected.elevation);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_size_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut reader, _) = split_stream(listener.accept().unwrap().0).unwrap();
+
+        // a version string with a huge length; the sequences are allocated as their elements
+        // arrive, so they are limited by the size of the data actually read
+        peer.write_all(&(u64::MAX / 2).to_le_bytes()).unwrap();
+        drop(peer);
+        assert!(receive::<String>(&mut reader, MAX_REQUEST_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_check_tile() {
+        let mut config = Config::default();
+        config.output.width = 4;
+        let params = config.into_params(&Terrain::new()).unwrap();
+        let settings = WorkerSettings::new(&params);
+
+        let pixel = ResultPixel {
+            elevation_angle: 0.0,
+            azimuth: 0.0,
+            trace_points: vec![],
+            exit_elevation: None,
+        };
+        let tile = |first_row, rows, width| Tile {
+            first_row,
+            rows: vec![vec![pixel.clone(); width]; rows],
+        };
+        assert!(settings.check_tile(&(5..8), &tile(5, 3, 4)).is_ok());
+        assert!(settings.check_tile(&(5..8), &tile(4, 3, 4)).is_err());
+        assert!(settings.check_tile(&(5..8), &tile(5, 2, 4)).is_err());
+        assert!(settings.check_tile(&(5..8), &tile(5, 3, 5)).is_err());
+    }
+}
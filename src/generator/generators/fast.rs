@@ -1,41 +1,54 @@
-use std::{
-    sync::atomic::{AtomicUsize, Ordering},
-    time::SystemTime,
-};
+use std::{ops::Range, sync::OnceLock, time::SystemTime};
 
 use rayon::prelude::*;
 
 use super::{
-    utils::{gen_path_cache, gen_terrain_cache, get_single_pixel},
-    Generator, ResultPixel,
+    lighting::sky_view_factor,
+    utils::{gen_path_cache, gen_terrain_cache, get_single_pixel, Progress, TerrainData},
+    Generator, PixelColor, ResultPixel, TracePoint,
 };
 
-use crate::{generator::params::Params, terrain::Terrain};
+use crate::{
+    generator::params::{Coloring, Params},
+    terrain::Terrain,
+};
 
 pub struct FastGenerator<'a, 'b> {
     params: &'a Params,
     terrain: &'b Terrain,
     start: SystemTime,
+    /// The terrain along the directions of the columns, generated when the first rows are
+    /// calculated.
+    terrain_cache: OnceLock<Vec<Column>>,
+}
+
+/// The terrain along the direction of a column of pixels.
+struct Column {
+    terrain: Vec<TerrainData>,
+    /// The sky-view factors of the points of `terrain`, calculated when a ray hits the terrain
+    /// next to them, as the columns are shared by all the rows.
+    sky_view: Vec<OnceLock<f64>>,
 }
 
 impl<'a, 'b> Generator for FastGenerator<'a, 'b> {
-    fn generate(&self) -> Vec<Vec<ResultPixel>> {
-        println!(
-            "{:.3}: Generating terrain cache...",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        let terrain_cache = (0..self.params.output.width)
-            .into_par_iter()
-            .map(|x| {
-                let dir = get_ray_dir(self.params, x);
-                gen_terrain_cache(self.params, self.terrain, dir)
-            })
-            .collect::<Vec<_>>();
-        println!(
-            "{:.3}: Generating path cache...",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        let path_cache = (0..self.params.output.height)
+    fn generate_rows(&self, rows: Range<u16>, progress: &Progress) -> Vec<Vec<ResultPixel>> {
+        let terrain_cache = self.terrain_cache.get_or_init(|| {
+            println!(
+                "{:.3}: Generating terrain cache...",
+                self.start.elapsed().unwrap().as_secs_f64()
+            );
+            (0..self.params.output.width)
+                .into_par_iter()
+                .map(|x| {
+                    let dir = get_ray_dir(self.params, x);
+                    let terrain = gen_terrain_cache(self.params, self.terrain, dir);
+                    let sky_view = terrain.iter().map(|_| OnceLock::new()).collect();
+                    Column { terrain, sky_view }
+                })
+                .collect::<Vec<_>>()
+        });
+        let path_cache = rows
+            .clone()
             .into_par_iter()
             .map(|y| {
                 let ray_elev = get_ray_elev(self.params, y);
@@ -43,58 +56,47 @@ impl<'a, 'b> Generator for FastGenerator<'a, 'b> {
             })
             .collect::<Vec<_>>();
 
-        println!(
-            "{:.3}: Calculating pixels...",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        let count_pixels = AtomicUsize::new(0);
-        let total_pixels = self.params.output.width as usize * self.params.output.height as usize;
-        let result = (0..self.params.output.height)
+        rows.clone()
             .into_par_iter()
             .map(|y| {
                 (0..self.params.output.width)
                     .into_par_iter()
                     .map(|x| {
-                        let trace_points = get_single_pixel(
-                            terrain_cache[x as usize]
+                        let column = &terrain_cache[x as usize];
+                        let mut trace_points = get_single_pixel(
+                            column
+                                .terrain
                                 .iter()
                                 .cloned()
-                                .zip(path_cache[y as usize].iter().copied()),
+                                .zip(path_cache[(y - rows.start) as usize].iter().copied()),
                             self.params.scene.objects(),
                             &self.params.model,
                             self.params.scene.terrain_alpha,
                         );
+                        if let Coloring::Shading {
+                            ambient_occlusion: true,
+                            ..
+                        } = self.params.view.coloring
+                        {
+                            self.fill_sky_view(column, &mut trace_points);
+                        }
                         let mut azimuth = get_ray_dir(self.params, x);
                         if azimuth < 0.0 {
                             azimuth += 360.0;
                         } else if azimuth >= 360.0 {
                             azimuth -= 360.0
                         };
-                        let pixel = ResultPixel {
+                        progress.pixel_done();
+                        ResultPixel {
                             elevation_angle: get_ray_elev(self.params, y),
                             azimuth,
                             trace_points,
-                        };
-                        let pixels_done = count_pixels.fetch_add(1, Ordering::SeqCst);
-                        let prev_percent = pixels_done * 100 / total_pixels;
-                        let new_percent = (pixels_done + 1) * 100 / total_pixels;
-                        if new_percent > prev_percent {
-                            println!(
-                                "{:.3}: {}%...",
-                                self.start.elapsed().unwrap().as_secs_f64(),
-                                new_percent,
-                            );
+                            exit_elevation: None,
                         }
-                        pixel
                     })
                     .collect::<Vec<_>>()
             })
-            .collect::<Vec<_>>();
-        println!(
-            "{:.3}: Done calculating",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        result
+            .collect::<Vec<_>>()
     }
 }
 
@@ -104,6 +106,33 @@ impl<'a, 'b> FastGenerator<'a, 'b> {
             params,
             terrain,
             start,
+            terrain_cache: OnceLock::new(),
+        }
+    }
+
+    /// Sets the sky-view factors of the terrain points hit in a column, interpolated between the
+    /// points of the column around them.
+    fn fill_sky_view(&self, column: &Column, trace_points: &mut [TracePoint]) {
+        let sky_view = |index: usize| {
+            *column.sky_view[index].get_or_init(|| {
+                let data = &column.terrain[index];
+                sky_view_factor(self.params, self.terrain, data.lat, data.lon, data.elev)
+            })
+        };
+        let last = match column.terrain.len().checked_sub(2) {
+            Some(last) => last,
+            None => return,
+        };
+        for point in trace_points
+            .iter_mut()
+            .filter(|point| matches!(point.color, PixelColor::Terrain(_)))
+        {
+            // the points of the column are `simulation_step` apart, like the points of the paths
+            let pos = point.distance / self.params.simulation_step;
+            let index = (pos.floor() as usize).min(last);
+            let prop = (pos - index as f64).clamp(0.0, 1.0);
+            let (sky_view1, sky_view2) = (sky_view(index), sky_view(index + 1));
+            point.sky_view = Some(sky_view1 + (sky_view2 - sky_view1) * prop);
         }
     }
 }
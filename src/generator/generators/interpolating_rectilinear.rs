@@ -1,9 +1,7 @@
 use std::{
     collections::HashMap,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        RwLock,
-    },
+    ops::Range,
+    sync::{OnceLock, RwLock},
     time::SystemTime,
 };
 
@@ -11,7 +9,7 @@ use nalgebra::{Matrix, Vector3};
 use rayon::prelude::*;
 
 use super::{
-    utils::{gen_path_cache, gen_terrain_cache, get_single_pixel, PathElem, TerrainData},
+    utils::{gen_path_cache, gen_terrain_cache, get_single_pixel, PathElem, Progress, TerrainData},
     Generator, ResultPixel, TracePoint,
 };
 
@@ -21,6 +19,9 @@ pub struct InterpolatingRectilinearGenerator<'a, 'b> {
     params: &'a Params,
     terrain: &'b Terrain,
     start: SystemTime,
+    /// The directions of the pixels and the cache of the pixels they are interpolated from,
+    /// generated when the first rows are calculated.
+    fov_data: OnceLock<(FovData, Cache)>,
 }
 
 struct Cache {
@@ -100,6 +101,7 @@ impl Cache {
                 elevation_angle: (point.elev_index as f64 * self.min_elev_step).to_degrees(),
                 azimuth,
                 trace_points,
+                exit_elevation: None,
             };
             self.pixels.write().unwrap().insert(point, result.clone());
             result
@@ -108,24 +110,18 @@ impl Cache {
 }
 
 impl<'a, 'b> Generator for InterpolatingRectilinearGenerator<'a, 'b> {
-    fn generate(&self) -> Vec<Vec<ResultPixel>> {
-        println!(
-            "{:.3}: Generating FoV data...",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        let fov_data = self.gen_fov_data();
-
-        println!(
-            "{:.3}: Calculating pixels...",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        let count_pixels = AtomicUsize::new(0);
-        let total_pixels = self.params.output.width as usize * self.params.output.height as usize;
-
-        let cache = Cache::new(fov_data.min_elev_step, fov_data.min_dir_step);
+    fn generate_rows(&self, rows: Range<u16>, progress: &Progress) -> Vec<Vec<ResultPixel>> {
+        let (fov_data, cache) = self.fov_data.get_or_init(|| {
+            println!(
+                "{:.3}: Generating FoV data...",
+                self.start.elapsed().unwrap().as_secs_f64()
+            );
+            let fov_data = self.gen_fov_data();
+            let cache = Cache::new(fov_data.min_elev_step, fov_data.min_dir_step);
+            (fov_data, cache)
+        });
 
-        let result = (0..self.params.output.height)
-            .into_par_iter()
+        rows.into_par_iter()
             .map(|y| {
                 (0..self.params.output.width)
                     .into_par_iter()
@@ -138,26 +134,12 @@ impl<'a, 'b> Generator for InterpolatingRectilinearGenerator<'a, 'b> {
                             .collect();
                         let pixel =
                             interpolate(pixels, rem_elev, rem_dir, self.params.simulation_step);
-                        let pixels_done = count_pixels.fetch_add(1, Ordering::SeqCst);
-                        let prev_percent = pixels_done * 100 / total_pixels;
-                        let new_percent = (pixels_done + 1) * 100 / total_pixels;
-                        if new_percent > prev_percent {
-                            println!(
-                                "{:.3}: {}%...",
-                                self.start.elapsed().unwrap().as_secs_f64(),
-                                new_percent,
-                            );
-                        }
+                        progress.pixel_done();
                         pixel
                     })
                     .collect::<Vec<_>>()
             })
-            .collect::<Vec<_>>();
-        println!(
-            "{:.3}: Done calculating",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        result
+            .collect::<Vec<_>>()
     }
 }
 
@@ -414,6 +396,7 @@ fn interpolate(
             + pixels[2].azimuth * rem_elev * (1.0 - rem_dir)
             + pixels[3].azimuth * rem_elev * rem_dir,
         trace_points,
+        exit_elevation: None,
     }
 }
 
@@ -423,6 +406,7 @@ impl<'a, 'b> InterpolatingRectilinearGenerator<'a, 'b> {
             params,
             terrain,
             start,
+            fov_data: OnceLock::new(),
         }
     }
 
@@ -0,0 +1,198 @@
+use atm_refraction::EarthShape;
+use nalgebra::Vector3;
+use rayon::prelude::*;
+
+use crate::{generator::params::Params, terrain::Terrain};
+
+use super::{PixelColor, ResultPixel, TracePoint};
+
+/// No terrain is higher than this, so rays above it can't be blocked anymore.
+const MAX_TERRAIN_ELEVATION: f64 = 9000.0;
+/// How high above the surface the shadow rays start, so that the surface doesn't shadow itself.
+const SHADOW_RAY_OFFSET: f64 = 1.0;
+
+/// The number of directions in which the horizon is searched for the sky-view factor.
+const SKY_VIEW_DIRECTIONS: usize = 8;
+/// The distances at which the terrain is sampled in every direction, in meters.
+const SKY_VIEW_DISTANCES: [f64; 7] = [30.0, 60.0, 120.0, 250.0, 500.0, 1000.0, 2000.0];
+
+/// Calculates the fraction of the sky visible from a point on the terrain, from the elevation
+/// angles of the horizon in a number of directions around it.
+pub fn sky_view_factor(params: &Params, terrain: &Terrain, lat: f64, lon: f64, elev: f64) -> f64 {
+    let curvature = curvature(&params.env.shape);
+    let horizon_sum: f64 = (0..SKY_VIEW_DIRECTIONS)
+        .map(|i| {
+            let azimuth = i as f64 * 360.0 / SKY_VIEW_DIRECTIONS as f64;
+            let dist_calc = params.model.coords_at_dist_calc((lat, lon), azimuth);
+            SKY_VIEW_DISTANCES
+                .iter()
+                .map(|&dist| {
+                    let (lat, lon) = dist_calc.coords_at_dist(dist);
+                    let height =
+                        terrain.get_elev(lat, lon).unwrap_or(0.0) - elev - dist * dist * curvature;
+                    // the sine of the elevation angle of the terrain
+                    height / height.hypot(dist)
+                })
+                .fold(0.0, f64::max)
+        })
+        .sum();
+    1.0 - horizon_sum / SKY_VIEW_DIRECTIONS as f64
+}
+
+/// The coefficient of the drop of the surface below a tangent straight line.
+fn curvature(shape: &EarthShape) -> f64 {
+    match *shape {
+        EarthShape::Spherical { radius } => 0.5 / radius,
+        EarthShape::Flat => 0.0,
+    }
+}
+
+/// Checks whether the terrain blocks the light coming to the point from the direction
+/// `light_dir`. The light travels along a straight line.
+fn is_in_shadow(
+    params: &Params,
+    terrain: &Terrain,
+    light_dir: &Vector3<f64>,
+    point: &TracePoint,
+) -> bool {
+    let (dir_north, dir_east, dir_up) = params.model.world_directions(point.lat, point.lon);
+    let elevation = light_dir.dot(&dir_up).clamp(-1.0, 1.0).asin();
+    let azimuth = light_dir.dot(&dir_east).atan2(light_dir.dot(&dir_north));
+    let dist_calc = params
+        .model
+        .coords_at_dist_calc((point.lat, point.lon), azimuth.to_degrees());
+    let curvature = curvature(&params.env.shape);
+
+    let start = point.elevation + SHADOW_RAY_OFFSET;
+    let mut dist = params.simulation_step;
+    while dist < params.view.frame.max_distance {
+        let ray_elev = start + dist * elevation.tan() + dist * dist * curvature;
+        if ray_elev > MAX_TERRAIN_ELEVATION {
+            return false;
+        }
+        let (lat, lon) = dist_calc.coords_at_dist(dist);
+        if terrain.get_elev(lat, lon).unwrap_or(0.0) > ray_elev {
+            return true;
+        }
+        dist += params.simulation_step;
+    }
+    false
+}
+
+/// Calculates the sky-view factor of the points of the terrain hit by the rays, unless it was
+/// calculated in advance.
+pub fn calc_sky_view(params: &Params, terrain: &Terrain, pixels: &mut [Vec<ResultPixel>]) {
+    pixels.par_iter_mut().for_each(|row| {
+        row.par_iter_mut()
+            .flat_map(|pixel| pixel.trace_points.par_iter_mut())
+            .filter(|point| {
+                matches!(point.color, PixelColor::Terrain(_)) && point.sky_view.is_none()
+            })
+            .for_each(|point| {
+                point.sky_view = Some(sky_view_factor(
+                    params,
+                    terrain,
+                    point.lat,
+                    point.lon,
+                    point.elevation,
+                ));
+            });
+    });
+}
+
+/// Marks the points in the pixels that the terrain shadows from the light coming from the
+/// direction `light_dir`.
+pub fn calc_shadows(
+    params: &Params,
+    terrain: &Terrain,
+    light_dir: Vector3<f64>,
+    pixels: &mut [Vec<ResultPixel>],
+) {
+    pixels.par_iter_mut().for_each(|row| {
+        row.par_iter_mut()
+            .flat_map(|pixel| pixel.trace_points.par_iter_mut())
+            .for_each(|point| {
+                // surfaces facing away from the light are dark anyway
+                let lit = point.normal.dot(&light_dir) > 0.0
+                    && !is_in_shadow(params, terrain, &light_dir, point);
+                point.shadow = if lit { 0.0 } else { 1.0 };
+            });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generator::params::Config, terrain::FnTile};
+
+    /// The direction of light coming from the given azimuth and elevation angle (in degrees).
+    fn light_dir(
+        params: &Params,
+        point: &TracePoint,
+        azimuth: f64,
+        elevation: f64,
+    ) -> Vector3<f64> {
+        let (dir_north, dir_east, dir_up) = params.model.world_directions(point.lat, point.lon);
+        let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+        dir_north * elevation.cos() * azimuth.cos()
+            + dir_east * elevation.cos() * azimuth.sin()
+            + dir_up * elevation.sin()
+    }
+
+    #[test]
+    fn test_shadow_behind_ridge() {
+        // a 500 m high ridge running east-west, 1 km north of the point
+        let mut terrain = Terrain::new();
+        terrain.add_tile(FnTile {
+            lat: 50.0,
+            lon: 20.0,
+            elev: |lat: f64, _| {
+                if (50.51..50.52).contains(&lat) {
+                    500.0
+                } else {
+                    0.0
+                }
+            },
+        });
+        let params = Config::default().into_params(&terrain).unwrap();
+        let point = TracePoint::test_point(50.5, 20.5, 0.0, Vector3::zeros());
+
+        let in_shadow = |azimuth, elevation| {
+            is_in_shadow(
+                &params,
+                &terrain,
+                &light_dir(&params, &point, azimuth, elevation),
+                &point,
+            )
+        };
+        assert!(in_shadow(0.0, 10.0));
+        assert!(in_shadow(20.0, 20.0));
+        // the light comes from above the ridge, or from the other side
+        assert!(!in_shadow(0.0, 40.0));
+        assert!(!in_shadow(180.0, 10.0));
+        assert!(!in_shadow(90.0, 5.0));
+    }
+
+    #[test]
+    fn test_sky_view_factor() {
+        // a pit 200 m deep and 500 m wide in otherwise flat terrain
+        let mut terrain = Terrain::new();
+        terrain.add_tile(FnTile {
+            lat: 50.0,
+            lon: 20.0,
+            elev: |lat: f64, lon: f64| {
+                if (50.498..50.502).contains(&lat) && (20.497..20.503).contains(&lon) {
+                    -200.0
+                } else {
+                    0.0
+                }
+            },
+        });
+        let params = Config::default().into_params(&terrain).unwrap();
+
+        let flat = sky_view_factor(&params, &terrain, 50.3, 20.3, 0.0);
+        assert!((flat - 1.0).abs() < 1e-9, "{}", flat);
+        let pit = sky_view_factor(&params, &terrain, 50.5, 20.5, -200.0);
+        assert!(pit < 0.6, "{}", pit);
+    }
+}
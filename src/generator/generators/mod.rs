@@ -1,23 +1,58 @@
 mod fast;
 mod interpolating_rectilinear;
+mod lighting;
 mod rectilinear;
+mod stepper;
 mod utils;
 
+use std::ops::Range;
+
 use nalgebra::Vector3;
 
 use crate::object::Color;
 
 pub use fast::FastGenerator;
 pub use interpolating_rectilinear::InterpolatingRectilinearGenerator;
+pub use lighting::{calc_shadows, calc_sky_view};
 pub use rectilinear::RectilinearGenerator;
+pub use stepper::{cast_ray_stepper, ray_stepper};
+pub use utils::{
+    calc_exit_elevations, calc_surface_colors, gen_path_cache, gen_terrain_cache, PathElem,
+    Progress, TerrainData,
+};
 
+/// The result of tracing the ray of a single pixel.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResultPixel {
+    /// The elevation angle of the pixel in degrees.
     pub elevation_angle: f64,
+    /// The azimuth of the pixel in degrees, from 0 to 360.
     pub azimuth: f64,
+    /// The points hit by the ray, from the nearest one; the ray goes on past the ones that aren't
+    /// opaque.
     pub trace_points: Vec<TracePoint>,
+    /// The elevation angle at which the ray leaves the atmosphere, if it does; only calculated
+    /// if there are celestial objects in the scene.
+    pub exit_elevation: Option<f64>,
+}
+
+impl ResultPixel {
+    pub fn direction(&self) -> PixelDirection {
+        PixelDirection {
+            elevation_angle: self.elevation_angle,
+            azimuth: self.azimuth,
+        }
+    }
+}
+
+/// The viewing direction of a pixel, which is all that is needed to draw the overlays on the image.
+#[derive(Debug, Clone, Copy)]
+pub struct PixelDirection {
+    pub elevation_angle: f64,
+    pub azimuth: f64,
 }
 
+/// A point of the terrain or of an object hit by a ray.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct TracePoint {
     pub lat: f64,
@@ -25,7 +60,18 @@ pub struct TracePoint {
     pub distance: f64,
     pub elevation: f64,
     pub path_length: f64,
+    pub air_column: f64,
+    pub aerosol_column: f64,
     pub normal: Vector3<f64>,
+    /// How much of the point is shadowed from the light by the terrain (0 - not at all, 1 -
+    /// completely); only calculated if the coloring casts shadows.
+    pub shadow: f64,
+    /// The fraction of the sky visible from the point, which scales the ambient light; only
+    /// calculated for the terrain if the coloring uses ambient occlusion.
+    pub sky_view: Option<f64>,
+    /// The color of the surface at the point from the land cover or the texture, if they are
+    /// defined there; replaces the color palette of the coloring method.
+    pub surface_color: Option<SurfaceColor>,
     pub color: PixelColor,
 }
 
@@ -37,12 +83,68 @@ impl TracePoint {
             distance: self.distance * (1.0 - coeff) + other.distance * coeff,
             elevation: self.elevation * (1.0 - coeff) + other.elevation * coeff,
             path_length: self.path_length * (1.0 - coeff) + other.path_length * coeff,
+            air_column: self.air_column * (1.0 - coeff) + other.air_column * coeff,
+            aerosol_column: self.aerosol_column * (1.0 - coeff) + other.aerosol_column * coeff,
             normal: self.normal * (1.0 - coeff) + other.normal * coeff,
+            shadow: self.shadow * (1.0 - coeff) + other.shadow * coeff,
+            sky_view: match (self.sky_view, other.sky_view) {
+                (Some(sky_view1), Some(sky_view2)) => {
+                    Some(sky_view1 * (1.0 - coeff) + sky_view2 * coeff)
+                }
+                (sky_view1, sky_view2) => {
+                    if coeff < 0.5 {
+                        sky_view1
+                    } else {
+                        sky_view2
+                    }
+                }
+            },
+            surface_color: match (self.surface_color, other.surface_color) {
+                (Some(color1), Some(color2)) => Some(SurfaceColor {
+                    color: color1.color * (1.0 - coeff) + color2.color * coeff,
+                    shaded: color1.shaded,
+                }),
+                (color1, color2) => {
+                    if coeff < 0.5 {
+                        color1
+                    } else {
+                        color2
+                    }
+                }
+            },
             color: self.color.interpolate(&other.color, coeff),
         }
     }
 }
 
+#[cfg(test)]
+impl TracePoint {
+    /// A terrain point at the given distance, with the other fields zeroed, for the tests.
+    pub(crate) fn test_point(lat: f64, lon: f64, distance: f64, normal: Vector3<f64>) -> Self {
+        Self {
+            lat,
+            lon,
+            distance,
+            elevation: 0.0,
+            path_length: distance,
+            air_column: 0.0,
+            aerosol_column: 0.0,
+            normal,
+            shadow: 0.0,
+            sky_view: None,
+            surface_color: None,
+            color: PixelColor::Terrain(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SurfaceColor {
+    pub color: Vector3<f64>,
+    /// Whether the lighting of the coloring method should be applied to the color.
+    pub shaded: bool,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum PixelColor {
     Terrain(f64),
@@ -80,5 +182,6 @@ impl PixelColor {
 }
 
 pub trait Generator {
-    fn generate(&self) -> Vec<Vec<ResultPixel>>;
+    /// Calculates the pixels in the given rows of the image.
+    fn generate_rows(&self, rows: Range<u16>, progress: &Progress) -> Vec<Vec<ResultPixel>>;
 }
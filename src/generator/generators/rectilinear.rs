@@ -1,14 +1,12 @@
-use std::{
-    sync::atomic::{AtomicUsize, Ordering},
-    time::SystemTime,
-};
+use std::ops::Range;
 
 use atm_refraction::{PathStepper, RayState};
 use nalgebra::{Matrix, Vector3};
 use rayon::prelude::*;
 
 use super::{
-    utils::{calc_dist, get_single_pixel, PathElem, TerrainData},
+    cast_ray_stepper,
+    utils::{calc_columns, calc_dist, get_single_pixel, PathElem, Progress, TerrainData},
     Generator, ResultPixel,
 };
 
@@ -17,46 +15,23 @@ use crate::{generator::params::Params, terrain::Terrain, utils::DirectionalCalc}
 pub struct RectilinearGenerator<'a, 'b> {
     params: &'a Params,
     terrain: &'b Terrain,
-    start: SystemTime,
 }
 
 impl<'a, 'b> Generator for RectilinearGenerator<'a, 'b> {
-    fn generate(&self) -> Vec<Vec<ResultPixel>> {
-        println!(
-            "{:.3}: Calculating pixels...",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        let count_pixels = AtomicUsize::new(0);
-        let total_pixels = self.params.output.width as usize * self.params.output.height as usize;
-
-        let result = (0..self.params.output.height)
-            .into_par_iter()
+    fn generate_rows(&self, rows: Range<u16>, progress: &Progress) -> Vec<Vec<ResultPixel>> {
+        rows.into_par_iter()
             .map(|y| {
                 (0..self.params.output.width)
                     .into_par_iter()
                     .map(|x| {
                         let ray_params = self.get_ray_params(x, y);
                         let pixel = self.gen_pixel(ray_params);
-                        let pixels_done = count_pixels.fetch_add(1, Ordering::SeqCst);
-                        let prev_percent = pixels_done * 100 / total_pixels;
-                        let new_percent = (pixels_done + 1) * 100 / total_pixels;
-                        if new_percent > prev_percent {
-                            println!(
-                                "{:.3}: {}%...",
-                                self.start.elapsed().unwrap().as_secs_f64(),
-                                new_percent,
-                            );
-                        }
+                        progress.pixel_done();
                         pixel
                     })
                     .collect::<Vec<_>>()
             })
-            .collect::<Vec<_>>();
-        println!(
-            "{:.3}: Done calculating",
-            self.start.elapsed().unwrap().as_secs_f64()
-        );
-        result
+            .collect::<Vec<_>>()
     }
 }
 
@@ -67,12 +42,8 @@ struct RayParams {
 }
 
 impl<'a, 'b> RectilinearGenerator<'a, 'b> {
-    pub fn new(params: &'a Params, terrain: &'b Terrain, start: SystemTime) -> Self {
-        Self {
-            params,
-            terrain,
-            start,
-        }
+    pub fn new(params: &'a Params, terrain: &'b Terrain) -> Self {
+        Self { params, terrain }
     }
 
     fn get_ray_params(&self, x: u16, y: u16) -> RayParams {
@@ -111,12 +82,15 @@ impl<'a, 'b> RectilinearGenerator<'a, 'b> {
             elevation_angle: ray_params.elevation.to_degrees(),
             azimuth: ray_params.direction.to_degrees(),
             trace_points,
+            exit_elevation: None,
         }
     }
 }
 
 struct PathIterator<'a, 'b> {
     path_length: f64,
+    air_column: f64,
+    aerosol_column: f64,
     ray_state: RayState,
     ray: Box<dyn PathStepper<Item = RayState> + 'a>,
     dist_calc: Box<dyn DirectionalCalc>,
@@ -131,10 +105,7 @@ impl<'a, 'b> PathIterator<'a, 'b> {
             params.view.position.latitude,
             params.view.position.longitude,
         );
-        let mut ray = params
-            .env
-            .cast_ray_stepper(alt, ray_params.elevation, params.straight_rays);
-        ray.set_step_size(params.simulation_step);
+        let ray = cast_ray_stepper(params, alt, ray_params.elevation);
 
         let dist_calc = params.model.coords_at_dist_calc(
             (
@@ -146,6 +117,8 @@ impl<'a, 'b> PathIterator<'a, 'b> {
 
         Self {
             path_length: 0.0,
+            air_column: 0.0,
+            aerosol_column: 0.0,
             ray_state: RayState {
                 x: 0.0,
                 h: alt,
@@ -163,6 +136,8 @@ impl<'a, 'b> PathIterator<'a, 'b> {
             dist: self.ray_state.x,
             elev: self.ray_state.h,
             path_length: self.path_length,
+            air_column: self.air_column,
+            aerosol_column: self.aerosol_column,
         };
         let (lat, lon) = self.dist_calc.coords_at_dist(elem.dist);
         let terrain_data = TerrainData::from_lat_lon(lat, lon, self.params, self.terrain);
@@ -179,7 +154,11 @@ impl<'a, 'b> Iterator for PathIterator<'a, 'b> {
             return None;
         }
         let new_state = self.ray.next()?;
-        self.path_length += calc_dist(self.params, self.ray_state, new_state);
+        let step_length = calc_dist(self.params, self.ray_state, new_state);
+        let (air, aerosol) = calc_columns(self.params, self.ray_state, new_state, step_length);
+        self.path_length += step_length;
+        self.air_column += air;
+        self.aerosol_column += aerosol;
         self.ray_state = new_state;
         Some(point)
     }
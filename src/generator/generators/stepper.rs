@@ -0,0 +1,389 @@
+use atm_refraction::{EarthShape, Environment, PathStepper, RayState, RayStateDerivative};
+use numeric_algs::integration::{Integrator as _, RK4Integrator, RK8Integrator, StepSize};
+
+use crate::generator::params::{Integrator, Params};
+
+/// Creates a stepper for a ray starting at the altitude `alt` with the elevation angle `elevation`
+/// (in radians), using the integrator chosen in the parameters.
+///
+/// Regardless of the integrator, consecutive states returned by the stepper are always
+/// `simulation_step` apart, so that they stay consistent with the terrain cache.
+pub fn cast_ray_stepper<'a>(
+    params: &'a Params,
+    alt: f64,
+    elevation: f64,
+) -> Box<dyn PathStepper<Item = RayState> + 'a> {
+    ray_stepper(
+        &params.env,
+        params.integrator,
+        params.straight_rays,
+        alt,
+        elevation,
+        params.simulation_step,
+    )
+}
+
+/// Creates a stepper like `cast_ray_stepper`, with the states `step` apart, for the rays traced
+/// without the full `Params`.
+pub fn ray_stepper(
+    env: &Environment,
+    integrator: Integrator,
+    straight_rays: bool,
+    alt: f64,
+    elevation: f64,
+    step: f64,
+) -> Box<dyn PathStepper<Item = RayState> + '_> {
+    let mut stepper = match integrator {
+        _ if straight_rays => env.cast_ray_stepper(alt, elevation, true),
+        Integrator::Rk4 => env.cast_ray_stepper(alt, elevation, false),
+        Integrator::Rk8 => Box::new(Rk8Stepper {
+            env,
+            state: initial_state(env, alt, elevation),
+            integrator: RK8Integrator::new(step),
+        }),
+        Integrator::Adaptive {
+            min_step,
+            max_step,
+            max_error,
+        } => Box::new(AdaptiveStepper::new(
+            env,
+            initial_state(env, alt, elevation),
+            (min_step, max_step),
+            max_error,
+        )),
+    };
+    stepper.set_step_size(step);
+    stepper
+}
+
+fn initial_state(env: &Environment, alt: f64, elevation: f64) -> RayState {
+    let dh = match env.shape {
+        EarthShape::Flat => elevation.tan(),
+        EarthShape::Spherical { radius } => (alt + radius) * elevation.tan() / radius,
+    };
+    RayState { x: 0.0, h: alt, dh }
+}
+
+fn ray_derivative(env: &Environment, state: &RayState) -> RayStateDerivative {
+    // the atmosphere can't be evaluated at a non-finite altitude, so just propagate the NaN
+    if !state.h.is_finite() {
+        return RayStateDerivative {
+            dx: 1.0,
+            dh: state.dh,
+            d2h: f64::NAN,
+        };
+    }
+
+    let n = env.n(state.h);
+    let dn = env.dn(state.h);
+
+    let d2h = match env.shape {
+        EarthShape::Flat => dn / n * (1.0 + state.dh * state.dh),
+        EarthShape::Spherical { radius } => {
+            let dh = state.dh * radius;
+            let r = state.h + radius;
+            (dh * dh * dn / n + r * r * dn / n + 2.0 * dh * dh / r + r) / radius / radius
+        }
+    };
+
+    RayStateDerivative {
+        dx: 1.0,
+        dh: state.dh,
+        d2h,
+    }
+}
+
+struct Rk8Stepper<'a> {
+    env: &'a Environment,
+    state: RayState,
+    integrator: RK8Integrator,
+}
+
+impl Iterator for Rk8Stepper<'_> {
+    type Item = RayState;
+
+    fn next(&mut self) -> Option<RayState> {
+        let env = self.env;
+        self.integrator.propagate_in_place(
+            &mut self.state,
+            |state| ray_derivative(env, state),
+            StepSize::UseDefault,
+        );
+        Some(self.state)
+    }
+}
+
+impl PathStepper for Rk8Stepper<'_> {
+    fn set_step_size(&mut self, step: f64) {
+        self.integrator.set_default_step(step);
+    }
+}
+
+/// A stepper that chooses its internal step size based on an error estimate (obtained by step
+/// doubling), so that the steps are short where the refractive index changes quickly and long
+/// where it is smooth. The returned states are interpolated onto a fixed grid.
+struct AdaptiveStepper<'a> {
+    env: &'a Environment,
+    integrator: RK4Integrator,
+    min_step: f64,
+    max_step: f64,
+    max_error: f64,
+    step: f64,
+    grid_step: f64,
+    last_x: f64,
+    prev_state: RayState,
+    next_state: RayState,
+}
+
+impl<'a> AdaptiveStepper<'a> {
+    fn new(
+        env: &'a Environment,
+        state: RayState,
+        (min_step, max_step): (f64, f64),
+        max_error: f64,
+    ) -> Self {
+        Self {
+            env,
+            integrator: RK4Integrator::new(max_step),
+            min_step,
+            max_step,
+            max_error,
+            step: max_step,
+            grid_step: max_step,
+            last_x: state.x,
+            prev_state: state,
+            next_state: state,
+        }
+    }
+
+    fn propagate(&mut self, state: &RayState, step: f64) -> RayState {
+        let env = self.env;
+        self.integrator.propagate(
+            state,
+            |state| ray_derivative(env, state),
+            StepSize::Step(step),
+        )
+    }
+
+    /// Performs a single internal step, shortening it until the error estimate is acceptable.
+    fn advance(&mut self) {
+        let start = self.next_state;
+        loop {
+            let step = self.step;
+            let full = self.propagate(&start, step);
+            let half = self.propagate(&start, step / 2.0);
+            let half = self.propagate(&half, step / 2.0);
+            // Richardson estimate of the error of the more accurate result; the error of the
+            // slope is converted into the altitude error it causes over the step
+            let error = (full.h - half.h)
+                .abs()
+                .max((full.dh - half.dh).abs() * step)
+                / 15.0;
+
+            // a non-finite state (e.g. in a degenerate atmosphere) has no meaningful error
+            // estimate - retry with the shortest step and accept whatever it gives, so that the
+            // ray still advances instead of looping forever
+            if !error.is_finite() {
+                self.step = self.min_step;
+                if step <= self.min_step {
+                    self.prev_state = start;
+                    self.next_state = half;
+                    return;
+                }
+                continue;
+            }
+
+            let factor = if error > 0.0 {
+                0.9 * (self.max_error / error).powf(0.2)
+            } else {
+                2.0
+            };
+            self.step = (step * factor.clamp(0.2, 2.0)).clamp(self.min_step, self.max_step);
+
+            if error <= self.max_error || step <= self.min_step {
+                self.prev_state = start;
+                self.next_state = half;
+                return;
+            }
+        }
+    }
+
+    /// Interpolates the state at `x` between the last two internal states with a cubic Hermite
+    /// polynomial.
+    fn interpolate(&self, x: f64) -> RayState {
+        let (p0, p1) = (self.prev_state, self.next_state);
+        let dx = p1.x - p0.x;
+        if dx <= 0.0 {
+            return p1;
+        }
+        let t = (x - p0.x) / dx;
+        let (t2, t3) = (t * t, t * t * t);
+
+        let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+        let h10 = t3 - 2.0 * t2 + t;
+        let h01 = -2.0 * t3 + 3.0 * t2;
+        let h11 = t3 - t2;
+        let h = h00 * p0.h + h10 * dx * p0.dh + h01 * p1.h + h11 * dx * p1.dh;
+
+        let dh00 = (6.0 * t2 - 6.0 * t) / dx;
+        let dh10 = 3.0 * t2 - 4.0 * t + 1.0;
+        let dh01 = (-6.0 * t2 + 6.0 * t) / dx;
+        let dh11 = 3.0 * t2 - 2.0 * t;
+        let dh = dh00 * p0.h + dh10 * p0.dh + dh01 * p1.h + dh11 * p1.dh;
+
+        RayState { x, h, dh }
+    }
+}
+
+impl Iterator for AdaptiveStepper<'_> {
+    type Item = RayState;
+
+    fn next(&mut self) -> Option<RayState> {
+        let target = self.last_x + self.grid_step;
+        while self.next_state.x < target {
+            self.advance();
+        }
+        self.last_x = target;
+        Some(self.interpolate(target))
+    }
+}
+
+impl PathStepper for AdaptiveStepper<'_> {
+    fn set_step_size(&mut self, step: f64) {
+        self.grid_step = step;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generator::params::Config, terrain::Terrain};
+
+    /// A temperature inversion just above the observer, bending the rays strongly.
+    const CONFIG: &str = "
+atmosphere:
+    pressure_fixed_point:
+        altitude: 0.0
+        pressure: 101325
+    first_temperature_function:
+        Linear:
+            gradient: -0.0065
+    next_functions:
+        - altitude: 20.0
+          function:
+            Spline:
+                boundary_condition: Natural
+                points: [[20.0, 287.0], [30.0, 292.0], [40.0, 296.0], [60.0, 297.0]]
+        - altitude: 60.0
+          function:
+            Linear:
+                gradient: -0.0065
+integrator:
+    Adaptive:
+        max_error: 0.000001
+";
+
+    /// The adaptive stepper should give the same rays as RK4 with a short step, resampled to
+    /// `simulation_step`.
+    #[test]
+    fn test_adaptive_stepper() {
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        let params = config.into_params(&Terrain::new()).unwrap();
+
+        for elevation in [0.05f64, 0.2, 0.5] {
+            let elevation = elevation.to_radians();
+            let reference: Vec<_> =
+                ray_stepper(&params.env, Integrator::Rk4, false, 10.0, elevation, 1.0)
+                    .take(50_000)
+                    .collect();
+            let adaptive: Vec<_> = cast_ray_stepper(&params, 10.0, elevation)
+                .take(1000)
+                .collect();
+
+            let mut last_x = 0.0;
+            for state in &adaptive {
+                assert!((state.x - last_x - params.simulation_step).abs() < 1e-9);
+                last_x = state.x;
+            }
+
+            for index in [99, 399, 999] {
+                let state = adaptive[index];
+                let expected = reference[state.x.round() as usize - 1];
+                assert!((expected.x - state.x).abs() < 1e-6);
+                assert!(
+                    (state.h - expected.h).abs() < 0.1,
+                    "{:?} {:?}",
+                    state,
+                    expected
+                );
+                assert!(
+                    (state.dh - expected.dh).abs() < 1e-5,
+                    "{:?} {:?}",
+                    state,
+                    expected
+                );
+            }
+        }
+    }
+
+    /// A non-finite state has no finite error estimate; the stepper should still advance along
+    /// the grid with the shortest step instead of hanging.
+    #[test]
+    fn test_adaptive_stepper_nan_state() {
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        let params = config.into_params(&Terrain::new()).unwrap();
+
+        let state = RayState {
+            x: 0.0,
+            h: f64::NAN,
+            dh: 0.0,
+        };
+        let mut stepper = AdaptiveStepper::new(&params.env, state, (1.0, 50.0), 1e-6);
+        stepper.set_step_size(10.0);
+        let states: Vec<_> = stepper.by_ref().take(5).collect();
+
+        for (index, state) in states.iter().enumerate() {
+            assert!((state.x - 10.0 * (index + 1) as f64).abs() < 1e-9);
+            assert!(state.h.is_nan());
+        }
+        assert_eq!(stepper.step, 1.0);
+    }
+
+    /// The RK8 stepper uses its own copy of the ray equations, which should give the same rays as
+    /// the RK4 stepper of atm-refraction.
+    #[test]
+    fn test_rk8_stepper() {
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        let params = config.into_params(&Terrain::new()).unwrap();
+
+        for elevation in [0.0f64, 0.1, 1.0] {
+            let elevation = elevation.to_radians();
+            // RK4 with a much shorter step as the reference
+            let rk4: Vec<_> =
+                ray_stepper(&params.env, Integrator::Rk4, false, 10.0, elevation, 0.5)
+                    .take(40_000)
+                    .collect();
+            let rk8: Vec<_> =
+                ray_stepper(&params.env, Integrator::Rk8, false, 10.0, elevation, 5.0)
+                    .take(4000)
+                    .collect();
+
+            for index in [9, 399, 3999] {
+                let (expected, state) = (rk4[index * 10 + 9], rk8[index]);
+                assert!((expected.x - state.x).abs() < 1e-6);
+                assert!(
+                    (state.h - expected.h).abs() < 0.01,
+                    "{:?} {:?}",
+                    state,
+                    expected
+                );
+                assert!(
+                    (state.dh - expected.dh).abs() < 1e-6,
+                    "{:?} {:?}",
+                    state,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -1,16 +1,24 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
 
 use atm_refraction::{EarthShape, RayState};
 use nalgebra::Vector3;
+use rayon::prelude::*;
 
 use crate::{
     generator::params::Params,
     object::Object,
     terrain::Terrain,
-    utils::{Coords, EarthModel},
+    utils::{exit_elevation, Coords, EarthModel, EXIT_STEP},
 };
 
-use super::{PixelColor, TracePoint};
+use super::{cast_ray_stepper, PixelColor, ResultPixel, SurfaceColor, TracePoint};
+
+/// The number of rays traced out of the atmosphere per row of pixels.
+const EXIT_RAYS_PER_ROW: usize = 4;
 
 pub fn find_normal(model: &EarthModel, lat: f64, lon: f64, terrain: &Terrain) -> Vector3<f64> {
     const DIFF: f64 = 15.0;
@@ -52,11 +60,50 @@ pub fn calc_dist(params: &Params, old_state: RayState, new_state: RayState) -> f
     }
 }
 
+/// The scale height of the aerosol density, in meters.
+const AEROSOL_SCALE_HEIGHT: f64 = 1200.0;
+const STD_PRESSURE: f64 = 101_325.0;
+const STD_TEMPERATURE: f64 = 288.15;
+
+/// Returns the densities of the air and of the aerosols at the given altitude, relative to their
+/// values at sea level in standard conditions.
+fn relative_densities(params: &Params, h: f64) -> (f64, f64) {
+    let atmosphere = &params.env.atmosphere;
+    let air = atmosphere.pressure(h) / atmosphere.temperature(h) * STD_TEMPERATURE / STD_PRESSURE;
+    let aerosol = (-h / AEROSOL_SCALE_HEIGHT).exp();
+    (air, aerosol)
+}
+
+/// Calculates the air and aerosol columns (see `PathElem`) passed by the ray in a single step of
+/// the given length. The columns are only used for the extinction, so they are left at zero when
+/// it's disabled.
+pub fn calc_columns(
+    params: &Params,
+    old_state: RayState,
+    new_state: RayState,
+    length: f64,
+) -> (f64, f64) {
+    if params.view.extinction.is_none() {
+        return (0.0, 0.0);
+    }
+    let (old_air, old_aerosol) = relative_densities(params, old_state.h);
+    let (new_air, new_aerosol) = relative_densities(params, new_state.h);
+    (
+        (old_air + new_air) / 2.0 * length,
+        (old_aerosol + new_aerosol) / 2.0 * length,
+    )
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PathElem {
     pub dist: f64,
     pub elev: f64,
     pub path_length: f64,
+    /// The air density integrated along the path, expressed as the length of a path in sea level
+    /// air in standard conditions with the same amount of air.
+    pub air_column: f64,
+    /// The aerosol density integrated along the path, like `air_column`.
+    pub aerosol_column: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -78,10 +125,11 @@ impl TerrainData {
             .filter(|(_, obj)| obj.is_close(&params.model, params.simulation_step, lat, lon))
             .map(|(index, _)| index)
             .collect();
+        let elev = terrain.get_elev(lat, lon).unwrap_or(0.0);
         TerrainData {
             lat,
             lon,
-            elev: terrain.get_elev(lat, lon).unwrap_or(0.0),
+            elev,
             normal,
             objects_close,
         }
@@ -93,15 +141,19 @@ struct TracingState {
     ray_elev: f64,
     dist: f64,
     path_len: f64,
+    air_column: f64,
+    aerosol_column: f64,
 }
 
 impl TracingState {
-    fn new(terrain_data: &TerrainData, ray_elev: f64, dist: f64, path_len: f64) -> Self {
+    fn new(terrain_data: &TerrainData, path_elem: &PathElem) -> Self {
         Self {
             terrain_data: terrain_data.clone(),
-            ray_elev,
-            dist,
-            path_len,
+            ray_elev: path_elem.elev,
+            dist: path_elem.dist,
+            path_len: path_elem.path_length,
+            air_column: path_elem.air_column,
+            aerosol_column: path_elem.aerosol_column,
         }
     }
 
@@ -121,6 +173,9 @@ impl TracingState {
             ray_elev: self.ray_elev + (other.ray_elev - self.ray_elev) * prop,
             dist: self.dist + (other.dist - self.dist) * prop,
             path_len: self.path_len + (other.path_len - self.path_len) * prop,
+            air_column: self.air_column + (other.air_column - self.air_column) * prop,
+            aerosol_column: self.aerosol_column
+                + (other.aerosol_column - self.aerosol_column) * prop,
         }
     }
 
@@ -139,30 +194,35 @@ pub fn gen_path_cache(params: &Params, terrain: &Terrain, ray_elev: f64) -> Vec<
         params.view.position.latitude,
         params.view.position.longitude,
     );
-    let mut ray = params
-        .env
-        .cast_ray_stepper(alt, ray_elev.to_radians(), params.straight_rays);
-    ray.set_step_size(params.simulation_step);
+    let mut ray = cast_ray_stepper(params, alt, ray_elev.to_radians());
 
     let mut path = vec![PathElem {
         dist: 0.0,
         elev: alt,
         path_length: 0.0,
+        air_column: 0.0,
+        aerosol_column: 0.0,
     }];
     let mut ray_state = RayState {
         x: 0.0,
         h: alt,
         dh: 0.0,
     };
-    let mut path_length = 0.0;
+    let (mut path_length, mut air_column, mut aerosol_column) = (0.0, 0.0, 0.0);
 
     loop {
         let new_ray_state = ray.next().unwrap();
-        path_length += calc_dist(params, ray_state, new_ray_state);
+        let step_length = calc_dist(params, ray_state, new_ray_state);
+        let (air, aerosol) = calc_columns(params, ray_state, new_ray_state, step_length);
+        path_length += step_length;
+        air_column += air;
+        aerosol_column += aerosol;
         path.push(PathElem {
             dist: new_ray_state.x,
             elev: new_ray_state.h,
             path_length,
+            air_column,
+            aerosol_column,
         });
         if ray_state.x > params.view.frame.max_distance || ray_state.h < -1000.0 {
             break;
@@ -198,6 +258,97 @@ pub fn gen_terrain_cache(params: &Params, terrain: &Terrain, dir: f64) -> Vec<Te
     result
 }
 
+/// Prints the percentage of the pixels calculated so far.
+pub struct Progress {
+    start: SystemTime,
+    done: AtomicUsize,
+    total: usize,
+}
+
+impl Progress {
+    pub fn new(start: SystemTime, total: usize) -> Self {
+        Self {
+            start,
+            done: AtomicUsize::new(0),
+            total,
+        }
+    }
+
+    pub fn pixel_done(&self) {
+        let pixels_done = self.done.fetch_add(1, Ordering::SeqCst);
+        let prev_percent = pixels_done * 100 / self.total;
+        let new_percent = (pixels_done + 1) * 100 / self.total;
+        if new_percent > prev_percent {
+            println!(
+                "{:.3}: {}%...",
+                self.start.elapsed().unwrap().as_secs_f64(),
+                new_percent,
+            );
+        }
+    }
+}
+
+/// Calculates the elevation angles at which the rays of the pixels leave the atmosphere. As the
+/// atmosphere is the same in every direction, these only depend on the initial elevation angles, so
+/// they are interpolated from a table of rays covering the whole image.
+pub fn calc_exit_elevations(params: &Params, terrain: &Terrain, pixels: &mut [Vec<ResultPixel>]) {
+    let alt = params.view.position.abs_altitude(terrain);
+    let elevations = || pixels.iter().flatten().map(|pixel| pixel.elevation_angle);
+    let min_elev = elevations().fold(f64::INFINITY, f64::min).max(-89.9);
+    let max_elev = elevations().fold(f64::NEG_INFINITY, f64::max).min(89.9);
+
+    let num_rays = (pixels.len() * EXIT_RAYS_PER_ROW).max(2);
+    let step = (max_elev - min_elev) / (num_rays - 1) as f64;
+    let exit_elevations: Vec<_> = (0..num_rays)
+        .into_par_iter()
+        .map(|i| {
+            let elevation = min_elev + i as f64 * step;
+            let mut ray = cast_ray_stepper(params, alt, elevation.to_radians());
+            ray.set_step_size(EXIT_STEP);
+            exit_elevation(&params.env.shape, alt, ray)
+        })
+        .collect();
+
+    pixels.par_iter_mut().flatten().for_each(|pixel| {
+        let pos = if step > 0.0 {
+            (pixel.elevation_angle - min_elev) / step
+        } else {
+            0.0
+        };
+        let index = (pos.floor().max(0.0) as usize).min(num_rays - 2);
+        let coeff = (pos - index as f64).clamp(0.0, 1.0);
+        pixel.exit_elevation = match (exit_elevations[index], exit_elevations[index + 1]) {
+            (Some(exit1), Some(exit2)) => Some(exit1 * (1.0 - coeff) + exit2 * coeff),
+            // the boundary between the rays that leave the atmosphere and those that don't
+            (exit1, exit2) => {
+                if coeff < 0.5 {
+                    exit1
+                } else {
+                    exit2
+                }
+            }
+        };
+    });
+}
+
+/// Sets the surface colors of the points where the rays hit the terrain to the ones returned by
+/// `color_at` for their coordinates; the points for which it returns `None` are left unchanged.
+pub fn calc_surface_colors<F>(pixels: &mut [Vec<ResultPixel>], color_at: F)
+where
+    F: Fn(f64, f64) -> Option<SurfaceColor> + Sync,
+{
+    pixels
+        .par_iter_mut()
+        .flatten()
+        .flat_map(|pixel| pixel.trace_points.par_iter_mut())
+        .filter(|point| matches!(point.color, PixelColor::Terrain(_)))
+        .for_each(|point| {
+            if let Some(color) = color_at(point.lat, point.lon) {
+                point.surface_color = Some(color);
+            }
+        });
+}
+
 pub fn get_single_pixel<I: Iterator<Item = (TerrainData, PathElem)>>(
     mut terrain_and_path: I,
     objects: &[Box<dyn Object + Sync>],
@@ -205,18 +356,13 @@ pub fn get_single_pixel<I: Iterator<Item = (TerrainData, PathElem)>>(
     terrain_alpha: f64,
 ) -> Vec<TracePoint> {
     let (first_terrain, first_path) = terrain_and_path.next().unwrap();
-    let mut old_tracing_state = TracingState::new(&first_terrain, first_path.elev, 0.0, 0.0);
+    let mut old_tracing_state = TracingState::new(&first_terrain, &first_path);
     let mut result = vec![];
 
     for (terrain_data, path_elem) in terrain_and_path {
         let mut finish = false;
         let mut step_result = vec![];
-        let new_tracing_state = TracingState::new(
-            &terrain_data,
-            path_elem.elev,
-            path_elem.dist,
-            path_elem.path_length,
-        );
+        let new_tracing_state = TracingState::new(&terrain_data, &path_elem);
         let diff1 = old_tracing_state.ray_elev - old_tracing_state.terrain_data.elev;
         let diff2 = new_tracing_state.ray_elev - new_tracing_state.terrain_data.elev;
         if diff1 * diff2 < 0.0 {
@@ -230,7 +376,12 @@ pub fn get_single_pixel<I: Iterator<Item = (TerrainData, PathElem)>>(
                     distance: interpolated.dist,
                     elevation: interpolated.terrain_data.elev,
                     path_length: interpolated.path_len,
+                    air_column: interpolated.air_column,
+                    aerosol_column: interpolated.aerosol_column,
                     normal: interpolated.terrain_data.normal,
+                    shadow: 0.0,
+                    sky_view: None,
+                    surface_color: None,
                     color: PixelColor::Terrain(terrain_alpha),
                 },
             ));
@@ -267,7 +418,12 @@ pub fn get_single_pixel<I: Iterator<Item = (TerrainData, PathElem)>>(
                             distance: interpolated.dist,
                             elevation: interpolated.ray_elev,
                             path_length: interpolated.path_len,
+                            air_column: interpolated.air_column,
+                            aerosol_column: interpolated.aerosol_column,
                             normal,
+                            shadow: 0.0,
+                            sky_view: None,
+                            surface_color: None,
                             color: PixelColor::Rgba(color),
                         },
                     ));
@@ -287,3 +443,77 @@ pub fn get_single_pixel<I: Iterator<Item = (TerrainData, PathElem)>>(
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::params::{Config, Extinction};
+
+    fn extinction_params() -> Params {
+        let mut config = Config::default();
+        config.view.extinction = Some(Extinction {
+            aerosol_extinction: 2e-5,
+            angstrom_exponent: 1.3,
+            airlight: None,
+        });
+        config.into_params(&Terrain::new()).unwrap()
+    }
+
+    /// Sums the columns along a straight horizontal ray starting at the altitude `h0` on the
+    /// spherical Earth, which rises as `h(s) = sqrt(r0^2 + s^2) - R` at the distance `s` along it.
+    fn horizontal_ray_columns(params: &Params, h0: f64) -> (f64, f64) {
+        let radius = match params.env.shape {
+            EarthShape::Spherical { radius } => radius,
+            EarthShape::Flat => unreachable!(),
+        };
+        let state_at = |x: f64| RayState {
+            x,
+            h: (radius + h0) / (x / radius).cos() - radius,
+            dh: 0.0,
+        };
+        let (mut air_column, mut aerosol_column) = (0.0, 0.0);
+        let mut old_state = state_at(0.0);
+        for i in 1..=10_000 {
+            let new_state = state_at(i as f64 * 100.0);
+            let length = calc_dist(params, old_state, new_state);
+            let (air, aerosol) = calc_columns(params, old_state, new_state, length);
+            air_column += air;
+            aerosol_column += aerosol;
+            old_state = new_state;
+        }
+        (air_column, aerosol_column)
+    }
+
+    /// The aerosol density falls off exponentially, so its column along a horizontal ray
+    /// starting at `h0` is `exp(-h0 / H) * sqrt(pi * r0 * H / 2)` to a very good approximation.
+    #[test]
+    fn test_horizontal_aerosol_column() {
+        let params = extinction_params();
+        let radius = match params.env.shape {
+            EarthShape::Spherical { radius } => radius,
+            EarthShape::Flat => unreachable!(),
+        };
+        for h0 in [0.0, 500.0, 2000.0] {
+            let (air_column, aerosol_column) = horizontal_ray_columns(&params, h0);
+            let expected = (-h0 / AEROSOL_SCALE_HEIGHT).exp()
+                * (std::f64::consts::PI * (radius + h0) * AEROSOL_SCALE_HEIGHT / 2.0).sqrt();
+            assert!(
+                (aerosol_column / expected - 1.0).abs() < 1e-3,
+                "h0 = {}: {} vs {}",
+                h0,
+                aerosol_column,
+                expected
+            );
+            // the air has a larger scale height, so its column is longer
+            assert!(air_column > aerosol_column);
+        }
+    }
+
+    /// The columns aren't calculated when the extinction is disabled.
+    #[test]
+    fn test_columns_without_extinction() {
+        let mut params = extinction_params();
+        params.view.extinction = None;
+        assert_eq!(horizontal_ray_columns(&params, 0.0), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use tiff::{
+    encoder::{colortype::ColorType, TiffEncoder},
+    tags::{PhotometricInterpretation, SampleFormat, Tag},
+};
+
+use super::ResultPixel;
+
+/// The names of the exported layers, in the order of the bands.
+const LAYER_NAMES: [&str; 7] = [
+    "distance",
+    "lat",
+    "lon",
+    "elevation",
+    "path_length",
+    "azimuth",
+    "elevation_angle",
+];
+
+/// The values of the layers for a single pixel.
+pub type PixelLayers = [f32; LAYER_NAMES.len()];
+
+/// A TIFF sample layout with a 32-bit float band per layer.
+struct Layers;
+
+impl ColorType for Layers {
+    type Inner = f32;
+    const TIFF_VALUE: PhotometricInterpretation = PhotometricInterpretation::BlackIsZero;
+    const BITS_PER_SAMPLE: &'static [u16] = &[32; LAYER_NAMES.len()];
+    const SAMPLE_FORMAT: &'static [SampleFormat] = &[SampleFormat::IEEEFP; LAYER_NAMES.len()];
+}
+
+/// Returns the values of the layers for a pixel, taken from its first opaque trace point; the
+/// values other than the direction of the pixel are NaN if it doesn't hit anything opaque.
+pub fn pixel_layers(pixel: &ResultPixel) -> PixelLayers {
+    let (distance, lat, lon, elevation, path_length) = pixel
+        .trace_points
+        .iter()
+        .find(|point| point.color.alpha() >= 1.0)
+        .map_or(
+            (f64::NAN, f64::NAN, f64::NAN, f64::NAN, f64::NAN),
+            |point| {
+                (
+                    point.distance,
+                    point.lat,
+                    point.lon,
+                    point.elevation,
+                    point.path_length,
+                )
+            },
+        );
+    [
+        distance as f32,
+        lat as f32,
+        lon as f32,
+        elevation as f32,
+        path_length as f32,
+        pixel.azimuth as f32,
+        pixel.elevation_angle as f32,
+    ]
+}
+
+fn save_tiff(layers: &[PixelLayers], width: u32, height: u32, path: &Path) -> Result<(), String> {
+    let file = BufWriter::new(File::create(path).map_err(|err| err.to_string())?);
+    let mut encoder = TiffEncoder::new(file).map_err(|err| err.to_string())?;
+    let mut image = encoder
+        .new_image::<Layers>(width, height)
+        .map_err(|err| err.to_string())?;
+    // all the bands past the first one are unspecified extra samples
+    image
+        .encoder()
+        .write_tag(Tag::ExtraSamples, &[0u16; LAYER_NAMES.len() - 1][..])
+        .map_err(|err| err.to_string())?;
+    image
+        .encoder()
+        .write_tag(Tag::ImageDescription, LAYER_NAMES.join(",").as_str())
+        .map_err(|err| err.to_string())?;
+    image
+        .write_data(layers.as_flattened())
+        .map_err(|err| err.to_string())
+}
+
+/// Saves the layers as a NumPy array of shape (layers, height, width).
+fn save_npy(layers: &[PixelLayers], width: u32, height: u32, path: &Path) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+        LAYER_NAMES.len(),
+        height,
+        width
+    );
+    // the header is padded with spaces and a newline so that the data is aligned to 64 bytes
+    let unpadded_len = 10 + header.len() + 1;
+    header.extend(std::iter::repeat_n(' ', (64 - unpadded_len % 64) % 64));
+    header.push('\n');
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"\x93NUMPY\x01\x00")?;
+    file.write_all(&(header.len() as u16).to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    for layer in 0..LAYER_NAMES.len() {
+        for pixel in layers {
+            file.write_all(&pixel[layer].to_le_bytes())?;
+        }
+    }
+    file.flush()
+}
+
+/// Exports the per-pixel data layers, given for the consecutive rows of the image; the format is
+/// chosen by the extension of the file (`.npy` or a multi-band TIFF otherwise).
+pub fn output_layers(
+    filename: &str,
+    layers: &[PixelLayers],
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    let path = Path::new(filename);
+    let is_npy = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("npy"));
+    let result = if is_npy {
+        save_npy(layers, width, height, path).map_err(|err| err.to_string())
+    } else {
+        save_tiff(layers, width, height, path)
+    };
+    result.map_err(|err| format!("failed to export the data layers to {}: {}", filename, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::TryInto, env, fs};
+
+    #[test]
+    fn test_npy_layout() {
+        // 3x2 pixels, with the layer index in the tens and the pixel index in the units
+        let layers: Vec<PixelLayers> = (0..6)
+            .map(|pixel| {
+                let mut values = [0.0; LAYER_NAMES.len()];
+                for (layer, value) in values.iter_mut().enumerate() {
+                    *value = (layer * 10 + pixel) as f32;
+                }
+                values
+            })
+            .collect();
+        let path = env::temp_dir().join(format!("atm-raytracer-{}-layers.npy", std::process::id()));
+        save_npy(&layers, 3, 2, &path).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(&data[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([data[8], data[9]]) as usize;
+        let data_start = 10 + header_len;
+        assert_eq!(data_start % 64, 0);
+        let header = std::str::from_utf8(&data[10..data_start]).unwrap();
+        assert!(
+            header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (7, 2, 3), }")
+        );
+        assert!(header.ends_with('\n'));
+
+        let values: Vec<f32> = data[data_start..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(values.len(), LAYER_NAMES.len() * 6);
+        for (index, value) in values.iter().enumerate() {
+            let (layer, pixel) = (index / 6, index % 6);
+            assert_eq!(*value, (layer * 10 + pixel) as f32);
+        }
+    }
+}
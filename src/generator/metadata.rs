@@ -0,0 +1,581 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use libflate::gzip::{Decoder, Encoder};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{params::Params, ResultPixel};
+
+mod legacy;
+
+/// The crate version reported for the files without a header.
+const LEGACY_CRATE_VERSION: &str = "0.13.0 or earlier";
+/// The bytes identifying a metadata file.
+const MAGIC: &[u8; 8] = b"ATMRDATA";
+/// The first bytes of a gzip stream, which is how the files without a header start.
+const GZIP_MAGIC: &[u8; 2] = &[0x1f, 0x8b];
+/// The version of the layout of the metadata; it has to be bumped whenever the layout or the
+/// definitions of `Params` or `ResultPixel` change, so that the files written by other versions
+/// are rejected instead of being misread. The readers of the older layouts are kept in the
+/// `legacy` submodule.
+///
+/// The files written by atm-raytracer 0.13 and earlier have no header and are read as format
+/// version 0: the whole gzipped `legacy::AllData`.
+///
+/// The header is followed by gzipped sections: the params and then the chunks of rows of pixels.
+/// Each section is preceded by its length (and the chunks by a `ChunkHeader`), so that the chunks
+/// can be found without an index and a file cut off during the generation can be read up to the
+/// last complete chunk.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The header following the magic bytes at the start of a metadata file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub format_version: u32,
+    /// The version of atm-raytracer that wrote the file.
+    pub crate_version: String,
+}
+
+impl Header {
+    fn current() -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+}
+
+/// The position of a gzipped section in the file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Section {
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Chunk {
+    first_row: u32,
+    num_rows: u32,
+    section: Section,
+}
+
+impl Chunk {
+    fn contains(&self, row: usize) -> bool {
+        (self.first_row as usize..(self.first_row + self.num_rows) as usize).contains(&row)
+    }
+}
+
+/// The record preceding each chunk of rows.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ChunkHeader {
+    first_row: u32,
+    num_rows: u32,
+    length: u64,
+}
+
+fn error<E: Display>(context: &'static str) -> impl Fn(E) -> String {
+    move |err| format!("{}: {}", context, err)
+}
+
+fn deflate<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    let mut encoder = Encoder::new(vec![]).map_err(error("failed to create a GZip encoder"))?;
+    bincode::serialize_into(&mut encoder, value).map_err(error("failed to serialize metadata"))?;
+    encoder
+        .finish()
+        .into_result()
+        .map_err(error("failed to finish deflating metadata"))
+}
+
+fn read_section<R: Read + Seek, T: DeserializeOwned>(
+    reader: &mut R,
+    section: &Section,
+) -> Result<T, String> {
+    reader
+        .seek(SeekFrom::Start(section.offset))
+        .map_err(error("couldn't read the data"))?;
+    let decoder =
+        Decoder::new(reader.take(section.length)).map_err(error("couldn't create the decoder"))?;
+    bincode::deserialize_from(decoder).map_err(error("couldn't deserialize the data"))
+}
+
+/// Writes a metadata file, with the rows of pixels passed in chunks as they are generated. Every
+/// chunk is flushed as soon as it's written, so the file can be read or resumed from at any time.
+pub struct MetadataWriter<W: Write> {
+    writer: W,
+    next_row: u32,
+}
+
+impl MetadataWriter<BufWriter<File>> {
+    pub fn create(filename: &str, params: &Params) -> Result<Self, String> {
+        let file = File::create(filename).map_err(error("failed to create a metadata file"))?;
+        Self::new(BufWriter::new(file), params)
+    }
+
+    /// Continues writing a file after the complete chunks found by `data`, discarding anything
+    /// following them.
+    pub fn resume<R: Read + Seek>(
+        filename: &str,
+        data: &MetadataReader<R>,
+    ) -> Result<Self, String> {
+        if data.header.format_version != FORMAT_VERSION {
+            return Err(format!(
+                "only files in metadata format version {} can be resumed",
+                FORMAT_VERSION
+            ));
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(filename)
+            .map_err(error("failed to open the metadata file"))?;
+        file.set_len(data.data_end)
+            .and_then(|_| file.seek(SeekFrom::End(0)))
+            .map_err(error("failed to truncate the metadata file"))?;
+        Ok(Self::append(BufWriter::new(file), data.rows_done() as u32))
+    }
+}
+
+impl<W: Write> MetadataWriter<W> {
+    pub fn new(mut writer: W, params: &Params) -> Result<Self, String> {
+        writer
+            .write_all(MAGIC)
+            .map_err(error("failed to write the metadata header"))?;
+        bincode::serialize_into(&mut writer, &Header::current())
+            .map_err(error("failed to write the metadata header"))?;
+        let params = deflate(params)?;
+        bincode::serialize_into(&mut writer, &(params.len() as u64))
+            .map_err(error("failed to write metadata"))?;
+        writer
+            .write_all(&params)
+            .and_then(|_| writer.flush())
+            .map_err(error("failed to write metadata"))?;
+        Ok(Self::append(writer, 0))
+    }
+
+    /// Continues writing chunks from `next_row` at the current position of `writer`.
+    fn append(writer: W, next_row: u32) -> Self {
+        Self { writer, next_row }
+    }
+
+    /// Appends the rows following the previously written ones.
+    pub fn write_rows(&mut self, rows: &[Vec<ResultPixel>]) -> Result<(), String> {
+        let data = deflate(rows)?;
+        let header = ChunkHeader {
+            first_row: self.next_row,
+            num_rows: rows.len() as u32,
+            length: data.len() as u64,
+        };
+        bincode::serialize_into(&mut self.writer, &header)
+            .map_err(error("failed to write metadata"))?;
+        self.writer
+            .write_all(&data)
+            .and_then(|_| self.writer.flush())
+            .map_err(error("failed to write metadata"))?;
+        self.next_row += rows.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, String> {
+        self.writer
+            .flush()
+            .map_err(error("failed to write metadata"))?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the magic bytes and the header of a metadata file. The files without a header, written
+/// before the format was versioned, are reported as format version 0.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<Header, String> {
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| "the file is too short to be a metadata file".to_owned())?;
+    if magic.starts_with(GZIP_MAGIC) {
+        return Ok(Header {
+            format_version: 0,
+            crate_version: LEGACY_CRATE_VERSION.to_owned(),
+        });
+    }
+    if &magic != MAGIC {
+        return Err("the file is not an atm-raytracer metadata file".to_owned());
+    }
+    bincode::deserialize_from(reader).map_err(error("couldn't read the metadata header"))
+}
+
+enum Rows {
+    /// The rows are read from the file by chunks; the last one read is kept in memory.
+    Chunked {
+        chunks: Vec<Chunk>,
+        cached: Option<(usize, Vec<Vec<ResultPixel>>)>,
+    },
+    /// All the rows, read at once from a file in format version 0.
+    InMemory(Vec<Vec<ResultPixel>>),
+}
+
+/// Reads a metadata file lazily, only loading the chunks of rows that are accessed.
+pub struct MetadataReader<R: Read + Seek> {
+    reader: R,
+    header: Header,
+    params: Params,
+    rows: Rows,
+    /// The end of the last complete chunk, where a resumed generation continues writing.
+    data_end: u64,
+}
+
+impl MetadataReader<BufReader<File>> {
+    pub fn open(filename: &str) -> Result<Self, String> {
+        let file = File::open(filename).map_err(error("couldn't open the file"))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> MetadataReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, String> {
+        let header = read_header(&mut reader)?;
+        let (params, rows, data_end) = match header.format_version {
+            FORMAT_VERSION => {
+                let (params, chunks, data_end) = scan_chunks(&mut reader)?;
+                let rows = Rows::Chunked {
+                    chunks,
+                    cached: None,
+                };
+                (params, rows, data_end)
+            }
+            0 => {
+                let mut data = vec![];
+                reader
+                    .seek(SeekFrom::Start(0))
+                    .map_err(error("couldn't read the data"))?;
+                let _ = Decoder::new(&mut reader)
+                    .and_then(|mut decoder| decoder.read_to_end(&mut data))
+                    .map_err(error("couldn't inflate the data"))?;
+                let all_data: legacy::AllData =
+                    bincode::deserialize(&data).map_err(error("couldn't deserialize the data"))?;
+                let rows = all_data
+                    .result
+                    .into_iter()
+                    .map(|row| row.into_iter().map(Into::into).collect())
+                    .collect();
+                (all_data.params.into(), Rows::InMemory(rows), 0)
+            }
+            version if version > FORMAT_VERSION => {
+                return Err(format!(
+                    "the file was written by atm-raytracer {} in metadata format version {}, but \
+                     only versions up to {} are supported; please update atm-raytracer",
+                    header.crate_version, version, FORMAT_VERSION
+                ));
+            }
+            version => {
+                return Err(format!(
+                    "unknown metadata format version {} (written by atm-raytracer {})",
+                    version, header.crate_version
+                ));
+            }
+        };
+        Ok(Self {
+            reader,
+            header,
+            params,
+            rows,
+            data_end,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// The number of rows at the top of the image contained in the file.
+    pub fn rows_done(&self) -> usize {
+        match &self.rows {
+            Rows::Chunked { chunks, .. } => chunks
+                .last()
+                .map_or(0, |chunk| (chunk.first_row + chunk.num_rows) as usize),
+            Rows::InMemory(rows) => rows.len(),
+        }
+    }
+
+    /// Whether the file contains all the rows of the image, which is not the case if the
+    /// generation is still running or was interrupted.
+    pub fn is_complete(&self) -> bool {
+        self.rows_done() >= self.params.output.height as usize
+    }
+
+    /// Returns a row of pixels, reading the chunk containing it if it isn't in memory.
+    pub fn row(&mut self, y: usize) -> Result<&[ResultPixel], String> {
+        match &mut self.rows {
+            Rows::Chunked { chunks, cached } => {
+                let index = chunks
+                    .iter()
+                    .position(|chunk| chunk.contains(y))
+                    .ok_or_else(|| format!("row {} is not in the file", y))?;
+                if !matches!(cached, Some((cached_index, _)) if *cached_index == index) {
+                    let rows = read_section(&mut self.reader, &chunks[index].section)?;
+                    *cached = Some((index, rows));
+                }
+                let (_, rows) = cached.as_ref().unwrap();
+                Ok(&rows[y - chunks[index].first_row as usize])
+            }
+            Rows::InMemory(rows) => rows
+                .get(y)
+                .map(Vec::as_slice)
+                .ok_or_else(|| format!("row {} is not in the file", y)),
+        }
+    }
+
+    /// Calls `f` with the consecutive chunks of rows and the indices of their first rows.
+    pub fn for_each_chunk<F>(&mut self, mut f: F) -> Result<(), String>
+    where
+        F: FnMut(usize, &[Vec<ResultPixel>]),
+    {
+        match &self.rows {
+            Rows::Chunked { chunks, .. } => {
+                for chunk in chunks {
+                    let rows: Vec<Vec<ResultPixel>> =
+                        read_section(&mut self.reader, &chunk.section)?;
+                    f(chunk.first_row as usize, &rows);
+                }
+            }
+            Rows::InMemory(rows) => f(0, rows),
+        }
+        Ok(())
+    }
+}
+
+/// Reads the params of a file, following the header, and finds its chunks, stopping at the first
+/// one that was cut off. Returns the params, the chunks and the end of the last chunk.
+fn scan_chunks<R: Read + Seek>(reader: &mut R) -> Result<(Params, Vec<Chunk>, u64), String> {
+    let mut position = reader
+        .stream_position()
+        .map_err(error("couldn't read the data"))?;
+    let file_length = reader
+        .seek(SeekFrom::End(0))
+        .and_then(|length| reader.seek(SeekFrom::Start(position)).map(|_| length))
+        .map_err(error("couldn't read the data"))?;
+    let length: u64 =
+        bincode::deserialize_from(&mut *reader).map_err(error("couldn't read the params"))?;
+    let params = Section {
+        offset: position + 8,
+        length,
+    };
+    let params = read_section(reader, &params)?;
+    position += 8 + length;
+
+    let mut chunks: Vec<Chunk> = vec![];
+    let next_row = |chunks: &[Chunk]| chunks.last().map_or(0, |c| c.first_row + c.num_rows);
+    loop {
+        reader
+            .seek(SeekFrom::Start(position))
+            .map_err(error("couldn't read the data"))?;
+        let header: ChunkHeader = match bincode::deserialize_from(&mut *reader) {
+            Ok(header) => header,
+            Err(_) => break,
+        };
+        let offset = position + bincode::serialized_size(&header).unwrap();
+        if offset + header.length > file_length || header.first_row != next_row(&chunks) {
+            break;
+        }
+        chunks.push(Chunk {
+            first_row: header.first_row,
+            num_rows: header.num_rows,
+            section: Section {
+                offset,
+                length: header.length,
+            },
+        });
+        position = offset + header.length;
+    }
+    Ok((params, chunks, position))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use nalgebra::Vector3;
+
+    use super::*;
+    use crate::{
+        coloring::ColorPalette,
+        generator::{
+            params::{Coloring, Config, GeneratorDef},
+            PixelColor,
+        },
+        terrain::Terrain,
+    };
+
+    #[test]
+    fn test_header() {
+        let mut bytes = MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, &Header::current()).unwrap();
+        let header = read_header(&mut &bytes[..]).unwrap();
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.crate_version, env!("CARGO_PKG_VERSION"));
+
+        let legacy = Encoder::new(Vec::new())
+            .unwrap()
+            .finish()
+            .into_result()
+            .unwrap();
+        let header = read_header(&mut &legacy[..]).unwrap();
+        assert_eq!(header.format_version, 0);
+        assert!(read_header(&mut &b"not a metadata file"[..]).is_err());
+    }
+
+    fn row(y: usize) -> Vec<ResultPixel> {
+        (0..3)
+            .map(|x| ResultPixel {
+                elevation_angle: y as f64,
+                azimuth: x as f64,
+                trace_points: vec![],
+                exit_elevation: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks() {
+        let params = Config::default().into_params(&Terrain::new()).unwrap();
+
+        let mut writer = MetadataWriter::new(Cursor::new(vec![]), &params).unwrap();
+        writer.write_rows(&[row(0), row(1)]).unwrap();
+        writer.write_rows(&[row(2)]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let mut reader = MetadataReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.row(2).unwrap()[1].elevation_angle, 2.0);
+        assert_eq!(reader.row(1).unwrap()[2].azimuth, 2.0);
+        assert!(reader.row(3).is_err());
+        let mut first_rows = vec![];
+        reader
+            .for_each_chunk(|first_row, rows| first_rows.push((first_row, rows.len())))
+            .unwrap();
+        assert_eq!(first_rows, vec![(0, 2), (2, 1)]);
+    }
+
+    /// The files written by atm-raytracer 0.13 and earlier, without a header, should be converted
+    /// to the current definitions.
+    #[test]
+    fn test_legacy() {
+        let params = Config::default().into_params(&Terrain::new()).unwrap();
+        let point = legacy::TracePoint {
+            lat: 50.0,
+            lon: 20.0,
+            distance: 1000.0,
+            elevation: 300.0,
+            path_length: 1000.5,
+            normal: Vector3::new(0.0, 0.0, 1.0),
+            color: PixelColor::Terrain(1.0),
+        };
+        let all_data = legacy::AllData {
+            params: legacy::Params {
+                scene: legacy::Scene {
+                    terrain_folder: "./terrain".to_owned(),
+                    objects: vec![],
+                    terrain_alpha: 1.0,
+                },
+                view: legacy::View {
+                    position: params.view.position,
+                    frame: params.view.frame,
+                    coloring: legacy::Coloring::Shading {
+                        water_level: 0.0,
+                        ambient_light: 0.4,
+                        light_dir: Vector3::new(0.0, 0.0, 1.0),
+                        palette: ColorPalette::Legacy,
+                    },
+                    fog_distance: Some(10_000.0),
+                },
+                model: params.model,
+                env: params.env.clone(),
+                straight_rays: false,
+                simulation_step: 50.0,
+                output: legacy::Output {
+                    file: "output.png".to_owned(),
+                    file_metadata: Some("output.dat".to_owned()),
+                    width: 1,
+                    height: 2,
+                    ticks: vec![],
+                    vertical_ticks: vec![],
+                    show_eye_level: true,
+                    show_flat_horizon: false,
+                    generator: GeneratorDef::Rectilinear,
+                },
+            },
+            result: vec![
+                vec![legacy::ResultPixel {
+                    elevation_angle: 1.0,
+                    azimuth: 0.0,
+                    trace_points: vec![],
+                }],
+                vec![legacy::ResultPixel {
+                    elevation_angle: 0.0,
+                    azimuth: 0.0,
+                    trace_points: vec![point],
+                }],
+            ],
+        };
+        let mut encoder = Encoder::new(vec![]).unwrap();
+        encoder
+            .write_all(&bincode::serialize(&all_data).unwrap())
+            .unwrap();
+        let bytes = encoder.finish().into_result().unwrap();
+
+        let mut reader = MetadataReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.header().format_version, 0);
+        assert!(reader.is_complete());
+        let params = reader.params();
+        assert_eq!(params.view.fog_distance, Some(10_000.0));
+        assert!(params.output.show_eye_level);
+        assert!(matches!(
+            params.view.coloring,
+            Coloring::Shading {
+                palette: ColorPalette::Legacy,
+                shadows: false,
+                ..
+            }
+        ));
+        let point = reader.row(1).unwrap()[0].trace_points[0];
+        assert_eq!(point.distance, 1000.0);
+        assert_eq!(point.path_length, 1000.5);
+        assert!(reader.row(2).is_err());
+        assert!(MetadataWriter::resume("output.dat", &reader).is_err());
+    }
+
+    #[test]
+    fn test_partial() {
+        let mut params = Config::default().into_params(&Terrain::new()).unwrap();
+        params.output.height = 3;
+
+        let mut writer = MetadataWriter::new(Cursor::new(vec![]), &params).unwrap();
+        writer.write_rows(&[row(0), row(1)]).unwrap();
+        writer.write_rows(&[row(2)]).unwrap();
+        let mut bytes = writer.finish().unwrap().into_inner();
+        assert!(MetadataReader::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .is_complete());
+
+        // cut off the last chunk, as if the generation was interrupted while writing it
+        bytes.truncate(bytes.len() - 5);
+        let mut reader = MetadataReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.rows_done(), 2);
+        assert!(!reader.is_complete());
+        assert!(reader.row(2).is_err());
+        assert_eq!(reader.row(1).unwrap()[0].elevation_angle, 1.0);
+
+        bytes.truncate(reader.data_end as usize);
+        let mut cursor = Cursor::new(bytes);
+        cursor.seek(SeekFrom::End(0)).unwrap();
+        let mut writer = MetadataWriter::append(cursor, reader.rows_done() as u32);
+        writer.write_rows(&[row(2)]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        let mut reader = MetadataReader::new(Cursor::new(bytes)).unwrap();
+        assert!(reader.is_complete());
+        assert_eq!(reader.row(2).unwrap()[1].azimuth, 1.0);
+    }
+}
@@ -0,0 +1,191 @@
+//! The layout of the metadata files written by atm-raytracer 0.13 and earlier - the whole gzipped
+//! `AllData` without a header - and its conversion into the current definitions.
+
+use atm_refraction::Environment;
+use nalgebra::Vector3;
+
+use crate::{
+    coloring::ColorPalette,
+    generator::{
+        self,
+        params::{self, Frame, GeneratorDef, Position, Tick, VerticalTick},
+        PixelColor,
+    },
+    object::SerializableObject,
+    utils::EarthModel,
+};
+
+/// The whole contents of a file.
+#[derive(Serialize, Deserialize)]
+pub struct AllData {
+    pub params: Params,
+    pub result: Vec<Vec<ResultPixel>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Params {
+    pub scene: Scene,
+    pub view: View,
+    pub model: EarthModel,
+    pub env: Environment,
+    pub straight_rays: bool,
+    pub simulation_step: f64,
+    pub output: Output,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Scene {
+    pub terrain_folder: String,
+    pub objects: Vec<SerializableObject>,
+    pub terrain_alpha: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct View {
+    pub position: Position,
+    pub frame: Frame,
+    pub coloring: Coloring,
+    pub fog_distance: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Coloring {
+    Simple {
+        water_level: f64,
+        max_distance: f64,
+    },
+    Shading {
+        water_level: f64,
+        ambient_light: f64,
+        light_dir: Vector3<f64>,
+        /// Only the `Legacy` and `Improved` palettes existed, which are still the first two
+        /// variants.
+        palette: ColorPalette,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Output {
+    pub file: String,
+    pub file_metadata: Option<String>,
+    pub width: u16,
+    pub height: u16,
+    pub ticks: Vec<Tick>,
+    pub vertical_ticks: Vec<VerticalTick>,
+    pub show_eye_level: bool,
+    pub show_flat_horizon: bool,
+    pub generator: GeneratorDef,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResultPixel {
+    pub elevation_angle: f64,
+    pub azimuth: f64,
+    pub trace_points: Vec<TracePoint>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TracePoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub distance: f64,
+    pub elevation: f64,
+    pub path_length: f64,
+    pub normal: Vector3<f64>,
+    pub color: PixelColor,
+}
+
+impl From<Params> for params::Params {
+    fn from(params: Params) -> Self {
+        let (scene, view, output) = (params.scene, params.view, params.output);
+        let coloring = match view.coloring {
+            Coloring::Simple {
+                water_level,
+                max_distance,
+            } => params::Coloring::Simple {
+                water_level,
+                max_distance,
+                palette: None,
+            },
+            Coloring::Shading {
+                water_level,
+                ambient_light,
+                light_dir,
+                palette,
+            } => params::Coloring::Shading {
+                water_level,
+                ambient_light,
+                light_dir,
+                palette,
+                shadows: false,
+                ambient_occlusion: false,
+                snow: None,
+            },
+        };
+        Self {
+            scene: params::Scene::with_objects(
+                scene.terrain_folder,
+                scene.objects,
+                scene.terrain_alpha,
+            ),
+            view: params::View {
+                position: view.position,
+                frame: view.frame,
+                coloring,
+                fog_distance: view.fog_distance,
+                extinction: None,
+                sky: None,
+                datetime: None,
+                sun: None,
+            },
+            model: params.model,
+            env: params.env,
+            straight_rays: params.straight_rays,
+            simulation_step: params.simulation_step,
+            integrator: Default::default(),
+            output: params::Output {
+                file: output.file,
+                file_metadata: output.file_metadata,
+                width: output.width,
+                height: output.height,
+                ticks: output.ticks,
+                vertical_ticks: output.vertical_ticks,
+                show_eye_level: output.show_eye_level,
+                show_flat_horizon: output.show_flat_horizon,
+                generator: output.generator,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl From<TracePoint> for generator::TracePoint {
+    fn from(point: TracePoint) -> Self {
+        Self {
+            lat: point.lat,
+            lon: point.lon,
+            distance: point.distance,
+            elevation: point.elevation,
+            path_length: point.path_length,
+            // the extinction wasn't modelled, so the columns weren't calculated
+            air_column: 0.0,
+            aerosol_column: 0.0,
+            normal: point.normal,
+            shadow: 0.0,
+            sky_view: None,
+            surface_color: None,
+            color: point.color,
+        }
+    }
+}
+
+impl From<ResultPixel> for generator::ResultPixel {
+    fn from(pixel: ResultPixel) -> Self {
+        Self {
+            elevation_angle: pixel.elevation_angle,
+            azimuth: pixel.azimuth,
+            trace_points: pixel.trace_points.into_iter().map(Into::into).collect(),
+            exit_elevation: None,
+        }
+    }
+}
@@ -1,96 +1,366 @@
+mod distributed;
 mod generators;
+mod layers;
+pub mod metadata;
 pub mod params;
 
-use std::{env, fs, io::Write, time::SystemTime};
+use std::{fs::File, io::BufReader, ops::Range, path::Path, time::SystemTime};
 
-use clap::ArgMatches;
-use libflate::gzip::Encoder;
-
-use crate::terrain::Terrain;
+use crate::{
+    renderer::{self, LinearImage},
+    terrain::{LandCover, Terrain, Texture},
+};
 
 pub use generators::{
-    FastGenerator, Generator, InterpolatingRectilinearGenerator, PixelColor, RectilinearGenerator,
-    ResultPixel, TracePoint,
+    calc_exit_elevations, calc_shadows, calc_sky_view, calc_surface_colors, cast_ray_stepper,
+    gen_path_cache, gen_terrain_cache, ray_stepper, FastGenerator, Generator,
+    InterpolatingRectilinearGenerator, PathElem, PixelColor, PixelDirection, Progress,
+    RectilinearGenerator, ResultPixel, SurfaceColor, TerrainData, TracePoint,
 };
-pub use params::subcommand_def;
-use params::{GeneratorDef, Params};
+use metadata::{MetadataReader, MetadataWriter, FORMAT_VERSION};
+use params::{Coloring, GeneratorDef, Output, Params};
 
-pub const SUBCOMMAND: &str = "gen";
+pub use distributed::{run_worker, LocalPaths};
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct AllData {
-    pub params: Params,
-    pub result: Vec<Vec<ResultPixel>>,
+/// The surface colors draped over the terrain.
+pub struct SurfaceColors {
+    land_cover: Option<LandCover>,
+    texture: Option<(Texture, bool)>,
 }
 
-fn output_metadata(filename: &str, pixels: Vec<Vec<ResultPixel>>, params: Params) {
-    let mut file = fs::File::create(filename).expect("failed to create a metadata file");
-    let all_data = AllData {
-        params,
-        result: pixels,
-    };
+/// Calculates the data of the pixels that isn't found by the generators: the exit elevations, the
+/// surface colors, the shadows and the sky-view factors.
+fn complete_pixels(
+    params: &Params,
+    terrain: &Terrain,
+    surface_colors: &SurfaceColors,
+    pixels: &mut [Vec<ResultPixel>],
+) {
+    if !params.scene.celestial_objects.is_empty() {
+        calc_exit_elevations(params, terrain, pixels);
+    }
 
-    let all_data_bytes = bincode::serialize(&all_data).expect("failed to serialize metadata");
-    let mut gzip_encoder = Encoder::new(Vec::new()).expect("failed to create a GZip encoder");
-    gzip_encoder
-        .write_all(&all_data_bytes)
-        .expect("failed to deflate metadata");
-    let zipped_data = gzip_encoder
-        .finish()
-        .into_result()
-        .expect("failed to finish deflating metadata");
-
-    file.write_all(&zipped_data)
-        .expect("failed to write metadata to the file");
+    if let Some(ref land_cover) = surface_colors.land_cover {
+        calc_surface_colors(pixels, |lat, lon| {
+            land_cover.color_at(lat, lon).map(|color| SurfaceColor {
+                color,
+                shaded: true,
+            })
+        });
+    }
+
+    if let Some((ref texture, shaded)) = surface_colors.texture {
+        calc_surface_colors(pixels, |lat, lon| {
+            texture
+                .color_at(lat, lon)
+                .map(|color| SurfaceColor { color, shaded })
+        });
+    }
+
+    if let Coloring::Shading {
+        shadows: true,
+        light_dir,
+        ..
+    } = params.view.coloring
+    {
+        calc_shadows(params, terrain, light_dir, pixels);
+    }
+
+    if let Coloring::Shading {
+        ambient_occlusion: true,
+        ..
+    } = params.view.coloring
+    {
+        calc_sky_view(params, terrain, pixels);
+    }
+}
+
+/// The results of the generation kept for the whole image: the rendered image, and the data needed
+/// for the overlays and the layers.
+struct ImageData {
+    img: LinearImage,
+    directions: Vec<Vec<PixelDirection>>,
+    pixel_layers: Option<Vec<layers::PixelLayers>>,
 }
 
-pub fn generate(matches: &ArgMatches<'_>) -> Result<(), String> {
-    let config = match params::read_config(matches) {
-        Ok(config) => config,
-        Err(()) => {
-            // this indicates that 'output-atm-data' was chosen and data was printed, nothing more
-            // to do
-            return Ok(());
+impl ImageData {
+    fn new(params: &Params) -> Self {
+        Self {
+            img: LinearImage::new(params.output.width as u32, params.output.height as u32),
+            directions: Vec::with_capacity(params.output.height as usize),
+            pixel_layers: params.output.file_layers.as_ref().map(|_| Vec::new()),
         }
-    };
+    }
 
-    let mut terrain_folder = env::current_dir().unwrap();
-    terrain_folder.push(config.terrain_folder());
+    /// Adds the rows following the ones added so far.
+    fn add_rows(&mut self, rows: &[Vec<ResultPixel>], params: &Params) {
+        renderer::render_rows(&mut self.img, self.directions.len() as u32, rows, params);
+        self.directions.extend(
+            rows.iter()
+                .map(|row| row.iter().map(ResultPixel::direction).collect()),
+        );
+        if let Some(ref mut pixel_layers) = self.pixel_layers {
+            pixel_layers.extend(rows.iter().flatten().map(layers::pixel_layers));
+        }
+    }
+}
+
+/// Splits the rows of the image into the tiles calculated at once.
+fn row_tiles(rows: Range<u16>, tile_height: u16) -> impl Iterator<Item = Range<u16>> {
+    let tile_height = tile_height.max(1);
+    rows.clone()
+        .step_by(tile_height as usize)
+        .map(move |start| start..start.saturating_add(tile_height).min(rows.end))
+}
 
-    let start = SystemTime::now();
+/// Checks whether the pixels in a metadata file were generated with the same settings, ignoring
+/// the ones only affecting the outputs.
+fn same_pixel_params(params1: &Params, params2: &Params) -> bool {
+    let strip = |params: &Params| {
+        let mut params = params.clone();
+        params.output = Output {
+            width: params.output.width,
+            height: params.output.height,
+            generator: params.output.generator,
+            ..Default::default()
+        };
+        bincode::serialize(&params).ok()
+    };
+    strip(params1) == strip(params2)
+}
 
+/// Opens the metadata file of an interrupted generation, if it can be resumed with `params`.
+fn open_checkpoint(
+    filename: &str,
+    params: &Params,
+    start: SystemTime,
+) -> Option<MetadataReader<BufReader<File>>> {
+    if !Path::new(filename).exists() {
+        println!(
+            "{:.3}: No metadata file to resume from, starting from the beginning",
+            start.elapsed().unwrap().as_secs_f64()
+        );
+        return None;
+    }
+    let reason = match MetadataReader::open(filename) {
+        Ok(data) if data.header().format_version != FORMAT_VERSION => {
+            "it was written in an older format".to_owned()
+        }
+        Ok(data) if !same_pixel_params(data.params(), params) => {
+            "it was generated with different settings".to_owned()
+        }
+        Ok(data) => {
+            println!(
+                "{:.3}: Resuming from {:?}: {} of {} rows already calculated",
+                start.elapsed().unwrap().as_secs_f64(),
+                filename,
+                data.rows_done(),
+                params.output.height
+            );
+            return Some(data);
+        }
+        Err(err) => err,
+    };
     println!(
-        "{:.3}: Using terrain data directory: {:?}",
+        "{:.3}: Can't resume from {:?} ({}), starting from the beginning",
         start.elapsed().unwrap().as_secs_f64(),
-        terrain_folder
+        filename,
+        reason
     );
+    None
+}
 
-    let terrain = Terrain::from_folder(terrain_folder);
-
-    let params = config.into_params(&terrain);
+impl SurfaceColors {
+    /// Loads the land cover and the texture defined in the scene, if any.
+    pub fn load(params: &Params, start: SystemTime) -> Result<Self, String> {
+        if params.scene.land_cover.is_some() || params.scene.texture.is_some() {
+            println!(
+                "{:.3}: Loading the surface colors...",
+                start.elapsed().unwrap().as_secs_f64()
+            );
+        }
+        Ok(Self {
+            land_cover: params
+                .scene
+                .land_cover
+                .as_ref()
+                .map(LandCover::from_def)
+                .transpose()?,
+            texture: params
+                .scene
+                .texture
+                .as_ref()
+                .map(|texture| Texture::from_def(texture).map(|image| (image, texture.shading)))
+                .transpose()?,
+        })
+    }
+}
 
-    let generator: Box<dyn Generator> = match params.output.generator {
-        GeneratorDef::Fast => Box::new(FastGenerator::new(&params, &terrain, start)),
+/// Creates the generator chosen in `params.output.generator`. The log messages are timestamped
+/// relative to `start`.
+pub fn create_generator<'a>(
+    params: &'a Params,
+    terrain: &'a Terrain,
+    start: SystemTime,
+) -> Box<dyn Generator + 'a> {
+    match params.output.generator {
+        GeneratorDef::Fast => Box::new(FastGenerator::new(params, terrain, start)),
         GeneratorDef::InterpolatingRectilinear => Box::new(InterpolatingRectilinearGenerator::new(
-            &params, &terrain, start,
+            params, terrain, start,
         )),
-        GeneratorDef::Rectilinear => Box::new(RectilinearGenerator::new(&params, &terrain, start)),
+        GeneratorDef::Rectilinear => Box::new(RectilinearGenerator::new(params, terrain)),
+    }
+}
+
+/// Calculates the pixels in a tile of rows of the image, completed with the exit elevations, the
+/// surface colors, the shadows and the sky-view factors.
+pub fn generate_tile(
+    generator: &dyn Generator,
+    params: &Params,
+    terrain: &Terrain,
+    surface_colors: &SurfaceColors,
+    tile: Range<u16>,
+    progress: &Progress,
+) -> Vec<Vec<ResultPixel>> {
+    let mut rows = generator.generate_rows(tile, progress);
+    complete_pixels(params, terrain, surface_colors, &mut rows);
+    rows
+}
+
+/// Calculates all the pixels of the image at once.
+pub fn generate_pixels(
+    params: &Params,
+    terrain: &Terrain,
+    start: SystemTime,
+) -> Result<Vec<Vec<ResultPixel>>, String> {
+    let generator = create_generator(params, terrain, start);
+    let surface_colors = SurfaceColors::load(params, start)?;
+    let progress = Progress::new(
+        start,
+        params.output.width as usize * params.output.height as usize,
+    );
+    Ok(
+        row_tiles(0..params.output.height, params.output.tile_height)
+            .flat_map(|tile| {
+                generate_tile(
+                    &*generator,
+                    params,
+                    terrain,
+                    &surface_colors,
+                    tile,
+                    &progress,
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Generates the image and writes it, along with the metadata and the data layers, to the files
+/// set in `params.output`, resuming from the metadata file if `params.output.resume` is set. If a
+/// `coordinator` address is given, the tiles are calculated by the workers connecting to it (see
+/// `run_worker`) instead of locally. The log messages are timestamped relative to `start`.
+pub fn generate(
+    params: &Params,
+    terrain: &Terrain,
+    coordinator: Option<&str>,
+    start: SystemTime,
+) -> Result<(), String> {
+    if params.output.resume && params.output.file_metadata.is_none() {
+        return Err("resuming the generation requires a metadata file (--output-meta)".to_owned());
+    }
+
+    // the pixels are processed in tiles of rows as they are generated, so that only the data
+    // needed for the overlays and the layers is kept for the whole image
+    let mut image_data = ImageData::new(params);
+    let mut metadata = None;
+    if let Some(ref filename) = params.output.file_metadata {
+        let checkpoint = if params.output.resume {
+            open_checkpoint(filename, params, start)
+        } else {
+            None
+        };
+        let writer = match checkpoint {
+            Some(mut data) => {
+                data.for_each_chunk(|_, rows| image_data.add_rows(rows, params))?;
+                MetadataWriter::resume(filename, &data)
+            }
+            None => MetadataWriter::create(filename, params),
+        };
+        metadata = Some(writer?);
+    }
+
+    let rows_done = image_data.directions.len() as u16;
+    let tiles: Vec<_> =
+        row_tiles(rows_done..params.output.height, params.output.tile_height).collect();
+    let mut output_tile = |rows: &[Vec<ResultPixel>]| {
+        image_data.add_rows(rows, params);
+        match metadata {
+            Some(ref mut metadata) => metadata.write_rows(rows),
+            None => Ok(()),
+        }
     };
 
-    let result_pixels = generator.generate();
+    if let Some(address) = coordinator {
+        distributed::coordinate(address, params, tiles, start, &mut output_tile)?;
+    } else {
+        let generator = create_generator(params, terrain, start);
+        let surface_colors = SurfaceColors::load(params, start)?;
+        let progress = Progress::new(
+            start,
+            params.output.width as usize * (params.output.height - rows_done) as usize,
+        );
+        println!(
+            "{:.3}: Calculating pixels...",
+            start.elapsed().unwrap().as_secs_f64()
+        );
+        for tile in tiles {
+            let rows = generate_tile(
+                &*generator,
+                params,
+                terrain,
+                &surface_colors,
+                tile,
+                &progress,
+            );
+            output_tile(&rows)?;
+        }
+    }
+    println!(
+        "{:.3}: Done calculating",
+        start.elapsed().unwrap().as_secs_f64()
+    );
+
+    let ImageData {
+        img,
+        directions,
+        pixel_layers,
+    } = image_data;
 
     println!(
         "{:.3}: Outputting image...",
         start.elapsed().unwrap().as_secs_f64()
     );
-    crate::renderer::output_image(&result_pixels, &params, &terrain);
+    renderer::output_image(img, &directions, params, terrain)?;
 
-    if let Some(ref filename) = params.output.file_metadata {
+    if let (Some(ref filename), Some(pixel_layers)) = (&params.output.file_layers, pixel_layers) {
+        println!(
+            "{:.3}: Exporting data layers...",
+            start.elapsed().unwrap().as_secs_f64()
+        );
+        layers::output_layers(
+            filename,
+            &pixel_layers,
+            params.output.width as u32,
+            params.output.height as u32,
+        )?;
+    }
+
+    if let Some(metadata) = metadata {
         println!(
-            "{:.3}: Outputting metadata...",
+            "{:.3}: Finishing metadata...",
             start.elapsed().unwrap().as_secs_f64()
         );
-        output_metadata(filename, result_pixels, params.clone());
+        metadata.finish()?;
     }
 
     println!("{:.3}: Done.", start.elapsed().unwrap().as_secs_f64());
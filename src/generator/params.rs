@@ -1,17 +1,22 @@
-use std::{env, fs::File, io::Read};
+use std::{env, fs::File, io::Read, path::Path};
 
 use crate::{
-    coloring::{ColorPalette, ColoringMethod, Shading, SimpleColors},
-    object::{ConfObject, Object, SerializableObject},
-    terrain::Terrain,
-    utils::EarthModel,
+    coloring::{
+        ColorPalette, ColoringMethod, FalseColorQuantity, FalseColors, GridColors, Shading,
+        SimpleColors, Sky, Snow,
+    },
+    object::{
+        CelestialObject, CelestialPosition, ConfCelestialObject, ConfObject, Object,
+        SerializableObject,
+    },
+    terrain::{LandCoverDef, Terrain, TextureDef},
+    utils::{apparent_elevation, sun_position, DateTime, EarthModel},
 };
 
 use atm_refraction::{
     air::{Atmosphere, AtmosphereDef},
     Environment,
 };
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use nalgebra::Vector3;
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -67,6 +72,12 @@ pub struct ConfScene {
     pub objects: Vec<ConfObject>,
     #[serde(default = "default_terrain_alpha")]
     pub terrain_alpha: f64,
+    #[serde(default)]
+    pub celestial_objects: Vec<ConfCelestialObject>,
+    #[serde(default)]
+    pub land_cover: Option<LandCoverDef>,
+    #[serde(default)]
+    pub texture: Option<TextureDef>,
 }
 
 fn default_terrain_folder() -> String {
@@ -83,12 +94,20 @@ impl Default for ConfScene {
             terrain_folder: default_terrain_folder(),
             objects: vec![],
             terrain_alpha: default_terrain_alpha(),
+            celestial_objects: vec![],
+            land_cover: None,
+            texture: None,
         }
     }
 }
 
 impl ConfScene {
-    fn into_scene(self, terrain: &Terrain) -> Scene {
+    fn into_scene(
+        self,
+        terrain: &Terrain,
+        datetime: Option<DateTime>,
+        position: &Position,
+    ) -> Result<Scene, String> {
         let objects: Vec<_> = self
             .objects
             .into_iter()
@@ -98,12 +117,20 @@ impl ConfScene {
             .iter()
             .map(SerializableObject::into_object)
             .collect();
-        Scene {
+        let celestial_objects = self
+            .celestial_objects
+            .into_iter()
+            .map(|obj| obj.into_celestial_object(datetime, position))
+            .collect::<Result<_, _>>()?;
+        Ok(Scene {
             terrain_folder: self.terrain_folder,
             objects,
             callable_objects,
             terrain_alpha: self.terrain_alpha,
-        }
+            celestial_objects,
+            land_cover: self.land_cover,
+            texture: self.texture,
+        })
     }
 }
 
@@ -114,6 +141,9 @@ pub struct Scene {
     #[serde(skip)]
     callable_objects: Vec<Box<dyn Object + Sync>>,
     pub terrain_alpha: f64,
+    pub celestial_objects: Vec<CelestialObject>,
+    pub land_cover: Option<LandCoverDef>,
+    pub texture: Option<TextureDef>,
 }
 
 impl Clone for Scene {
@@ -127,11 +157,36 @@ impl Clone for Scene {
                 .map(SerializableObject::into_object)
                 .collect(),
             terrain_alpha: self.terrain_alpha,
+            celestial_objects: self.celestial_objects.clone(),
+            land_cover: self.land_cover.clone(),
+            texture: self.texture.clone(),
         }
     }
 }
 
 impl Scene {
+    /// Creates a scene with only the terrain and the objects on it, as in the metadata files
+    /// written before the other elements of the scene were introduced.
+    pub(crate) fn with_objects(
+        terrain_folder: String,
+        objects: Vec<SerializableObject>,
+        terrain_alpha: f64,
+    ) -> Self {
+        let callable_objects = objects
+            .iter()
+            .map(SerializableObject::into_object)
+            .collect();
+        Self {
+            terrain_folder,
+            objects,
+            callable_objects,
+            terrain_alpha,
+            celestial_objects: vec![],
+            land_cover: None,
+            texture: None,
+        }
+    }
+
     pub fn objects(&self) -> &[Box<dyn Object + Sync>] {
         &self.callable_objects
     }
@@ -172,11 +227,13 @@ impl Default for Frame {
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum ConfColoring {
     Simple {
         #[serde(default)]
         water_level: f64,
+        #[serde(default)]
+        palette: Option<ColorPalette>,
     },
     Shading {
         #[serde(default)]
@@ -189,6 +246,38 @@ pub enum ConfColoring {
         light_dir: f64,
         #[serde(default)]
         palette: ColorPalette,
+        #[serde(default)]
+        shadows: bool,
+        #[serde(default)]
+        ambient_occlusion: bool,
+        #[serde(default)]
+        snow: Option<Snow>,
+    },
+    /// A depth map: colors by the distance in km (by default up to the max distance).
+    Distance {
+        #[serde(default)]
+        min: f64,
+        max: Option<f64>,
+    },
+    /// Colors by the slope of the terrain in degrees.
+    Slope {
+        #[serde(default)]
+        min: f64,
+        #[serde(default = "default_max_slope")]
+        max: f64,
+    },
+    /// Colors by the difference between the length of the light path and the distance, in
+    /// meters.
+    RefractionExcess {
+        #[serde(default)]
+        min: f64,
+        #[serde(default = "default_max_refraction_excess")]
+        max: f64,
+    },
+    /// Draws the latitude/longitude grid over the terrain, with the spacing in degrees.
+    Grid {
+        #[serde(default = "default_grid_spacing")]
+        spacing: f64,
     },
 }
 
@@ -200,6 +289,30 @@ fn default_zenith_angle() -> f64 {
     45.0
 }
 
+/// Creates a false color coloring, checking that the range of the values is valid.
+fn false_color(quantity: FalseColorQuantity, min: f64, max: f64) -> Result<Coloring, String> {
+    if !min.is_finite() || !max.is_finite() || min >= max {
+        return Err(format!(
+            "the minimum of the false color scale has to be smaller than the maximum (got {} \
+            and {})",
+            min, max
+        ));
+    }
+    Ok(Coloring::FalseColor { quantity, min, max })
+}
+
+fn default_max_slope() -> f64 {
+    60.0
+}
+
+fn default_max_refraction_excess() -> f64 {
+    50.0
+}
+
+fn default_grid_spacing() -> f64 {
+    0.1
+}
+
 impl Default for ConfColoring {
     fn default() -> Self {
         ConfColoring::Shading {
@@ -208,43 +321,83 @@ impl Default for ConfColoring {
             light_zenith_angle: default_zenith_angle(),
             light_dir: 0.0,
             palette: ColorPalette::default(),
+            shadows: false,
+            ambient_occlusion: false,
+            snow: None,
         }
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Coloring {
     Simple {
         water_level: f64,
         max_distance: f64,
+        palette: Option<ColorPalette>,
     },
     Shading {
         water_level: f64,
         ambient_light: f64,
         light_dir: Vector3<f64>,
         palette: ColorPalette,
+        shadows: bool,
+        ambient_occlusion: bool,
+        snow: Option<Snow>,
+    },
+    FalseColor {
+        quantity: FalseColorQuantity,
+        min: f64,
+        max: f64,
+    },
+    Grid {
+        spacing: f64,
+        max_distance: f64,
     },
 }
 
 impl ConfColoring {
+    /// Converts the coloring config into the coloring method; if the position of the sun is
+    /// known, it overrides the direction of light set in the config. Returns an error if the
+    /// config is invalid.
     pub fn into_coloring(
         self,
         frame: &Frame,
         position: &Position,
         earth_model: &EarthModel,
-    ) -> Coloring {
-        match self {
-            ConfColoring::Simple { water_level } => Coloring::Simple {
+        sun: Option<SunPosition>,
+    ) -> Result<Coloring, String> {
+        Ok(match self {
+            ConfColoring::Simple {
                 water_level,
-                max_distance: frame.max_distance,
-            },
+                palette,
+            } => {
+                if let Some(ref palette) = palette {
+                    palette.validate()?;
+                }
+                Coloring::Simple {
+                    water_level,
+                    max_distance: frame.max_distance,
+                    palette,
+                }
+            }
             ConfColoring::Shading {
                 water_level,
                 ambient_light,
                 light_zenith_angle,
                 light_dir,
                 palette,
+                shadows,
+                ambient_occlusion,
+                snow,
             } => {
+                palette.validate()?;
+                if let Some(snow) = snow {
+                    if !(snow.transition >= 0.0 && snow.transition.is_finite()) {
+                        return Err(
+                            "the snow line transition has to be a non-negative number".to_owned()
+                        );
+                    }
+                }
                 let light_zenith_angle = light_zenith_angle.to_radians();
                 let light_dir = light_dir.to_radians();
                 let (dir_north, dir_east, dir_up) =
@@ -252,68 +405,199 @@ impl ConfColoring {
                 let front_azimuth = frame.direction.to_radians();
                 let dir_front = dir_north * front_azimuth.cos() + dir_east * front_azimuth.sin();
                 let dir_right = dir_east * front_azimuth.cos() - dir_north * front_azimuth.sin();
-                let light_dir = (-dir_front * light_zenith_angle.sin() * light_dir.cos()
-                    + dir_right * light_zenith_angle.sin() * light_dir.sin()
-                    + dir_up * light_zenith_angle.cos())
-                .normalize();
+                let light_dir = match sun {
+                    Some(sun) => {
+                        let azimuth = sun.azimuth.to_radians();
+                        let elevation = sun.elevation.to_radians();
+                        (dir_north * elevation.cos() * azimuth.cos()
+                            + dir_east * elevation.cos() * azimuth.sin()
+                            + dir_up * elevation.sin())
+                        .normalize()
+                    }
+                    None => (-dir_front * light_zenith_angle.sin() * light_dir.cos()
+                        + dir_right * light_zenith_angle.sin() * light_dir.sin()
+                        + dir_up * light_zenith_angle.cos())
+                    .normalize(),
+                };
                 Coloring::Shading {
                     water_level,
                     ambient_light,
                     light_dir,
                     palette,
+                    shadows,
+                    ambient_occlusion,
+                    snow,
                 }
             }
-        }
+            ConfColoring::Distance { min, max } => false_color(
+                FalseColorQuantity::Distance,
+                min,
+                max.unwrap_or(frame.max_distance / 1000.0),
+            )?,
+            ConfColoring::Slope { min, max } => false_color(FalseColorQuantity::Slope, min, max)?,
+            ConfColoring::RefractionExcess { min, max } => {
+                false_color(FalseColorQuantity::RefractionExcess, min, max)?
+            }
+            ConfColoring::Grid { spacing } => {
+                if !(spacing > 0.0 && spacing.is_finite()) {
+                    return Err("the grid spacing has to be positive".to_owned());
+                }
+                Coloring::Grid {
+                    spacing,
+                    max_distance: frame.max_distance,
+                }
+            }
+        })
     }
 }
 
 impl Coloring {
-    pub fn coloring_method(&self) -> Box<dyn ColoringMethod> {
-        match *self {
+    pub fn coloring_method(&self, sky: Option<Sky>, model: &EarthModel) -> Box<dyn ColoringMethod> {
+        match self {
             Coloring::Simple {
                 water_level,
                 max_distance,
-            } => Box::new(SimpleColors::new(max_distance, water_level)),
+                palette,
+            } => Box::new(SimpleColors::new(
+                *max_distance,
+                *water_level,
+                palette.clone(),
+                sky,
+            )),
             Coloring::Shading {
                 water_level,
                 ambient_light,
                 light_dir,
                 palette,
-            } => Box::new(Shading::new(water_level, ambient_light, light_dir, palette)),
+                snow,
+                ..
+            } => Box::new(Shading::new(
+                *water_level,
+                *ambient_light,
+                *light_dir,
+                palette.clone(),
+                *snow,
+                *model,
+                sky,
+            )),
+            Coloring::FalseColor { quantity, min, max } => {
+                Box::new(FalseColors::new(*quantity, *min, *max, *model))
+            }
+            Coloring::Grid {
+                spacing,
+                max_distance,
+            } => Box::new(GridColors::new(*spacing, *max_distance)),
         }
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize, Default)]
+/// Parameters of the extinction of light by the air (Rayleigh scattering) and aerosols (Mie
+/// scattering).
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Extinction {
+    /// The extinction coefficient of aerosols at sea level for 550 nm light, in 1/m.
+    #[serde(default = "default_aerosol_extinction")]
+    pub aerosol_extinction: f64,
+    /// The Angstrom exponent describing how the aerosol extinction depends on the wavelength.
+    #[serde(default = "default_angstrom_exponent")]
+    pub angstrom_exponent: f64,
+    /// The color (RGB, 0-1) of the light scattered into the line of sight, which is what very
+    /// distant objects fade into; by default the color of the sky in the direction of the pixel,
+    /// or `DEFAULT_AIRLIGHT` without a sky model.
+    #[serde(default)]
+    pub airlight: Option<[f64; 3]>,
+}
+
+pub const DEFAULT_AIRLIGHT: [f64; 3] = [0.8, 0.8, 0.8];
+
+fn default_aerosol_extinction() -> f64 {
+    2e-5
+}
+
+fn default_angstrom_exponent() -> f64 {
+    1.3
+}
+
+/// The position of the sun in the sky, in degrees.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SunPosition {
+    pub azimuth: f64,
+    /// The elevation angle at which the sun is seen, including the refraction.
+    pub elevation: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
 pub struct ConfView {
     #[serde(default)]
-    position: Position,
+    pub position: Position,
     #[serde(default)]
-    frame: Frame,
+    pub frame: Frame,
+    #[serde(default)]
+    pub coloring: ConfColoring,
+    pub fog_distance: Option<f64>,
+    pub extinction: Option<Extinction>,
+    pub sky: Option<Sky>,
+    pub datetime: Option<DateTime>,
+    /// Draws the disk of the sun; a shorthand for a `Sun` celestial object.
     #[serde(default)]
-    coloring: ConfColoring,
-    fog_distance: Option<f64>,
+    pub sun_disk: bool,
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct View {
     pub position: Position,
     pub frame: Frame,
     pub coloring: Coloring,
     pub fog_distance: Option<f64>,
+    pub extinction: Option<Extinction>,
+    pub sky: Option<Sky>,
+    pub datetime: Option<DateTime>,
+    pub sun: Option<SunPosition>,
 }
 
 impl ConfView {
-    pub fn into_view(self, earth_model: &EarthModel) -> View {
-        let coloring = self
-            .coloring
-            .into_coloring(&self.frame, &self.position, earth_model);
-        View {
+    /// Calculates the apparent position of the sun at the configured date and time.
+    fn sun_position(
+        &self,
+        env: &Environment,
+        observer_alt: f64,
+        straight_rays: bool,
+        integrator: Integrator,
+    ) -> Option<SunPosition> {
+        let datetime = self.datetime?;
+        let (azimuth, elevation) =
+            sun_position(&datetime, self.position.latitude, self.position.longitude);
+        let elevation = if straight_rays {
+            elevation
+        } else {
+            // if the sun is so low that no ray reaches it, its geometric position is good enough
+            apparent_elevation(env, integrator, observer_alt, elevation).unwrap_or(elevation)
+        };
+        Some(SunPosition { azimuth, elevation })
+    }
+
+    pub fn into_view(
+        self,
+        earth_model: &EarthModel,
+        sun: Option<SunPosition>,
+    ) -> Result<View, String> {
+        let coloring =
+            self.coloring
+                .into_coloring(&self.frame, &self.position, earth_model, sun)?;
+        let sky = match sun {
+            Some(sun) => self.sky.map(|sky| sky.with_sun(sun.azimuth, sun.elevation)),
+            None => self.sky,
+        };
+        Ok(View {
             position: self.position,
             frame: self.frame,
             coloring,
             fog_distance: self.fog_distance,
-        }
+            extinction: self.extinction,
+            sky,
+            datetime: self.datetime,
+            sun,
+        })
     }
 }
 
@@ -391,11 +675,29 @@ pub enum GeneratorDef {
     Rectilinear,
 }
 
+/// The format of the output image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    /// 8-bit sRGB, in any file format supported by the image library (chosen by the extension).
+    Srgb8,
+    /// 16-bit sRGB PNG.
+    Png16,
+    /// 32-bit floating point OpenEXR, in linear light.
+    Exr,
+    /// 32-bit floating point Portable Float Map, in linear light.
+    Pfm,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Output {
     #[serde(default = "default_file")]
     pub file: String,
+    /// The format of the image; by default it is chosen by the extension of the file (`.exr`,
+    /// `.pfm` or 8-bit otherwise).
+    pub format: Option<OutputFormat>,
     pub file_metadata: Option<String>,
+    /// The file to export the per-pixel data layers to, as a float TIFF or a `.npy` array.
+    pub file_layers: Option<String>,
     #[serde(default = "default_width")]
     pub width: u16,
     #[serde(default = "default_height")]
@@ -408,14 +710,46 @@ pub struct Output {
     pub show_eye_level: bool,
     #[serde(default)]
     pub show_flat_horizon: bool,
+    /// Whether ordered dithering is applied when the colors are quantized to 8 bits, which hides
+    /// the banding of smooth gradients.
+    #[serde(default)]
+    pub dither: bool,
     #[serde(default = "default_generator")]
     pub generator: GeneratorDef,
+    /// The number of rows of pixels calculated at once; the metadata file is written after every
+    /// tile, so that an interrupted generation can be resumed.
+    #[serde(default = "default_tile_height")]
+    pub tile_height: u16,
+    /// Whether to continue the generation from the tiles already in the metadata file.
+    #[serde(default)]
+    pub resume: bool,
+    /// How long (in seconds) the coordinator of a distributed generation waits for a worker to
+    /// send a tile back before giving it to another one.
+    #[serde(default = "default_worker_timeout")]
+    pub worker_timeout: u64,
 }
 
 fn default_file() -> String {
     "./output.png".to_owned()
 }
 
+impl Output {
+    pub fn format(&self) -> OutputFormat {
+        if let Some(format) = self.format {
+            return format;
+        }
+        let extension = Path::new(&self.file)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("exr") => OutputFormat::Exr,
+            Some("pfm") => OutputFormat::Pfm,
+            _ => OutputFormat::Srgb8,
+        }
+    }
+}
+
 fn default_width() -> u16 {
     640
 }
@@ -428,40 +762,116 @@ fn default_generator() -> GeneratorDef {
     GeneratorDef::Fast
 }
 
+fn default_tile_height() -> u16 {
+    32
+}
+
+fn default_worker_timeout() -> u64 {
+    3600
+}
+
 impl Default for Output {
     fn default() -> Output {
         Output {
             file: default_file(),
+            format: None,
             file_metadata: None,
+            file_layers: None,
             width: default_width(),
             height: default_height(),
             ticks: Vec::new(),
             vertical_ticks: Vec::new(),
             show_eye_level: false,
             show_flat_horizon: false,
+            dither: false,
             generator: default_generator(),
+            tile_height: default_tile_height(),
+            resume: false,
+            worker_timeout: default_worker_timeout(),
         }
     }
 }
 
+/// The method of integrating the ray equations.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub enum Integrator {
+    #[default]
+    Rk4,
+    Rk8,
+    /// Runge-Kutta with the step size chosen so that the estimated error of the altitude in a
+    /// single step doesn't exceed `max_error` meters.
+    Adaptive {
+        #[serde(default = "default_min_step")]
+        min_step: f64,
+        #[serde(default = "default_max_step")]
+        max_step: f64,
+        #[serde(default = "default_max_error")]
+        max_error: f64,
+    },
+}
+
+impl Integrator {
+    /// Returns an error if the step sizes or the tolerance of the adaptive integrator are invalid.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Integrator::Adaptive {
+            min_step,
+            max_step,
+            max_error,
+        } = *self
+        {
+            let positive = |value: f64| value.is_finite() && value > 0.0;
+            if !positive(min_step) || !positive(max_step) {
+                return Err("the steps of the adaptive integrator have to be positive".to_owned());
+            }
+            if min_step > max_step {
+                return Err(
+                    "the minimum step of the adaptive integrator can't exceed the maximum step"
+                        .to_owned(),
+                );
+            }
+            if !positive(max_error) {
+                return Err(
+                    "the maximum error of the adaptive integrator has to be positive".to_owned(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_min_step() -> f64 {
+    1.0
+}
+
+fn default_max_step() -> f64 {
+    500.0
+}
+
+fn default_max_error() -> f64 {
+    1e-4
+}
+
+/// The settings of the generation, as read from the YAML config file.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
-    scene: ConfScene,
+    pub scene: ConfScene,
     #[serde(default)]
-    view: ConfView,
+    pub view: ConfView,
     #[serde(default = "AtmosphereDef::us_76")]
-    pub(crate) atmosphere: AtmosphereDef,
+    pub atmosphere: AtmosphereDef,
     #[serde(default = "default_earth_shape")]
-    pub(crate) earth_shape: EarthModel,
+    pub earth_shape: EarthModel,
     #[serde(default = "default_wavelength")]
-    pub(crate) wavelength: f64,
+    pub wavelength: f64,
     #[serde(default)]
-    straight_rays: bool,
+    pub straight_rays: bool,
     #[serde(default = "default_simulation_step")]
-    simulation_step: f64,
+    pub simulation_step: f64,
+    #[serde(default)]
+    pub integrator: Integrator,
     #[serde(default)]
-    output: Output,
+    pub output: Output,
 }
 
 fn default_earth_shape() -> EarthModel {
@@ -488,11 +898,13 @@ impl Default for Config {
             wavelength: default_wavelength(),
             straight_rays: false,
             simulation_step: default_simulation_step(),
+            integrator: Default::default(),
             output: Default::default(),
         }
     }
 }
 
+/// The settings of the generation, resolved into the form used by the generators and the renderer.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Params {
     pub scene: Scene,
@@ -501,6 +913,7 @@ pub struct Params {
     pub env: Environment,
     pub straight_rays: bool,
     pub simulation_step: f64,
+    pub integrator: Integrator,
     pub output: Output,
 }
 
@@ -509,172 +922,54 @@ impl Config {
         &self.scene.terrain_folder
     }
 
-    pub fn into_params(self, terrain: &Terrain) -> Params {
-        let scene = self.scene.into_scene(terrain);
+    pub fn position(&self) -> &Position {
+        &self.view.position
+    }
+
+    /// Resolves the config into `Params`; the terrain is used for the altitudes defined relative to
+    /// the ground. Returns an error if the config is invalid.
+    pub fn into_params(mut self, terrain: &Terrain) -> Result<Params, String> {
+        if self.view.sun_disk
+            && !self
+                .scene
+                .celestial_objects
+                .iter()
+                .any(|obj| matches!(obj.position, CelestialPosition::Sun))
+        {
+            self.scene
+                .celestial_objects
+                .insert(0, ConfCelestialObject::sun());
+        }
+        self.integrator.validate()?;
+        let scene = self
+            .scene
+            .into_scene(terrain, self.view.datetime, &self.view.position)?;
         let atmosphere = Atmosphere::from_def(self.atmosphere);
-        Params {
+        let env = Environment {
+            shape: self.earth_shape.to_shape(),
+            atmosphere,
+            wavelength: self.wavelength,
+        };
+        let sun = self.view.sun_position(
+            &env,
+            self.view.position.abs_altitude(terrain),
+            self.straight_rays,
+            self.integrator,
+        );
+        Ok(Params {
             scene,
-            view: self.view.into_view(&self.earth_shape),
+            view: self.view.into_view(&self.earth_shape, sun)?,
             model: self.earth_shape,
-            env: Environment {
-                shape: self.earth_shape.to_shape(),
-                atmosphere,
-                wavelength: self.wavelength,
-            },
+            env,
             straight_rays: self.straight_rays,
             simulation_step: self.simulation_step,
+            integrator: self.integrator,
             output: self.output,
-        }
+        })
     }
 }
 
-pub fn subcommand_def() -> App<'static, 'static> {
-    SubCommand::with_name(super::SUBCOMMAND).about("Render a panorama")
-        .setting(AppSettings::AllowLeadingHyphen)
-        .arg(
-            Arg::with_name("terrain")
-                .short("t")
-                .long("terrain")
-                .value_name("PATH")
-                .help("Path to the folder with terrain files (./terrain assumed if none)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("latitude")
-                .short("l")
-                .long("lat")
-                .value_name("DEG")
-                .help("Viewpoint latitude in degrees (default: 0)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("longitude")
-                .short("g")
-                .long("lon")
-                .value_name("DEG")
-                .help("Viewpoint longitude in degrees (default: 0)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("altitude")
-                .short("a")
-                .long("alt")
-                .value_name("ALT")
-                .conflicts_with("elevation")
-                .help("Viewpoint altitude in meters")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("elevation")
-                .short("e")
-                .long("elev")
-                .value_name("ELEV")
-                .conflicts_with("altitude")
-                .help("Viewpoint elevation in meters (above the terrain)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("direction")
-                .short("d")
-                .long("dir")
-                .value_name("DEG")
-                .help(
-                    "Viewing azimuth in degrees (ie. 0 = north, 90 = east, 180 = south, 270 = west)",
-                )
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("fov")
-                .short("f")
-                .long("fov")
-                .value_name("DEG")
-                .help("Horizontal field of view in degrees (default: 30)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("tilt")
-                .short("i")
-                .long("tilt")
-                .value_name("DEG")
-                .help("Observer tilt relative to the horizon in degrees (default: 0)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("max-dist")
-                .short("m")
-                .long("maxdist")
-                .value_name("DIST")
-                .help("Cutoff distance in km (default: 150)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("step")
-                .long("step")
-                .value_name("STEP")
-                .help("Light ray propagation step in meters (default: 50)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("radius")
-                .short("R")
-                .long("radius")
-                .value_name("RADIUS")
-                .help("Calculate assuming the given value as the Earth's radius, in km (default: 6371) (conflicts with --flat)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("flat")
-                .long("flat")
-                .help("Simulate a flat Earth using the FlatDistorted model (light paths like on a flat Earth, but with distances distorted for southern hemisphere to yield reasonable results) (conflicts with --radius)")
-                .takes_value(false),
-        )
-        .arg(
-            Arg::with_name("straight")
-                .short("s")
-                .long("straight")
-                .help("Ignore the atmosphere (use straight-line light rays)")
-                .takes_value(false),
-        )
-        .arg(
-            Arg::with_name("output")
-                .long("output")
-                .value_name("FILE")
-                .help("File name to save the output image as (default: output.png)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("output-meta")
-                .long("output-meta")
-                .value_name("FILE")
-                .help("File name to save the output metadata as")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("width")
-                .short("w")
-                .long("width")
-                .value_name("PIXELS")
-                .help("Output image width in pixels (default: 640)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("height")
-                .short("h")
-                .long("height")
-                .value_name("PIXELS")
-                .help("Output image height in pixels (default: 480)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("config")
-                .short("c")
-                .long("config")
-                .value_name("FILE")
-                .help("Path to a config file with alternative defaults")
-                .takes_value(true),
-        )
-}
-
+/// Reads a YAML config file, with the path relative to the working directory.
 pub fn parse_config(filename: &str) -> Config {
     let mut config_abs_path = env::current_dir().unwrap();
     config_abs_path.push(filename);
@@ -691,87 +986,104 @@ pub fn parse_config(filename: &str) -> Config {
     serde_yaml::from_str::<Config>(&contents).expect("failed parsing config file")
 }
 
-pub fn read_config(matches: &ArgMatches<'_>) -> Result<Config, ()> {
-    let mut config = if let Some(config_path) = matches.value_of("config") {
-        parse_config(config_path)
-    } else {
-        Default::default()
-    };
-
-    if let Some(terrain) = matches.value_of("terrain") {
-        config.scene.terrain_folder = terrain.to_owned();
-    }
-    if let Some(output) = matches.value_of("output") {
-        config.output.file = output.to_owned();
-    }
-    if let Some(output_metadata) = matches.value_of("output-meta") {
-        config.output.file_metadata = Some(output_metadata.to_owned());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sun_disk() {
+        let terrain = Terrain::new();
+        let mut config = Config::default();
+        config.view.sun_disk = true;
+        assert!(config.clone().into_params(&terrain).is_err());
+
+        config.view.datetime = Some("2024-06-20T12:00Z".parse().unwrap());
+        let params = config.into_params(&terrain).unwrap();
+        assert_eq!(params.scene.celestial_objects.len(), 1);
+        let sun = params.view.sun.unwrap();
+        assert!((params.scene.celestial_objects[0].azimuth - sun.azimuth).abs() < 1e-9);
     }
 
-    if let Some(pic_width) = matches.value_of("width") {
-        config.output.width = pic_width.parse().expect("Invalid output width");
-    }
-
-    if let Some(pic_height) = matches.value_of("height") {
-        config.output.height = pic_height.parse().expect("Invalid output height");
-    }
-
-    if let Some(lat) = matches.value_of("latitude") {
-        config.view.position.latitude = lat.parse().expect("Invalid viewpoint latitude");
-    }
-
-    if let Some(lon) = matches.value_of("longitude") {
-        config.view.position.longitude = lon.parse().expect("Invalid viewpoint longitude");
-    }
-
-    match (matches.value_of("altitude"), matches.value_of("elevation")) {
-        (Some(a), None) => {
-            config.view.position.altitude =
-                Altitude::Absolute(a.parse().expect("Invalid viewpoint altitude"));
-        }
-        (None, Some(e)) => {
-            config.view.position.altitude =
-                Altitude::Relative(e.parse().expect("Invalid viewpoint elevation"));
+    #[test]
+    fn test_invalid_false_color_range() {
+        let terrain = Terrain::new();
+        let with_coloring = |coloring| {
+            let mut config = Config::default();
+            config.view.coloring = coloring;
+            config.into_params(&terrain)
+        };
+        assert!(with_coloring(ConfColoring::Slope {
+            min: 0.0,
+            max: 45.0
+        })
+        .is_ok());
+        assert!(with_coloring(ConfColoring::Slope {
+            min: 10.0,
+            max: 10.0
+        })
+        .is_err());
+        assert!(with_coloring(ConfColoring::RefractionExcess { min: 5.0, max: 1.0 }).is_err());
+        assert!(with_coloring(ConfColoring::Distance {
+            min: 0.0,
+            max: Some(f64::NAN)
+        })
+        .is_err());
+        // the maximum distance is the default end of the range
+        assert!(with_coloring(ConfColoring::Distance {
+            min: 1000.0,
+            max: None
+        })
+        .is_err());
+        assert!(with_coloring(ConfColoring::Grid { spacing: 1000.0 }).is_ok());
+        for spacing in [0.0, -1000.0, f64::NAN, f64::INFINITY] {
+            assert!(with_coloring(ConfColoring::Grid { spacing }).is_err());
         }
-        _ => (),
-    };
-
-    if let Some(dir) = matches.value_of("direction") {
-        config.view.frame.direction = dir.parse().expect("Invalid viewing azimuth");
-    }
-
-    if let Some(fov) = matches.value_of("fov") {
-        config.view.frame.fov = fov.parse().expect("Invalid field of view");
     }
 
-    if let Some(tilt) = matches.value_of("tilt") {
-        config.view.frame.tilt = tilt.parse().expect("Invalid view tilt");
-    }
-
-    if let Some(max_dist) = matches.value_of("max-dist") {
-        config.view.frame.max_distance =
-            max_dist.parse::<f64>().expect("Invalid cutoff distance") * 1e3;
-    }
-
-    if let Some(step) = matches.value_of("step") {
-        config.simulation_step = step.parse().expect("Invalid step value");
-    }
-
-    match (matches.is_present("flat"), matches.value_of("radius")) {
-        (true, None) => {
-            config.earth_shape = EarthModel::FlatDistorted;
-        }
-        (false, Some(radius)) => {
-            let r: f64 = radius.parse().expect("Invalid radius passed");
-            config.earth_shape = EarthModel::Spherical { radius: r * 1e3 };
+    #[test]
+    fn test_invalid_snow_transition() {
+        let terrain = Terrain::new();
+        let with_transition = |transition| {
+            let mut coloring: ConfColoring =
+                serde_yaml::from_str("Shading:\n  snow:\n    snow_line: 1000.0").unwrap();
+            if let ConfColoring::Shading {
+                snow: Some(snow), ..
+            } = &mut coloring
+            {
+                snow.transition = transition;
+            }
+            let mut config = Config::default();
+            config.view.coloring = coloring;
+            config.into_params(&terrain)
+        };
+        assert!(with_transition(0.0).is_ok());
+        assert!(with_transition(100.0).is_ok());
+        for transition in [-1.0, f64::NAN, f64::INFINITY] {
+            assert!(with_transition(transition).is_err());
         }
-        (true, Some(_)) => panic!("Conflicting Earth shape options chosen!"),
-        _ => (),
-    };
-
-    if matches.is_present("straight") {
-        config.straight_rays = true;
     }
 
-    Ok(config)
+    #[test]
+    fn test_invalid_adaptive_integrator() {
+        let terrain = Terrain::new();
+        let adaptive = |min_step, max_step, max_error| {
+            let config = Config {
+                integrator: Integrator::Adaptive {
+                    min_step,
+                    max_step,
+                    max_error,
+                },
+                ..Default::default()
+            };
+            config.into_params(&terrain)
+        };
+        assert!(adaptive(1.0, 500.0, 1e-4).is_ok());
+        assert!(adaptive(10.0, 10.0, 1e-4).is_ok());
+        assert!(adaptive(100.0, 10.0, 1e-4).is_err());
+        assert!(adaptive(0.0, 500.0, 1e-4).is_err());
+        assert!(adaptive(-1.0, 500.0, 1e-4).is_err());
+        assert!(adaptive(1.0, f64::INFINITY, 1e-4).is_err());
+        assert!(adaptive(1.0, 500.0, 0.0).is_err());
+        assert!(adaptive(1.0, 500.0, f64::NAN).is_err());
+    }
 }
@@ -0,0 +1,246 @@
+use std::env;
+
+use atm_raytracer::{
+    generator::{
+        gen_path_cache, gen_terrain_cache,
+        params::{Config, Params},
+    },
+    terrain::Terrain,
+    utils::EarthModel,
+};
+use atm_refraction::EarthShape;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use crate::{
+    elev_profile::elev_profile,
+    line_of_sight::{height_at_dist, lowest_visible_ray},
+    plot::Plot,
+};
+
+pub const SUBCOMMAND: &str = "output-hidden-height";
+
+const GLOBE_COLOR: [u8; 3] = [0, 96, 192];
+const FLAT_COLOR: [u8; 3] = [192, 48, 0];
+
+/// Calculates the hidden height (the height of the part of an object that is hidden behind the
+/// terrain or the horizon) at distances separated by `step` along the given azimuth.
+pub fn hidden_height_profile(
+    params: &Params,
+    terrain: &Terrain,
+    azim: f64,
+    step: f64,
+    cutoff: f64,
+) -> Vec<(f64, f64)> {
+    let mut params = params.clone();
+    params.view.frame.max_distance = cutoff + 2.0 * params.simulation_step;
+
+    let terrain_cache = gen_terrain_cache(&params, terrain, azim);
+    let dist_calc = params.model.coords_at_dist_calc(
+        (
+            params.view.position.latitude,
+            params.view.position.longitude,
+        ),
+        azim,
+    );
+
+    let mut min_ang = -89.0;
+    let mut result = vec![];
+
+    // the lowest visible ray can only get higher with the distance, so the search for every point
+    // can start where the previous one ended
+    for (dist, ground) in elev_profile(terrain, &*dist_calc, step, cutoff)
+        .into_iter()
+        .skip(1)
+    {
+        let lowest_alt =
+            match lowest_visible_ray(&params, terrain, &terrain_cache, dist, (min_ang, 89.0)) {
+                Some(ang) => {
+                    min_ang = ang;
+                    height_at_dist(&gen_path_cache(&params, terrain, ang), dist)
+                }
+                None => None,
+            };
+        let hidden = lowest_alt.map_or(f64::INFINITY, |alt| (alt - ground).max(0.0));
+        result.push((dist, hidden));
+    }
+
+    result
+}
+
+fn params_for_model(
+    config: &Config,
+    model: EarthModel,
+    terrain: &Terrain,
+) -> Result<Params, String> {
+    let mut config = config.clone();
+    config.earth_shape = model;
+    config.into_params(terrain)
+}
+
+fn is_flat(model: &EarthModel) -> bool {
+    matches!(model.to_shape(), EarthShape::Flat)
+}
+
+pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
+    let filename = matches
+        .value_of("input")
+        .expect("please provide an input file");
+
+    let azim: f64 = matches
+        .value_of("azim")
+        .unwrap_or("0.0")
+        .parse()
+        .expect("please provide a valid azimuth");
+
+    let step: f64 = matches
+        .value_of("step")
+        .unwrap_or("50.0")
+        .parse()
+        .expect("please provide a valid step size");
+
+    let cutoff: f64 = matches
+        .value_of("cutoff_dist")
+        .unwrap_or("10000.0")
+        .parse()
+        .expect("please provide a valid cutoff distance");
+
+    assert!(step > 0.0, "step must be positive");
+
+    let config = atm_raytracer::generator::params::parse_config(filename);
+
+    let flat_model = match matches.value_of("flat_model") {
+        Some(model) => serde_yaml::from_str::<EarthModel>(model)
+            .map_err(|err| format!("invalid flat Earth model {:?}: {}", model, err))?,
+        None if is_flat(&config.earth_shape) => config.earth_shape,
+        None => EarthModel::FlatDistorted,
+    };
+    if !is_flat(&flat_model) {
+        return Err(format!("{:?} is not a flat Earth model", flat_model));
+    }
+    let globe_model = if is_flat(&config.earth_shape) {
+        EarthModel::SimpleSphere
+    } else {
+        config.earth_shape
+    };
+
+    let mut terrain_folder = env::current_dir().unwrap();
+    terrain_folder.push(config.terrain_folder());
+
+    let terrain = Terrain::from_folder(terrain_folder);
+
+    let globe_params = params_for_model(&config, globe_model, &terrain)?;
+    let flat_params = params_for_model(&config, flat_model, &terrain)?;
+
+    let globe = hidden_height_profile(&globe_params, &terrain, azim, step, cutoff);
+    let flat = hidden_height_profile(&flat_params, &terrain, azim, step, cutoff);
+
+    if let Some(plot_file) = matches.value_of("plot") {
+        let mut plot = Plot::new("Distance [m]", "Hidden height [m]");
+        let finite = |points: &[(f64, f64)]| {
+            points
+                .iter()
+                .copied()
+                .filter(|(_, h)| h.is_finite())
+                .collect()
+        };
+        plot.add_labelled_series("Globe", finite(&globe), GLOBE_COLOR);
+        plot.add_labelled_series("Flat", finite(&flat), FLAT_COLOR);
+        plot.save(plot_file)?;
+    }
+
+    for ((dist, globe_hidden), (_, flat_hidden)) in globe.into_iter().zip(flat) {
+        println!("{}\t{}\t{}", dist, globe_hidden, flat_hidden);
+    }
+
+    Ok(())
+}
+
+pub fn subcommand_def() -> App<'static, 'static> {
+    SubCommand::with_name(SUBCOMMAND)
+        .about(
+            "Output the hidden height along an azimuth for the globe and a flat Earth model \
+            (columns: distance, globe, flat)",
+        )
+        .setting(AppSettings::AllowLeadingHyphen)
+        .arg(
+            Arg::with_name("input")
+                .help("Path to the input file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("azim")
+                .short("a")
+                .long("azim")
+                .value_name("DEGREES")
+                .help("Azimuth along which the profile will be generated")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("step")
+                .short("s")
+                .long("step")
+                .value_name("METERS")
+                .help(
+                    "The interval between points in the output \
+                    (default: 50.0)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cutoff_dist")
+                .short("c")
+                .long("cutoff-dist")
+                .value_name("METERS")
+                .help(
+                    "The length of the profile path (max distance from the observer) \
+                    (default: 10000)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flat_model")
+                .short("f")
+                .long("flat-model")
+                .value_name("MODEL")
+                .help(
+                    "The flat Earth model to compare against, in the config file syntax \
+                    (default: the model from the input file if it is flat, FlatDistorted \
+                    otherwise)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("plot")
+                .short("p")
+                .long("plot")
+                .value_name("FILE")
+                .help("Additionally draw the profiles into a PNG or SVG file")
+                .takes_value(true),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use atm_raytracer::generator::params::Altitude;
+
+    use super::*;
+
+    #[test]
+    fn test_profile_flat_terrain() {
+        let terrain = Terrain::new();
+        let mut config = Config::default();
+        config.view.position.altitude = Altitude::Absolute(10.0);
+        let params = config.into_params(&terrain).unwrap();
+
+        let profile = hidden_height_profile(&params, &terrain, 0.0, 1000.0, 30000.0);
+        assert_eq!(profile.len(), 30);
+        // nothing is hidden before the horizon, and more and more after it
+        assert_eq!(profile[0].1, 0.0);
+        assert!(profile.last().unwrap().1 > 0.0);
+        for points in profile.windows(2) {
+            assert!(points[1].1.is_finite());
+            assert!(points[1].1 >= points[0].1, "{:?}", points);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Simulation of panoramas as seen through the atmosphere, with the light rays bent by refraction.
+//!
+//! The main steps of generating an image are:
+//!
+//! 1. Loading the terrain with [`terrain::Terrain::from_folder`] (or [`terrain::Terrain::new`]
+//!    for no terrain at all).
+//! 2. Building the [`generator::params::Params`] - usually by creating a
+//!    [`generator::params::Config`], either programmatically or by deserializing it from YAML, and
+//!    calling [`generator::params::Config::into_params`].
+//! 3. Calculating the [`generator::ResultPixel`]s - the directions of the pixels and the points
+//!    hit by their rays - with [`generator::generate_pixels`], or tile by tile with
+//!    [`generator::create_generator`] and [`generator::generate_tile`].
+//! 4. Rendering the pixels into an image with [`renderer::render_image`], and encoding it with
+//!    [`renderer::encode_image`].
+//!
+//! [`generator::generate`] does all of that the way the `gen` subcommand of the `atm-raytracer`
+//! binary does, writing the image, the metadata and the data layers to the files set in the params.
+//!
+//! ```
+//! use std::time::SystemTime;
+//!
+//! use atm_raytracer::{generator, generator::params::Config, renderer, terrain::Terrain};
+//!
+//! let terrain = Terrain::new();
+//! let mut config = Config::default();
+//! config.output.width = 16;
+//! config.output.height = 12;
+//! let params = config.into_params(&terrain).unwrap();
+//!
+//! let pixels = generator::generate_pixels(&params, &terrain, SystemTime::now()).unwrap();
+//! assert_eq!(pixels.len(), 12);
+//!
+//! let image = renderer::render_image(&pixels, &params);
+//! let image = renderer::encode_image(&image, params.output.dither);
+//! assert_eq!(image.dimensions(), (16, 12));
+//! ```
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod coloring;
+pub mod generator;
+pub mod object;
+pub mod renderer;
+pub mod terrain;
+pub mod utils;
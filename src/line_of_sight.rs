@@ -0,0 +1,469 @@
+use std::env;
+
+use atm_raytracer::{
+    generator::{
+        gen_path_cache, gen_terrain_cache,
+        params::{Altitude, Params, Position},
+        PathElem, TerrainData,
+    },
+    terrain::Terrain,
+};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+pub const SUBCOMMAND: &str = "line-of-sight";
+
+const ANGLE_EPSILON: f64 = 1e-7;
+/// How close to the target altitude (in meters) the ray found by `find_ray_elev` has to get.
+const ALT_TOLERANCE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LineOfSight {
+    pub distance: f64,
+    pub azimuth: f64,
+    pub apparent_elevation: f64,
+    pub visible: bool,
+    pub clearance: f64,
+    pub clearance_distance: f64,
+    pub hidden_height: f64,
+}
+
+/// Returns the altitude of the path at the given distance, or `None` if the path ended before
+/// reaching it.
+pub fn height_at_dist(path: &[PathElem], dist: f64) -> Option<f64> {
+    path.windows(2)
+        .find(|elems| elems[0].dist <= dist && elems[1].dist >= dist)
+        .map(|elems| {
+            let prop = (dist - elems[0].dist) / (elems[1].dist - elems[0].dist);
+            elems[0].elev + (elems[1].elev - elems[0].elev) * prop
+        })
+}
+
+/// Returns the smallest height of the path above the terrain before the given distance, along
+/// with the distance at which it occurs.
+pub fn clearance(path: &[PathElem], terrain_cache: &[TerrainData], until: f64) -> (f64, f64) {
+    path.iter()
+        .zip(terrain_cache)
+        .skip(1)
+        .take_while(|(elem, _)| elem.dist < until)
+        .map(|(elem, terrain)| (elem.elev - terrain.elev, elem.dist))
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+        .unwrap_or((f64::INFINITY, 0.0))
+}
+
+/// Finds the elevation angle (in degrees) of the ray leaving the observer that reaches the given
+/// altitude at the given distance, or `None` if no ray between -89 and 89 degrees reaches it.
+pub fn find_ray_elev(params: &Params, terrain: &Terrain, dist: f64, alt: f64) -> Option<f64> {
+    let (mut min_ang, mut max_ang) = (-89.0, 89.0);
+
+    while max_ang - min_ang > ANGLE_EPSILON {
+        let cur_ang = 0.5 * (min_ang + max_ang);
+        let path = gen_path_cache(params, terrain, cur_ang);
+        let too_high = match height_at_dist(&path, dist) {
+            Some(h) => h > alt,
+            // the path ended before reaching the distance, either under the ground or above the
+            // target
+            None => path.last().is_some_and(|elem| elem.elev > alt),
+        };
+        if too_high {
+            max_ang = cur_ang;
+        } else {
+            min_ang = cur_ang;
+        }
+    }
+
+    let ang = 0.5 * (min_ang + max_ang);
+    match height_at_dist(&gen_path_cache(params, terrain, ang), dist) {
+        Some(h) if (h - alt).abs() < ALT_TOLERANCE => Some(ang),
+        _ => None,
+    }
+}
+
+/// Finds the lowest elevation angle (in degrees) within `angle_range` of a ray that reaches the
+/// given distance without being obstructed by the terrain.
+///
+/// The angle is found by bisection, which assumes that the rays above a visible one are visible
+/// too. This holds unless the refraction makes the rays cross (like in a superior mirage), in
+/// which case the result is an angle at which the rays turn from hidden to visible, but not
+/// necessarily the lowest one.
+pub fn lowest_visible_ray(
+    params: &Params,
+    terrain: &Terrain,
+    terrain_cache: &[TerrainData],
+    dist: f64,
+    angle_range: (f64, f64),
+) -> Option<f64> {
+    let (min_ang, max_ang) = angle_range;
+    let is_visible = |ang: f64| {
+        let path = gen_path_cache(params, terrain, ang);
+        height_at_dist(&path, dist).is_some()
+            && clearance(&path, terrain_cache, dist - params.simulation_step).0 >= 0.0
+    };
+
+    if is_visible(min_ang) {
+        return Some(min_ang);
+    }
+
+    let (mut min_ang, mut max_ang) = (min_ang, max_ang);
+    if !is_visible(max_ang) {
+        return None;
+    }
+
+    while max_ang - min_ang > ANGLE_EPSILON {
+        let cur_ang = 0.5 * (min_ang + max_ang);
+        if is_visible(cur_ang) {
+            max_ang = cur_ang;
+        } else {
+            min_ang = cur_ang;
+        }
+    }
+
+    Some(max_ang)
+}
+
+/// Finds the lowest altitude at the given distance that is visible from the observer, ie. not
+/// hidden behind the terrain.
+pub fn lowest_visible_alt(
+    params: &Params,
+    terrain: &Terrain,
+    terrain_cache: &[TerrainData],
+    dist: f64,
+) -> Option<f64> {
+    lowest_visible_ray(params, terrain, terrain_cache, dist, (-89.0, 89.0))
+        .and_then(|ang| height_at_dist(&gen_path_cache(params, terrain, ang), dist))
+}
+
+pub fn line_of_sight(
+    params: &Params,
+    terrain: &Terrain,
+    target: &Position,
+) -> Result<LineOfSight, String> {
+    let (azimuth, distance) = params
+        .model
+        .direction_to(
+            (
+                params.view.position.latitude,
+                params.view.position.longitude,
+            ),
+            (target.latitude, target.longitude),
+        )
+        .ok_or("couldn't find the direction to the target")?;
+    if distance == 0.0 {
+        return Err("the target is at the position of the observer".to_owned());
+    }
+
+    let mut params = params.clone();
+    params.view.frame.max_distance = distance + 2.0 * params.simulation_step;
+
+    let target_alt = target.abs_altitude(terrain);
+    let ground_alt = terrain
+        .get_elev(target.latitude, target.longitude)
+        .unwrap_or(0.0);
+
+    let apparent_elevation = find_ray_elev(&params, terrain, distance, target_alt)
+        .ok_or("no ray from the observer reaches the target")?;
+    let terrain_cache = gen_terrain_cache(&params, terrain, azimuth);
+    let path = gen_path_cache(&params, terrain, apparent_elevation);
+    let (clearance, clearance_distance) =
+        clearance(&path, &terrain_cache, distance - params.simulation_step);
+
+    let hidden_height = lowest_visible_alt(&params, terrain, &terrain_cache, distance)
+        .map_or(f64::INFINITY, |alt| (alt - ground_alt).max(0.0));
+
+    Ok(LineOfSight {
+        distance,
+        azimuth,
+        apparent_elevation,
+        visible: clearance >= 0.0,
+        clearance,
+        clearance_distance,
+        hidden_height,
+    })
+}
+
+fn parse_position(
+    matches: &ArgMatches<'_>,
+    prefix: &str,
+    default: Option<Position>,
+) -> Result<Position, String> {
+    let arg = |name: &str| -> Result<Option<f64>, String> {
+        matches
+            .value_of(format!("{}-{}", prefix, name))
+            .map(|val| {
+                val.parse()
+                    .map_err(|_| format!("invalid value of --{}-{}: {}", prefix, name, val))
+            })
+            .transpose()
+    };
+
+    let mut position = match default {
+        Some(position) => position,
+        None => Position {
+            latitude: arg("lat")?.ok_or(format!("please provide --{}-lat", prefix))?,
+            longitude: arg("lon")?.ok_or(format!("please provide --{}-lon", prefix))?,
+            altitude: Altitude::Relative(0.0),
+        },
+    };
+
+    if let Some(lat) = arg("lat")? {
+        position.latitude = lat;
+    }
+    if let Some(lon) = arg("lon")? {
+        position.longitude = lon;
+    }
+    match (arg("alt")?, arg("elev")?) {
+        (Some(alt), None) => position.altitude = Altitude::Absolute(alt),
+        (None, Some(elev)) => position.altitude = Altitude::Relative(elev),
+        _ => (),
+    }
+
+    Ok(position)
+}
+
+pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
+    let filename = matches
+        .value_of("input")
+        .expect("please provide an input file");
+
+    let mut config = atm_raytracer::generator::params::parse_config(filename);
+
+    // the observer has to be set before converting the config, as the position of the sun and the
+    // lighting depend on it
+    config.view.position = parse_position(matches, "obs", Some(*config.position()))?;
+    let target = parse_position(matches, "tgt", None)?;
+
+    let mut terrain_folder = env::current_dir().unwrap();
+    terrain_folder.push(config.terrain_folder());
+
+    let terrain = Terrain::from_folder(terrain_folder);
+
+    let params = config.into_params(&terrain)?;
+
+    let result = line_of_sight(&params, &terrain, &target)?;
+
+    if matches.is_present("json") {
+        let json = serde_json::to_string_pretty(&result).map_err(|err| err.to_string())?;
+        println!("{}", json);
+    } else {
+        println!("Distance: {:.1} m", result.distance);
+        println!("Azimuth: {:.4}°", result.azimuth);
+        println!(
+            "Apparent elevation angle: {:.4}°",
+            result.apparent_elevation
+        );
+        println!("Visible: {}", if result.visible { "yes" } else { "no" });
+        println!(
+            "Clearance: {:.1} m (at {:.1} m)",
+            result.clearance, result.clearance_distance
+        );
+        println!("Hidden height: {:.1} m", result.hidden_height);
+    }
+
+    Ok(())
+}
+
+pub fn subcommand_def() -> App<'static, 'static> {
+    SubCommand::with_name(SUBCOMMAND)
+        .about("Check whether the target point is visible from the observer")
+        .setting(AppSettings::AllowLeadingHyphen)
+        .arg(
+            Arg::with_name("input")
+                .help("Path to the input file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("obs-lat")
+                .long("obs-lat")
+                .value_name("DEG")
+                .help("Observer latitude in degrees (default: taken from the input file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("obs-lon")
+                .long("obs-lon")
+                .value_name("DEG")
+                .help("Observer longitude in degrees (default: taken from the input file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("obs-alt")
+                .long("obs-alt")
+                .value_name("ALT")
+                .conflicts_with("obs-elev")
+                .help("Observer altitude in meters (default: taken from the input file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("obs-elev")
+                .long("obs-elev")
+                .value_name("ELEV")
+                .conflicts_with("obs-alt")
+                .help(
+                    "Observer elevation in meters (above the terrain) (default: taken from the \
+                    input file)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tgt-lat")
+                .long("tgt-lat")
+                .value_name("DEG")
+                .help("Target latitude in degrees")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tgt-lon")
+                .long("tgt-lon")
+                .value_name("DEG")
+                .help("Target longitude in degrees")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tgt-alt")
+                .long("tgt-alt")
+                .value_name("ALT")
+                .conflicts_with("tgt-elev")
+                .help("Target altitude in meters")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tgt-elev")
+                .long("tgt-elev")
+                .value_name("ELEV")
+                .conflicts_with("tgt-alt")
+                .help("Target elevation in meters (above the terrain)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Output the results as JSON")
+                .takes_value(false),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use atm_raytracer::{generator::params::Config, terrain::FnTile, utils::EarthModel};
+
+    /// The distance of the target from the observer; on the flat Earth, a degree of latitude is
+    /// 10000/90 km long.
+    const TARGET_DIST: f64 = 0.2 * 10_000_000.0 / 90.0;
+
+    /// A flat Earth with straight rays, so that the results follow from simple geometry. The
+    /// terrain is at sea level, with a ridge 300 m high between the latitudes 50.2 and 50.21 if
+    /// `ridge` is set. The observer is 2 m above the ground at 50.1° N, 20.5° E.
+    fn setup(ridge: bool) -> (Params, Terrain) {
+        let mut terrain = Terrain::new();
+        terrain.add_tile(FnTile {
+            lat: 50.0,
+            lon: 20.0,
+            elev: move |lat: f64, _| {
+                if ridge && (50.2..50.21).contains(&lat) {
+                    300.0
+                } else {
+                    0.0
+                }
+            },
+        });
+        let mut config = Config {
+            earth_shape: EarthModel::AzimuthalEquidistant,
+            straight_rays: true,
+            ..Default::default()
+        };
+        config.view.position = Position {
+            latitude: 50.1,
+            longitude: 20.5,
+            altitude: Altitude::Relative(2.0),
+        };
+        let params = config.into_params(&terrain).unwrap();
+        (params, terrain)
+    }
+
+    /// A target north of the observer at the given elevation above the ground.
+    fn target(elev: f64) -> Position {
+        Position {
+            latitude: 50.3,
+            longitude: 20.5,
+            altitude: Altitude::Relative(elev),
+        }
+    }
+
+    #[test]
+    fn test_visible_target() {
+        let (params, terrain) = setup(false);
+        let result = line_of_sight(&params, &terrain, &target(100.0)).unwrap();
+
+        assert!((result.distance - TARGET_DIST).abs() < 1.0, "{:?}", result);
+        assert!(result.azimuth.abs() < 1e-6, "{:?}", result);
+        let expected_elev = (98.0 / TARGET_DIST).atan().to_degrees();
+        assert!(
+            (result.apparent_elevation - expected_elev).abs() < 1e-3,
+            "{:?}",
+            result
+        );
+        assert!(result.visible);
+        // the ray is the closest to the ground at the first step
+        assert!(
+            result.clearance > 2.0 && result.clearance < 2.5,
+            "{:?}",
+            result
+        );
+        // nothing is hidden on a flat Earth without obstacles
+        assert!(result.hidden_height < 1.0, "{:?}", result);
+    }
+
+    #[test]
+    fn test_target_behind_ridge() {
+        let (params, terrain) = setup(true);
+        let ridge_dist = 0.1 * 10_000_000.0 / 90.0;
+        let result = line_of_sight(&params, &terrain, &target(100.0)).unwrap();
+
+        assert!(!result.visible);
+        // the ray is the deepest in the ridge at its near edge
+        assert!(
+            result.clearance_distance >= ridge_dist
+                && result.clearance_distance < ridge_dist + params.simulation_step,
+            "{:?}",
+            result
+        );
+        let ray_height = 2.0 + 98.0 * result.clearance_distance / TARGET_DIST;
+        assert!(
+            (result.clearance - (ray_height - 300.0)).abs() < 1.0,
+            "{:?}",
+            result
+        );
+        // the lowest visible ray grazes the near edge of the top of the ridge
+        let hidden_height = 2.0 + 298.0 * TARGET_DIST / result.clearance_distance;
+        assert!(
+            (result.hidden_height - hidden_height).abs() < 5.0,
+            "{:?}",
+            result
+        );
+
+        let above = target(result.hidden_height + 10.0);
+        assert!(line_of_sight(&params, &terrain, &above).unwrap().visible);
+    }
+
+    #[test]
+    fn test_unreachable_target() {
+        let (params, terrain) = setup(false);
+        // the rays end when they go 1000 m below sea level
+        assert_eq!(find_ray_elev(&params, &terrain, TARGET_DIST, -5000.0), None);
+        let below = Position {
+            altitude: Altitude::Absolute(-5000.0),
+            ..target(0.0)
+        };
+        assert!(line_of_sight(&params, &terrain, &below).is_err());
+
+        // no ray reaches beyond the maximum distance
+        let terrain_cache = gen_terrain_cache(&params, &terrain, 0.0);
+        let beyond = params.view.frame.max_distance + 1000.0;
+        assert_eq!(
+            lowest_visible_ray(&params, &terrain, &terrain_cache, beyond, (-89.0, 89.0)),
+            None
+        );
+    }
+}
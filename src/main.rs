@@ -1,12 +1,13 @@
+mod atm_fit;
 mod atm_printer;
-mod coloring;
 mod elev_profile;
-mod generator;
-mod object;
+mod gen;
+mod hidden_height;
+mod line_of_sight;
+mod plot;
 mod ray_path;
-mod renderer;
-mod terrain;
-mod utils;
+mod rerender;
+#[cfg(feature = "viewer")]
 mod viewer;
 
 #[macro_use]
@@ -15,21 +16,32 @@ extern crate serde_derive;
 use clap::{crate_version, App};
 
 fn main() {
-    let matches = App::new("Atmospheric Panorama Raytracer")
+    let app = App::new("Atmospheric Panorama Raytracer")
         .version(crate_version!())
-        .subcommand(generator::subcommand_def())
-        .subcommand(viewer::subcommand_def())
+        .subcommand(gen::subcommand_def());
+    #[cfg(feature = "viewer")]
+    let app = app.subcommand(viewer::subcommand_def());
+    let matches = app
+        .subcommand(rerender::subcommand_def())
         .subcommand(atm_printer::subcommand_def())
         .subcommand(ray_path::subcommand_def())
         .subcommand(elev_profile::subcommand_def())
+        .subcommand(line_of_sight::subcommand_def())
+        .subcommand(hidden_height::subcommand_def())
+        .subcommand(atm_fit::subcommand_def())
         .get_matches();
 
     let result = match matches.subcommand() {
-        (generator::SUBCOMMAND, Some(matches)) => generator::generate(matches),
+        (gen::SUBCOMMAND, Some(matches)) => gen::run(matches),
+        #[cfg(feature = "viewer")]
         (viewer::SUBCOMMAND, Some(matches)) => viewer::run(matches),
+        (rerender::SUBCOMMAND, Some(matches)) => rerender::run(matches),
         (atm_printer::SUBCOMMAND, Some(matches)) => atm_printer::run(matches),
         (ray_path::SUBCOMMAND, Some(matches)) => ray_path::run(matches),
         (elev_profile::SUBCOMMAND, Some(matches)) => elev_profile::run(matches),
+        (line_of_sight::SUBCOMMAND, Some(matches)) => line_of_sight::run(matches),
+        (hidden_height::SUBCOMMAND, Some(matches)) => hidden_height::run(matches),
+        (atm_fit::SUBCOMMAND, Some(matches)) => atm_fit::run(matches),
         _ => panic!("Unknown subcommand!"),
     };
 
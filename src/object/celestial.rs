@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    generator::params::Position,
+    utils::{angular_distance, moon_position, sun_position, DateTime},
+};
+
+/// The angular radius of the sun, in degrees.
+const SUN_RADIUS: f64 = 0.2666;
+const DEFAULT_RADIUS: f64 = 0.25;
+
+const SUN_COLOR: [u8; 3] = [255, 250, 235];
+const MOON_COLOR: [u8; 3] = [235, 235, 225];
+const DEFAULT_COLOR: [u8; 3] = [255, 255, 255];
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum CelestialPosition {
+    /// The sun at the date and time of the view.
+    Sun,
+    /// The Moon at the date and time of the view.
+    Moon,
+    /// A fixed direction, given by the azimuth and the elevation (without the refraction) in
+    /// degrees.
+    Fixed { azimuth: f64, elevation: f64 },
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct ConfCelestialObject {
+    pub position: CelestialPosition,
+    /// The angular radius in degrees; by default, the actual size of the sun or the Moon.
+    pub angular_radius: Option<f64>,
+    pub color: Option<[u8; 3]>,
+}
+
+/// A body outside of the atmosphere, drawn as a disk in the sky.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CelestialObject {
+    pub azimuth: f64,
+    /// The elevation angle without the refraction, in degrees.
+    pub elevation: f64,
+    pub angular_radius: f64,
+    pub color: [u8; 3],
+}
+
+impl ConfCelestialObject {
+    /// The sun with its actual size and color.
+    pub fn sun() -> Self {
+        Self {
+            position: CelestialPosition::Sun,
+            angular_radius: None,
+            color: None,
+        }
+    }
+
+    pub fn into_celestial_object(
+        self,
+        datetime: Option<DateTime>,
+        position: &Position,
+    ) -> Result<CelestialObject, String> {
+        let datetime = || {
+            datetime.ok_or_else(|| {
+                "the position of the sun and the Moon requires `datetime` in the view".to_owned()
+            })
+        };
+        let (azimuth, elevation, radius, color) = match self.position {
+            CelestialPosition::Sun => {
+                let (azimuth, elevation) =
+                    sun_position(&datetime()?, position.latitude, position.longitude);
+                (azimuth, elevation, SUN_RADIUS, SUN_COLOR)
+            }
+            CelestialPosition::Moon => {
+                let (azimuth, elevation, radius) =
+                    moon_position(&datetime()?, position.latitude, position.longitude);
+                (azimuth, elevation, radius, MOON_COLOR)
+            }
+            CelestialPosition::Fixed { azimuth, elevation } => {
+                (azimuth, elevation, DEFAULT_RADIUS, DEFAULT_COLOR)
+            }
+        };
+        Ok(CelestialObject {
+            azimuth,
+            elevation,
+            angular_radius: self.angular_radius.unwrap_or(radius),
+            color: self.color.unwrap_or(color),
+        })
+    }
+}
+
+impl CelestialObject {
+    /// Checks whether a ray leaving the atmosphere in the given direction (in degrees) hits the
+    /// object.
+    pub fn is_hit(&self, azimuth: f64, elevation: f64) -> bool {
+        angular_distance(azimuth, elevation, self.azimuth, self.elevation) < self.angular_radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::params::Altitude;
+
+    #[test]
+    fn test_is_hit() {
+        let datetime = "2024-06-20T12:00Z".parse().ok();
+        let position = Position {
+            latitude: 50.0,
+            longitude: 20.0,
+            altitude: Altitude::Relative(1.0),
+        };
+        let positions = [
+            CelestialPosition::Sun,
+            CelestialPosition::Moon,
+            CelestialPosition::Fixed {
+                azimuth: 120.0,
+                elevation: 5.0,
+            },
+        ];
+        for position_conf in positions {
+            let object = ConfCelestialObject {
+                position: position_conf,
+                angular_radius: None,
+                color: None,
+            }
+            .into_celestial_object(datetime, &position)
+            .unwrap();
+            assert!(object.is_hit(object.azimuth, object.elevation));
+            let margin = object.angular_radius * 1.01;
+            assert!(object.is_hit(object.azimuth, object.elevation + margin * 0.98));
+            assert!(!object.is_hit(object.azimuth, object.elevation + margin));
+            assert!(!object.is_hit(object.azimuth, object.elevation - margin));
+        }
+
+        let sun = ConfCelestialObject::sun();
+        assert!(sun.into_celestial_object(None, &position).is_err());
+    }
+}
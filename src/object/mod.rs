@@ -1,4 +1,5 @@
 mod billboard;
+mod celestial;
 mod frustum;
 
 use std::env;
@@ -16,6 +17,8 @@ use serde::{Deserialize, Serialize};
 use billboard::Billboard;
 use frustum::Frustum;
 
+pub use celestial::{CelestialObject, CelestialPosition, ConfCelestialObject};
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum ConfShape {
     Cylinder {
@@ -0,0 +1,414 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use atm_raytracer::renderer::FONT;
+use atm_refraction::EarthShape;
+use image::{ImageBuffer, Rgb};
+use imageproc::drawing::{draw_line_segment_mut, draw_text_mut};
+use rusttype::{Font, Scale};
+
+const MARGIN_LEFT: f64 = 80.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 20.0;
+const MARGIN_BOTTOM: f64 = 60.0;
+
+const NUM_TICKS: f64 = 8.0;
+const FONT_SIZE: f32 = 15.0;
+
+const GRID_COLOR: [u8; 3] = [220, 220, 220];
+const AXIS_COLOR: [u8; 3] = [0, 0, 0];
+
+/// Converts a point given as (distance along the surface, altitude) into the coordinates of a
+/// vertical plane tangent to the surface at the observer, so that the Earth's curvature drop is
+/// visible on the plot.
+pub fn with_curvature_drop(shape: &EarthShape, dist: f64, h: f64) -> (f64, f64) {
+    match *shape {
+        EarthShape::Flat => (dist, h),
+        EarthShape::Spherical { radius } => {
+            let ang = dist / radius;
+            ((radius + h) * ang.sin(), (radius + h) * ang.cos() - radius)
+        }
+    }
+}
+
+/// Escapes the characters that have a special meaning in XML.
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct Series {
+    label: Option<String>,
+    points: Vec<(f64, f64)>,
+    color: [u8; 3],
+}
+
+pub struct Plot {
+    width: u32,
+    height: u32,
+    x_label: String,
+    y_label: String,
+    series: Vec<Series>,
+}
+
+struct Axis {
+    min: f64,
+    max: f64,
+    step: f64,
+}
+
+impl Axis {
+    fn new(min: f64, max: f64) -> Self {
+        let (min, max) = if max <= min {
+            (min - 1.0, max + 1.0)
+        } else {
+            (min, max)
+        };
+        let raw_step = (max - min) / NUM_TICKS;
+        let magnitude = 10.0_f64.powf(raw_step.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .iter()
+            .map(|mul| mul * magnitude)
+            .find(|step| *step >= raw_step)
+            .unwrap();
+        Self {
+            min: (min / step).floor() * step,
+            max: (max / step).ceil() * step,
+            step,
+        }
+    }
+
+    fn ticks(&self) -> Vec<f64> {
+        let n = ((self.max - self.min) / self.step).round() as usize;
+        (0..=n).map(|i| self.min + i as f64 * self.step).collect()
+    }
+
+    fn label(&self, value: f64) -> String {
+        let decimals = (-self.step.log10().floor()).max(0.0) as usize;
+        format!("{:.1$}", value, decimals)
+    }
+}
+
+impl Plot {
+    pub fn new(x_label: &str, y_label: &str) -> Self {
+        Self {
+            width: 1200,
+            height: 800,
+            x_label: x_label.to_owned(),
+            y_label: y_label.to_owned(),
+            series: vec![],
+        }
+    }
+
+    pub fn add_series(&mut self, points: Vec<(f64, f64)>, color: [u8; 3]) {
+        self.series.push(Series {
+            label: None,
+            points,
+            color,
+        });
+    }
+
+    pub fn add_labelled_series(&mut self, label: &str, points: Vec<(f64, f64)>, color: [u8; 3]) {
+        self.series.push(Series {
+            label: Some(label.to_owned()),
+            points,
+            color,
+        });
+    }
+
+    fn legend(&self) -> impl Iterator<Item = (usize, &str, [u8; 3])> {
+        self.series
+            .iter()
+            .filter_map(|series| series.label.as_deref().map(|label| (label, series.color)))
+            .enumerate()
+            .map(|(index, (label, color))| (index, label, color))
+    }
+
+    fn axes(&self) -> (Axis, Axis) {
+        let points = || self.series.iter().flat_map(|series| series.points.iter());
+        let min_x = points().map(|p| p.0).fold(f64::INFINITY, f64::min);
+        let max_x = points().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max);
+        let min_y = points().map(|p| p.1).fold(f64::INFINITY, f64::min);
+        let max_y = points().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+        (Axis::new(min_x, max_x), Axis::new(min_y, max_y))
+    }
+
+    fn to_canvas(&self, x_axis: &Axis, y_axis: &Axis, point: (f64, f64)) -> (f32, f32) {
+        let plot_w = self.width as f64 - MARGIN_LEFT - MARGIN_RIGHT;
+        let plot_h = self.height as f64 - MARGIN_TOP - MARGIN_BOTTOM;
+        let x = MARGIN_LEFT + (point.0 - x_axis.min) / (x_axis.max - x_axis.min) * plot_w;
+        let y = MARGIN_TOP + (y_axis.max - point.1) / (y_axis.max - y_axis.min) * plot_h;
+        (x as f32, y as f32)
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        if self.series.iter().all(|series| series.points.is_empty()) {
+            return Err("nothing to plot".to_owned());
+        }
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match extension.as_deref() {
+            Some("svg") => fs::write(filename, self.to_svg()).map_err(|err| err.to_string()),
+            Some("png") => self.to_png().save(filename).map_err(|err| err.to_string()),
+            _ => Err(format!(
+                "unsupported plot format of {:?} (expected .png or .svg)",
+                filename
+            )),
+        }
+    }
+
+    fn to_png(&self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut img = ImageBuffer::from_pixel(self.width, self.height, Rgb([255u8, 255, 255]));
+        let font = Font::try_from_bytes(FONT).unwrap();
+        let scale = Scale::uniform(FONT_SIZE);
+        let (x_axis, y_axis) = self.axes();
+        let (left, top) = self.to_canvas(&x_axis, &y_axis, (x_axis.min, y_axis.max));
+        let (right, bottom) = self.to_canvas(&x_axis, &y_axis, (x_axis.max, y_axis.min));
+
+        for x in x_axis.ticks() {
+            let (cx, _) = self.to_canvas(&x_axis, &y_axis, (x, y_axis.min));
+            draw_line_segment_mut(&mut img, (cx, top), (cx, bottom), Rgb(GRID_COLOR));
+            let label = x_axis.label(x);
+            let cx = cx as i32 - label.len() as i32 * 4;
+            draw_text_mut(
+                &mut img,
+                Rgb(AXIS_COLOR),
+                cx,
+                bottom as i32 + 5,
+                scale,
+                &font,
+                &label,
+            );
+        }
+        for y in y_axis.ticks() {
+            let (_, cy) = self.to_canvas(&x_axis, &y_axis, (x_axis.min, y));
+            draw_line_segment_mut(&mut img, (left, cy), (right, cy), Rgb(GRID_COLOR));
+            let label = y_axis.label(y);
+            let cx = left as i32 - 8 - label.len() as i32 * 8;
+            draw_text_mut(
+                &mut img,
+                Rgb(AXIS_COLOR),
+                cx,
+                cy as i32 - 7,
+                scale,
+                &font,
+                &label,
+            );
+        }
+        draw_line_segment_mut(&mut img, (left, bottom), (right, bottom), Rgb(AXIS_COLOR));
+        draw_line_segment_mut(&mut img, (left, top), (left, bottom), Rgb(AXIS_COLOR));
+        draw_text_mut(
+            &mut img,
+            Rgb(AXIS_COLOR),
+            (left + right) as i32 / 2 - self.x_label.len() as i32 * 4,
+            bottom as i32 + 30,
+            scale,
+            &font,
+            &self.x_label,
+        );
+        draw_text_mut(
+            &mut img,
+            Rgb(AXIS_COLOR),
+            5,
+            top as i32 - 15,
+            scale,
+            &font,
+            &self.y_label,
+        );
+
+        for series in &self.series {
+            for segment in series.points.windows(2) {
+                let start = self.to_canvas(&x_axis, &y_axis, segment[0]);
+                let end = self.to_canvas(&x_axis, &y_axis, segment[1]);
+                draw_line_segment_mut(&mut img, start, end, Rgb(series.color));
+            }
+        }
+
+        for (index, label, color) in self.legend() {
+            let y = top + 15.0 + index as f32 * 20.0;
+            draw_line_segment_mut(&mut img, (right - 180.0, y), (right - 150.0, y), Rgb(color));
+            draw_text_mut(
+                &mut img,
+                Rgb(AXIS_COLOR),
+                right as i32 - 140,
+                y as i32 - 8,
+                scale,
+                &font,
+                label,
+            );
+        }
+
+        img
+    }
+
+    fn to_svg(&self) -> String {
+        let (x_axis, y_axis) = self.axes();
+        let (left, top) = self.to_canvas(&x_axis, &y_axis, (x_axis.min, y_axis.max));
+        let (right, bottom) = self.to_canvas(&x_axis, &y_axis, (x_axis.max, y_axis.min));
+        let color = |c: [u8; 3]| format!("rgb({},{},{})", c[0], c[1], c[2]);
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" \
+            viewBox=\"0 0 {0} {1}\" font-family=\"DejaVu Sans, sans-serif\" font-size=\"{2}\">",
+            self.width, self.height, FONT_SIZE
+        );
+        let _ = writeln!(svg, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>");
+
+        for x in x_axis.ticks() {
+            let (cx, _) = self.to_canvas(&x_axis, &y_axis, (x, y_axis.min));
+            let _ = writeln!(
+                svg,
+                "<line x1=\"{0}\" y1=\"{1}\" x2=\"{0}\" y2=\"{2}\" stroke=\"{3}\"/>\
+                <text x=\"{0}\" y=\"{4}\" text-anchor=\"middle\">{5}</text>",
+                cx,
+                top,
+                bottom,
+                color(GRID_COLOR),
+                bottom + 20.0,
+                x_axis.label(x)
+            );
+        }
+        for y in y_axis.ticks() {
+            let (_, cy) = self.to_canvas(&x_axis, &y_axis, (x_axis.min, y));
+            let _ = writeln!(
+                svg,
+                "<line x1=\"{0}\" y1=\"{1}\" x2=\"{2}\" y2=\"{1}\" stroke=\"{3}\"/>\
+                <text x=\"{4}\" y=\"{5}\" text-anchor=\"end\">{6}</text>",
+                left,
+                cy,
+                right,
+                color(GRID_COLOR),
+                left - 8.0,
+                cy + 5.0,
+                y_axis.label(y)
+            );
+        }
+        let _ = writeln!(
+            svg,
+            "<polyline points=\"{},{} {},{} {},{}\" fill=\"none\" stroke=\"{}\"/>",
+            left,
+            top,
+            left,
+            bottom,
+            right,
+            bottom,
+            color(AXIS_COLOR)
+        );
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+            (left + right) / 2.0,
+            bottom + 45.0,
+            escape_xml(&self.x_label)
+        );
+        let _ = writeln!(
+            svg,
+            "<text x=\"5\" y=\"{}\">{}</text>",
+            top - 3.0,
+            escape_xml(&self.y_label)
+        );
+
+        for series in &self.series {
+            let points: Vec<_> = series
+                .points
+                .iter()
+                .map(|point| {
+                    let (x, y) = self.to_canvas(&x_axis, &y_axis, *point);
+                    format!("{:.2},{:.2}", x, y)
+                })
+                .collect();
+            let _ = writeln!(
+                svg,
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\"/>",
+                points.join(" "),
+                color(series.color)
+            );
+        }
+
+        for (index, label, series_color) in self.legend() {
+            let y = top + 15.0 + index as f32 * 20.0;
+            let _ = writeln!(
+                svg,
+                "<line x1=\"{0}\" y1=\"{1}\" x2=\"{2}\" y2=\"{1}\" stroke=\"{3}\"/>\
+                <text x=\"{4}\" y=\"{5}\">{6}</text>",
+                right - 180.0,
+                y,
+                right - 150.0,
+                color(series_color),
+                right - 140.0,
+                y + 5.0,
+                escape_xml(label)
+            );
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len(), "{:?} {:?}", a, b);
+        assert!(
+            a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9),
+            "{:?} {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_axis() {
+        let axis = Axis::new(0.0, 97.0);
+        assert_close(&axis.ticks(), &[0.0, 20.0, 40.0, 60.0, 80.0, 100.0]);
+        assert_eq!(axis.label(40.0), "40");
+
+        let axis = Axis::new(-0.013, 0.021);
+        assert_eq!(axis.step, 0.005);
+        assert_close(
+            &axis.ticks(),
+            &[-0.015, -0.01, -0.005, 0.0, 0.005, 0.01, 0.015, 0.02, 0.025],
+        );
+        assert_eq!(axis.label(0.01), "0.010");
+
+        // a single value still gets a range around it
+        let axis = Axis::new(5.0, 5.0);
+        assert!(axis.min < 5.0 && axis.max > 5.0);
+    }
+
+    #[test]
+    fn test_svg() {
+        let mut plot = Plot::new("Distance [m]", "Height <h> [m]");
+        plot.add_series(vec![(0.0, 0.0), (10.0, 5.0)], [1, 2, 3]);
+        plot.add_labelled_series(
+            "a & b",
+            vec![(0.0, 1.0), (5.0, 2.0), (10.0, 3.0)],
+            [4, 5, 6],
+        );
+        let svg = plot.to_svg();
+
+        // the axes and the two series
+        assert_eq!(svg.matches("<polyline").count(), 3);
+        assert_eq!(svg.matches("stroke=\"rgb(1,2,3)\"").count(), 1);
+        // the series and its legend
+        assert_eq!(svg.matches("stroke=\"rgb(4,5,6)\"").count(), 2);
+        let series = svg
+            .lines()
+            .find(|line| line.contains("rgb(4,5,6)") && line.starts_with("<polyline"))
+            .unwrap();
+        let points = series.split('"').nth(1).unwrap();
+        assert_eq!(points.split(' ').count(), 3);
+
+        assert!(svg.contains(">Height &lt;h&gt; [m]<"));
+        assert!(svg.contains(">a &amp; b<"));
+        assert!(!svg.contains("a & b"));
+    }
+}
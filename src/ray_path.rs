@@ -1,6 +1,18 @@
-use atm_refraction::{air::Atmosphere, Environment};
+use std::env;
+
+use atm_raytracer::{generator::ray_stepper, terrain::Terrain};
+use atm_refraction::{air::Atmosphere, EarthShape, Environment};
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
+use crate::{
+    elev_profile::elev_profile,
+    plot::{with_curvature_drop, Plot},
+};
+
+const RAY_COLOR: [u8; 3] = [0, 96, 192];
+const TERRAIN_COLOR: [u8; 3] = [120, 80, 40];
+const SEA_LEVEL_COLOR: [u8; 3] = [0, 160, 255];
+
 pub const SUBCOMMAND: &str = "output-ray-paths";
 
 pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
@@ -50,9 +62,13 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
         .parse()
         .expect("please provide a valid output step");
 
+    let azim: Option<f64> = matches
+        .value_of("azim")
+        .map(|azim| azim.parse().expect("please provide a valid azimuth"));
+
     assert!(step > 0.0, "step must be positive");
 
-    let config = crate::generator::params::parse_config(filename);
+    let config = atm_raytracer::generator::params::parse_config(filename);
 
     let atmosphere = Atmosphere::from_def(config.atmosphere.clone());
 
@@ -68,8 +84,14 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
 
     while ang <= max_ang {
         eprintln!("Elevation angle {} (min={}, max={})", ang, min_ang, max_ang);
-        let mut stepper = env.cast_ray_stepper(height, ang.to_radians(), false);
-        stepper.set_step_size(ray_step);
+        let mut stepper = ray_stepper(
+            &env,
+            config.integrator,
+            false,
+            height,
+            ang.to_radians(),
+            ray_step,
+        );
 
         let mut ray = vec![height];
 
@@ -94,6 +116,26 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
         ang += step;
     }
 
+    if let Some(plot_file) = matches.value_of("plot") {
+        let terrain_profile = azim.map(|azim| {
+            let mut terrain_folder = env::current_dir().unwrap();
+            terrain_folder.push(config.terrain_folder());
+            let terrain = Terrain::from_folder(terrain_folder);
+            let dist_calc = config.earth_shape.coords_at_dist_calc(
+                (config.position().latitude, config.position().longitude),
+                azim,
+            );
+            elev_profile(&terrain, &*dist_calc, output_step, cutoff)
+        });
+        return plot_rays(
+            plot_file,
+            &env.shape,
+            &xs,
+            &rays,
+            terrain_profile.as_deref(),
+        );
+    }
+
     for i in 0..xs.len() {
         print!("{}\t", xs[i]);
         for ray in &rays {
@@ -105,6 +147,43 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
     Ok(())
 }
 
+fn plot_rays(
+    filename: &str,
+    shape: &EarthShape,
+    xs: &[f64],
+    rays: &[Vec<f64>],
+    terrain_profile: Option<&[(f64, f64)]>,
+) -> Result<(), String> {
+    let mut plot = Plot::new("Distance [m]", "Height [m]");
+
+    if let EarthShape::Spherical { .. } = shape {
+        let sea_level = xs
+            .iter()
+            .map(|x| with_curvature_drop(shape, *x, 0.0))
+            .collect();
+        plot.add_series(sea_level, SEA_LEVEL_COLOR);
+    }
+
+    if let Some(profile) = terrain_profile {
+        let terrain = profile
+            .iter()
+            .map(|(x, h)| with_curvature_drop(shape, *x, *h))
+            .collect();
+        plot.add_series(terrain, TERRAIN_COLOR);
+    }
+
+    for ray in rays {
+        let points = xs
+            .iter()
+            .zip(ray)
+            .map(|(x, h)| with_curvature_drop(shape, *x, *h))
+            .collect();
+        plot.add_series(points, RAY_COLOR);
+    }
+
+    plot.save(filename)
+}
+
 pub fn subcommand_def() -> App<'static, 'static> {
     SubCommand::with_name(SUBCOMMAND)
         .about("Output ray paths")
@@ -191,4 +270,118 @@ pub fn subcommand_def() -> App<'static, 'static> {
                 )
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("plot")
+                .short("p")
+                .long("plot")
+                .value_name("FILE")
+                .help(
+                    "Draw the ray paths into a PNG or SVG file (chosen by the extension) instead \
+                    of printing them",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("azim")
+                .short("z")
+                .long("azim")
+                .value_name("DEGREES")
+                .help(
+                    "Overlay the terrain profile along the given azimuth from the observer \
+                    position in the config file on the plot",
+                )
+                .takes_value(true)
+                .requires("plot"),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use atm_raytracer::{terrain::FnTile, utils::EarthModel};
+
+    #[test]
+    fn test_plot_rays() {
+        let shape = EarthShape::Spherical {
+            radius: 6_371_000.0,
+        };
+        let xs = [0.0, 5000.0, 10000.0];
+        let rays = [vec![2.0, 10.0, 30.0], vec![2.0, 40.0, 100.0]];
+        let terrain = [(0.0, 0.0), (5000.0, 20.0), (10000.0, 5.0)];
+        let mut path = env::temp_dir();
+        path.push(format!(
+            "atm-raytracer-test-rays-{}.svg",
+            std::process::id()
+        ));
+        let filename = path.to_str().unwrap();
+
+        plot_rays(filename, &shape, &xs, &rays, Some(&terrain)).unwrap();
+        let svg = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let stroke = |color: [u8; 3]| {
+            let stroke = format!("stroke=\"rgb({},{},{})\"", color[0], color[1], color[2]);
+            svg.lines()
+                .filter(|line| line.starts_with("<polyline") && line.contains(&stroke))
+                .count()
+        };
+        assert_eq!(stroke(SEA_LEVEL_COLOR), 1);
+        assert_eq!(stroke(TERRAIN_COLOR), 1);
+        assert_eq!(stroke(RAY_COLOR), 2);
+    }
+
+    /// The PNG plot should have the terrain profile along the azimuth drawn below the rays.
+    #[test]
+    fn test_plot_rays_png() {
+        let mut terrain = Terrain::new();
+        // rising by about 90 m over 10 km to the north of the observer
+        terrain.add_tile(FnTile {
+            lat: 49.0,
+            lon: 20.0,
+            elev: |lat: f64, _lon: f64| (lat - 49.5).max(0.0) * 1000.0,
+        });
+        let model = EarthModel::Spherical {
+            radius: 6_371_000.0,
+        };
+        let dist_calc = model.coords_at_dist_calc((49.5, 20.5), 0.0);
+        let profile = elev_profile(&terrain, &*dist_calc, 500.0, 10000.0);
+        assert_eq!(profile.len(), 21);
+        assert!((profile[20].1 - 90.0).abs() < 1.0, "{:?}", profile[20]);
+
+        let xs = [0.0, 5000.0, 10000.0];
+        let rays = [vec![2.0, 150.0, 300.0], vec![2.0, 300.0, 600.0]];
+        let mut path = env::temp_dir();
+        path.push(format!(
+            "atm-raytracer-test-rays-{}.png",
+            std::process::id()
+        ));
+        let filename = path.to_str().unwrap();
+
+        plot_rays(filename, &model.to_shape(), &xs, &rays, Some(&profile)).unwrap();
+        let img = image::open(&path).unwrap().to_rgb8();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(img.dimensions(), (1200, 800));
+
+        let rows = |x: u32, color: [u8; 3]| -> Vec<u32> {
+            (0..img.height())
+                .filter(|&y| img.get_pixel(x, y).0 == color)
+                .collect()
+        };
+        let mut columns = 0;
+        for x in 0..img.width() {
+            let (terrain_rows, ray_rows) = (rows(x, TERRAIN_COLOR), rows(x, RAY_COLOR));
+            if terrain_rows.is_empty() || ray_rows.is_empty() {
+                continue;
+            }
+            columns += 1;
+            assert!(
+                ray_rows.iter().max() < terrain_rows.iter().min(),
+                "column {}",
+                x
+            );
+        }
+        assert!(columns > 500, "{}", columns);
+    }
 }
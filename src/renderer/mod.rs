@@ -1,23 +1,42 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
     env,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
 };
 
 use crate::{
+    coloring::{Legend, Sky},
     generator::{
-        params::{Params, Tick, TickLike, VerticalTick},
-        ResultPixel,
+        params::{
+            Extinction, OutputFormat, Params, Tick, TickLike, VerticalTick, DEFAULT_AIRLIGHT,
+        },
+        PixelDirection, ResultPixel, TracePoint,
     },
     terrain::Terrain,
-    utils::{rgb_to_vec3, vec3_to_rgb},
+    utils::{decode_srgb, encode_srgb, linear_to_srgb, srgb_to_linear},
 };
 
 use atm_refraction::EarthShape;
-use image::{ImageBuffer, Pixel, Rgb};
-use imageproc::drawing::{draw_line_segment_mut, draw_text_mut};
+use image::{DynamicImage, ImageBuffer, ImageError, ImageFormat, ImageResult, Rgb};
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_line_segment_mut, draw_text_mut, text_size},
+    rect::Rect,
+};
+use nalgebra::Vector3;
 use rusttype::{Font, Scale};
 
-static FONT: &[u8] = include_bytes!("DejaVuSans.ttf");
+pub static FONT: &[u8] = include_bytes!("DejaVuSans.ttf");
+
+/// An image with the colors in linear light.
+pub type LinearImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// The distance of the legend from the corner of the image and of its contents from its edges.
+const LEGEND_MARGIN: i32 = 8;
+const LEGEND_BAR_WIDTH: u32 = 200;
+const LEGEND_BAR_HEIGHT: u32 = 12;
+const LEGEND_TEXT_HEIGHT: i32 = 15;
 
 struct DrawTick {
     size: u32,
@@ -36,7 +55,7 @@ fn diff_azimuth(az1: f64, az2: f64) -> f64 {
     }
 }
 
-fn azimuth_to_x(azimuth: f64, pixels: &[Vec<ResultPixel>]) -> Option<u32> {
+fn azimuth_to_x(azimuth: f64, pixels: &[Vec<PixelDirection>]) -> Option<u32> {
     let candidate = pixels[0]
         .iter()
         .enumerate()
@@ -58,7 +77,7 @@ fn azimuth_to_x(azimuth: f64, pixels: &[Vec<ResultPixel>]) -> Option<u32> {
         .then_some(candidate)
 }
 
-fn elevation_to_y(elevation: f64, pixels: &[Vec<ResultPixel>]) -> Option<u32> {
+fn elevation_to_y(elevation: f64, pixels: &[Vec<PixelDirection>]) -> Option<u32> {
     let candidate = pixels
         .iter()
         .map(|pixels_row| &pixels_row[0])
@@ -82,7 +101,7 @@ fn elevation_to_y(elevation: f64, pixels: &[Vec<ResultPixel>]) -> Option<u32> {
 fn into_draw_ticks(
     tick: &Tick,
     params: &Params,
-    pixels: &[Vec<ResultPixel>],
+    pixels: &[Vec<PixelDirection>],
     decimals: usize,
 ) -> Vec<(u32, DrawTick)> {
     match *tick {
@@ -142,7 +161,7 @@ fn into_draw_ticks(
 fn into_draw_ticks_vertical(
     tick: &VerticalTick,
     params: &Params,
-    pixels: &[Vec<ResultPixel>],
+    pixels: &[Vec<PixelDirection>],
     decimals: usize,
 ) -> Vec<(u32, DrawTick)> {
     match *tick {
@@ -224,7 +243,7 @@ fn round_decimals<T: TickLike>(ticks: &[T]) -> usize {
         .unwrap_or(0)
 }
 
-fn gen_ticks(params: &Params, pixels: &[Vec<ResultPixel>]) -> TicksToDraw {
+fn gen_ticks(params: &Params, pixels: &[Vec<PixelDirection>]) -> TicksToDraw {
     let mut horizontal = HashMap::new();
     let mut vertical = HashMap::new();
 
@@ -267,11 +286,7 @@ fn gen_ticks(params: &Params, pixels: &[Vec<ResultPixel>]) -> TicksToDraw {
     }
 }
 
-fn draw_ticks(
-    img: &mut ImageBuffer<Rgb<u8>, Vec<<Rgb<u8> as Pixel>::Subpixel>>,
-    params: &Params,
-    pixels: &[Vec<ResultPixel>],
-) {
+fn draw_ticks(img: &mut LinearImage, params: &Params, pixels: &[Vec<PixelDirection>]) {
     let font = Font::try_from_bytes(FONT).unwrap();
     let height = 15.0;
     let scale = Scale {
@@ -287,12 +302,12 @@ fn draw_ticks(
             img,
             (x as f32, 0.0),
             (x as f32, tick.size as f32),
-            Rgb([255, 255, 255]),
+            linear_rgb([255, 255, 255]),
         );
         if tick.labelled {
             draw_text_mut(
                 img,
-                Rgb([255, 255, 255]),
+                linear_rgb([255, 255, 255]),
                 x as i32 - 8,
                 tick.size as i32 + 5,
                 scale,
@@ -306,12 +321,12 @@ fn draw_ticks(
             img,
             (0.0, y as f32),
             (tick.size as f32, y as f32),
-            Rgb([255, 255, 255]),
+            linear_rgb([255, 255, 255]),
         );
         if tick.labelled {
             draw_text_mut(
                 img,
-                Rgb([255, 255, 255]),
+                linear_rgb([255, 255, 255]),
                 tick.size as i32 + 5,
                 y as i32 - 7,
                 scale,
@@ -322,7 +337,110 @@ fn draw_ticks(
     }
 }
 
-fn find_elev(pixels: &[Vec<ResultPixel>], column: u32, elev: f64) -> Option<u32> {
+/// Draws the legend in the bottom right corner of the image.
+fn draw_legend(img: &mut LinearImage, legend: &Legend) {
+    let font = Font::try_from_bytes(FONT).unwrap();
+    let scale = Scale {
+        x: LEGEND_TEXT_HEIGHT as f32,
+        y: LEGEND_TEXT_HEIGHT as f32,
+    };
+    let white = linear_rgb([255, 255, 255]);
+    let line_height = LEGEND_TEXT_HEIGHT + LEGEND_MARGIN / 2;
+    let (img_width, img_height) = (img.width() as i32, img.height() as i32);
+
+    match legend {
+        Legend::Scale {
+            title,
+            min,
+            max,
+            colors,
+        } => {
+            let width = LEGEND_BAR_WIDTH as i32 + 2 * LEGEND_MARGIN;
+            let height = 2 * line_height + LEGEND_BAR_HEIGHT as i32 + 2 * LEGEND_MARGIN;
+            let (x0, y0) = (
+                img_width - width - LEGEND_MARGIN,
+                img_height - height - LEGEND_MARGIN,
+            );
+            draw_filled_rect_mut(
+                img,
+                Rect::at(x0, y0).of_size(width as u32, height as u32),
+                linear_rgb([0, 0, 0]),
+            );
+            let (x, mut y) = (x0 + LEGEND_MARGIN, y0 + LEGEND_MARGIN);
+            draw_text_mut(img, white, x, y, scale, &font, title);
+            y += line_height;
+            for i in 0..LEGEND_BAR_WIDTH {
+                let color = colors[i as usize * colors.len() / LEGEND_BAR_WIDTH as usize];
+                let color = linear_rgb(color.0);
+                draw_line_segment_mut(
+                    img,
+                    ((x + i as i32) as f32, y as f32),
+                    (
+                        (x + i as i32) as f32,
+                        (y + LEGEND_BAR_HEIGHT as i32 - 1) as f32,
+                    ),
+                    color,
+                );
+            }
+            y += LEGEND_BAR_HEIGHT as i32 + LEGEND_MARGIN / 2;
+            let decimals = num_decimals(*min).max(num_decimals(*max)).min(3);
+            let min_label = format!("{:.1$}", min, decimals);
+            let max_label = format!("{:.1$}", max, decimals);
+            let (max_width, _) = text_size(scale, &font, &max_label);
+            draw_text_mut(img, white, x, y, scale, &font, &min_label);
+            draw_text_mut(
+                img,
+                white,
+                x + LEGEND_BAR_WIDTH as i32 - max_width,
+                y,
+                scale,
+                &font,
+                &max_label,
+            );
+        }
+        Legend::Swatches(swatches) => {
+            let swatch_size = LEGEND_TEXT_HEIGHT;
+            let text_width = swatches
+                .iter()
+                .map(|(_, label)| text_size(scale, &font, label).0)
+                .max()
+                .unwrap_or(0);
+            let width = swatch_size + LEGEND_MARGIN + text_width + 2 * LEGEND_MARGIN;
+            let height = swatches.len() as i32 * line_height + 2 * LEGEND_MARGIN;
+            let (x0, y0) = (
+                img_width - width - LEGEND_MARGIN,
+                img_height - height - LEGEND_MARGIN,
+            );
+            draw_filled_rect_mut(
+                img,
+                Rect::at(x0, y0).of_size(width as u32, height as u32),
+                linear_rgb([0, 0, 0]),
+            );
+            for (i, (color, label)) in swatches.iter().enumerate() {
+                let (x, y) = (
+                    x0 + LEGEND_MARGIN,
+                    y0 + LEGEND_MARGIN + i as i32 * line_height,
+                );
+                draw_filled_rect_mut(
+                    img,
+                    Rect::at(x, y).of_size(swatch_size as u32, swatch_size as u32),
+                    linear_rgb(color.0),
+                );
+                draw_text_mut(
+                    img,
+                    white,
+                    x + swatch_size + LEGEND_MARGIN,
+                    y,
+                    scale,
+                    &font,
+                    label,
+                );
+            }
+        }
+    }
+}
+
+fn find_elev(pixels: &[Vec<PixelDirection>], column: u32, elev: f64) -> Option<u32> {
     let mut closest_elev = f64::INFINITY;
     let mut closest_elev_idx = 0;
     for (y, row) in pixels.iter().enumerate() {
@@ -343,9 +461,9 @@ fn find_elev(pixels: &[Vec<ResultPixel>], column: u32, elev: f64) -> Option<u32>
 }
 
 fn draw_const_elev(
-    img: &mut ImageBuffer<Rgb<u8>, Vec<<Rgb<u8> as Pixel>::Subpixel>>,
+    img: &mut LinearImage,
     params: &Params,
-    pixels: &[Vec<ResultPixel>],
+    pixels: &[Vec<PixelDirection>],
     elev: f64,
     color: [u8; 3],
 ) {
@@ -357,65 +475,176 @@ fn draw_const_elev(
                 img,
                 ((x - 1) as f32, y_old as f32),
                 (x as f32, y_new as f32),
-                Rgb(color),
+                linear_rgb(color),
             );
         }
         maybe_y_old = maybe_y_new;
     }
 }
 
-fn fog(fog_dist: f64, pixel_dist: f64, color: Rgb<u8>) -> Rgb<u8> {
+fn fog(
+    fog_dist: f64,
+    pixel_dist: f64,
+    fog_color: Vector3<f64>,
+    color: Vector3<f64>,
+) -> Vector3<f64> {
     let fog_coeff = 1.0 - (-pixel_dist / fog_dist).exp();
-    let fog_color = Rgb([160u8, 160, 160]);
-    let mut new_color = Rgb([0u8; 3]);
-    for i in 0..3 {
-        new_color.0[i] =
-            (color.0[i] as f64 * (1.0 - fog_coeff) + fog_color.0[i] as f64 * fog_coeff) as u8;
+    color * (1.0 - fog_coeff) + fog_color * fog_coeff
+}
+
+/// Wavelengths (in meters) representative of the red, green and blue channels.
+const CHANNEL_WAVELENGTHS: [f64; 3] = [680e-9, 550e-9, 440e-9];
+/// The Rayleigh scattering coefficient of sea level air in standard conditions for 550 nm light,
+/// in 1/m.
+const RAYLEIGH_550: f64 = 1.35e-5;
+
+/// Returns the color of the light scattered into the line of sight of the pixel, in linear light.
+fn airlight(extinction: &Extinction, sky: Option<&Sky>, pixel: &ResultPixel) -> Vector3<f64> {
+    match (extinction.airlight, sky) {
+        // the airlight is given as an sRGB color
+        (Some(airlight), _) => Vector3::from(airlight).map(srgb_to_linear),
+        (None, Some(sky)) => sky.color(pixel.azimuth, pixel.elevation_angle),
+        (None, None) => Vector3::from(DEFAULT_AIRLIGHT).map(srgb_to_linear),
+    }
+}
+
+fn extinction(
+    extinction: &Extinction,
+    pixel: &TracePoint,
+    color: Vector3<f64>,
+    airlight: Vector3<f64>,
+) -> Vector3<f64> {
+    let mut new_color = Vector3::zeros();
+    for (i, wavelength) in CHANNEL_WAVELENGTHS.iter().enumerate() {
+        let rel_wavelength = 550e-9 / wavelength;
+        let rayleigh = RAYLEIGH_550 * rel_wavelength.powi(4);
+        let mie = extinction.aerosol_extinction * rel_wavelength.powf(extinction.angstrom_exponent);
+        let optical_depth = rayleigh * pixel.air_column + mie * pixel.aerosol_column;
+        let transmittance = (-optical_depth).exp();
+        new_color[i] = color[i] * transmittance + airlight[i] * (1.0 - transmittance);
     }
     new_color
 }
 
-fn add(rgb1: Rgb<u8>, rgb2: Rgb<u8>, a: f64) -> Rgb<u8> {
-    let color1 = rgb_to_vec3(rgb1);
-    let color2 = rgb_to_vec3(rgb2);
-    let result = color1 + color2 * a;
-    vec3_to_rgb(result)
+/// Converts an 8-bit sRGB color for drawing on a linear image.
+fn linear_rgb(rgb: [u8; 3]) -> Rgb<f32> {
+    let color = decode_srgb(rgb);
+    Rgb([color[0] as f32, color[1] as f32, color[2] as f32])
 }
 
-pub fn draw_image(pixels: &[Vec<ResultPixel>], params: &Params) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let mut img = ImageBuffer::new(params.output.width as u32, params.output.height as u32);
-    let coloring = params.view.coloring.coloring_method();
-    let def_color = if params.view.fog_distance.is_some() {
-        coloring.fog_color()
-        //Rgb([160, 160, 160])
-    } else {
-        coloring.sky_color()
-        //Rgb([28, 28, 28])
-    };
-    for (x, y, px) in img.enumerate_pixels_mut() {
-        let mut result = Rgb([0, 0, 0]);
-        let mut accum_neg_alpha = 1.0;
-
-        for pixel in &pixels[y as usize][x as usize].trace_points {
-            let color1 = coloring.color_for_pixel(pixel);
-            let color2 = if let Some(fog_dist) = params.view.fog_distance {
-                fog(fog_dist, pixel.path_length, color1)
+/// The thresholds of 4x4 ordered dithering.
+const BAYER_MATRIX: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Renders the rows of pixels into the image with the colors in linear light, starting at the row
+/// `first_row`.
+pub fn render_rows(
+    img: &mut LinearImage,
+    first_row: u32,
+    pixels: &[Vec<ResultPixel>],
+    params: &Params,
+) {
+    let coloring = params
+        .view
+        .coloring
+        .coloring_method(params.view.sky, &params.model);
+    let fog_color = coloring.fog_color();
+    for (y, row) in (first_row..).zip(pixels) {
+        for (x, result_pixel) in (0..).zip(row) {
+            let def_color = if params.view.fog_distance.is_some() {
+                fog_color
             } else {
-                color1
+                coloring.sky_color_at(result_pixel.azimuth, result_pixel.elevation_angle)
             };
-            result = add(result, color2, accum_neg_alpha * pixel.color.alpha());
-            accum_neg_alpha *= 1.0 - pixel.color.alpha();
-        }
+            // the objects listed later are drawn in front of the earlier ones
+            let celestial_object = result_pixel.exit_elevation.and_then(|exit_elevation| {
+                params
+                    .scene
+                    .celestial_objects
+                    .iter()
+                    .rev()
+                    .find(|object| object.is_hit(result_pixel.azimuth, exit_elevation))
+            });
+            let def_color = celestial_object.map_or(def_color, |object| decode_srgb(object.color));
+            let airlight = params
+                .view
+                .extinction
+                .as_ref()
+                .map(|ext| airlight(ext, params.view.sky.as_ref(), result_pixel));
+            let mut result = Vector3::zeros();
+            let mut accum_neg_alpha = 1.0;
 
-        *px = add(result, def_color, accum_neg_alpha);
+            for pixel in &result_pixel.trace_points {
+                let mut color = coloring.color_for_pixel(pixel);
+                if let Some(fog_dist) = params.view.fog_distance {
+                    color = fog(fog_dist, pixel.path_length, fog_color, color);
+                }
+                if let (Some(ext), Some(airlight)) = (&params.view.extinction, airlight) {
+                    color = extinction(ext, pixel, color, airlight);
+                }
+                result += color * accum_neg_alpha * pixel.color.alpha();
+                accum_neg_alpha *= 1.0 - pixel.color.alpha();
+            }
+
+            let color = result + def_color * accum_neg_alpha;
+            img.put_pixel(
+                x,
+                y,
+                Rgb([color[0] as f32, color[1] as f32, color[2] as f32]),
+            );
+        }
     }
+}
 
+/// Renders the pixels of the whole image, without the overlays drawn by `output_image`.
+pub fn render_image(pixels: &[Vec<ResultPixel>], params: &Params) -> LinearImage {
+    let mut img = LinearImage::new(params.output.width as u32, params.output.height as u32);
+    render_rows(&mut img, 0, pixels, params);
     img
 }
 
-pub fn output_image(pixels: &[Vec<ResultPixel>], params: &Params, terrain: &Terrain) {
-    let mut img = draw_image(pixels, params);
+/// Encodes an image in linear light as 8-bit sRGB.
+pub fn encode_image(img: &LinearImage, dither: bool) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let Rgb([r, g, b]) = *img.get_pixel(x, y);
+        let dither = if dither {
+            (BAYER_MATRIX[y as usize % 4][x as usize % 4] as f64 + 0.5) / 16.0 - 0.5
+        } else {
+            0.0
+        };
+        encode_srgb(Vector3::new(r as f64, g as f64, b as f64), dither)
+    })
+}
+
+/// Encodes an image in linear light as 16-bit sRGB.
+fn encode_image_16(img: &LinearImage) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let encode = |c: f32| (linear_to_srgb(c as f64) * 65535.0).round() as u16;
+        let Rgb([r, g, b]) = *img.get_pixel(x, y);
+        Rgb([encode(r), encode(g), encode(b)])
+    })
+}
 
+/// Saves an image in linear light as a color Portable Float Map.
+fn save_pfm(img: &LinearImage, path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    // the negative scale denotes little endian values
+    write!(file, "PF\n{} {}\n-1.0\n", img.width(), img.height())?;
+    // the rows are stored from the bottom to the top
+    for row in img.rows().rev() {
+        for value in row.flat_map(|pixel| pixel.0) {
+            file.write_all(&value.to_le_bytes())?;
+        }
+    }
+    file.flush()
+}
+
+/// Draws the overlays on the rendered image and saves it.
+pub fn output_image(
+    mut img: LinearImage,
+    pixels: &[Vec<PixelDirection>],
+    params: &Params,
+    terrain: &Terrain,
+) -> Result<(), String> {
     draw_ticks(&mut img, params, pixels);
     if params.output.show_flat_horizon
         && matches!(params.env.shape, EarthShape::Flat)
@@ -429,16 +658,193 @@ pub fn output_image(pixels: &[Vec<ResultPixel>], params: &Params, terrain: &Terr
     if params.output.show_eye_level {
         draw_const_elev(&mut img, params, pixels, 0.0, [255, 128, 255]);
     }
+    if let Some(legend) = params
+        .view
+        .coloring
+        .coloring_method(params.view.sky, &params.model)
+        .legend()
+    {
+        draw_legend(&mut img, &legend);
+    }
 
-    let mut output_file = env::current_dir().unwrap();
+    let mut output_file =
+        env::current_dir().map_err(|err| format!("invalid working directory: {}", err))?;
     output_file.push(&params.output.file);
 
-    img.save(output_file).unwrap();
+    save_image(
+        img,
+        params.output.format(),
+        params.output.dither,
+        &output_file,
+    )
+    .map_err(|err| {
+        format!(
+            "failed to save the output image {}: {}",
+            params.output.file, err
+        )
+    })
+}
+
+/// Saves an image in linear light in the given format.
+fn save_image(
+    img: LinearImage,
+    format: OutputFormat,
+    dither: bool,
+    path: &Path,
+) -> ImageResult<()> {
+    match format {
+        OutputFormat::Srgb8 => encode_image(&img, dither).save(path),
+        OutputFormat::Png16 => encode_image_16(&img).save_with_format(path, ImageFormat::Png),
+        OutputFormat::Exr => {
+            DynamicImage::ImageRgb32F(img).save_with_format(path, ImageFormat::OpenExr)
+        }
+        OutputFormat::Pfm => save_pfm(&img, path).map_err(ImageError::IoError),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::num_decimals;
+    use super::*;
+    use std::{convert::TryInto, fs, path::PathBuf};
+
+    /// A small image with values covering the range of an 8-bit sRGB channel and beyond.
+    fn test_image() -> LinearImage {
+        ImageBuffer::from_fn(7, 3, |x, y| {
+            let value = x as f32 / 6.0;
+            Rgb([value, 1.0 - value, value * (y + 1) as f32 / 3.0])
+        })
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("atm-raytracer-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_png16_round_trip() {
+        let img = test_image();
+        let path = temp_path("out.png");
+        save_image(img.clone(), OutputFormat::Png16, false, &path).unwrap();
+        let saved = image::open(&path).unwrap().into_rgb16();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.dimensions(), img.dimensions());
+        for (saved, original) in saved.pixels().zip(img.pixels()) {
+            for (saved, original) in saved.0.iter().zip(original.0.iter()) {
+                let decoded = srgb_to_linear(*saved as f64 / 65535.0);
+                assert!((decoded - *original as f64).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_exr_round_trip() {
+        let img = test_image();
+        let path = temp_path("out.exr");
+        save_image(img.clone(), OutputFormat::Exr, false, &path).unwrap();
+        let saved = image::open(&path).unwrap().into_rgb32f();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved, img);
+    }
+
+    #[test]
+    fn test_pfm_round_trip() {
+        let img = test_image();
+        let path = temp_path("out.pfm");
+        save_image(img.clone(), OutputFormat::Pfm, false, &path).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = b"PF\n7 3\n-1.0\n";
+        assert_eq!(&data[..header.len()], header);
+        let values: Vec<f32> = data[header.len()..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(values.len(), 7 * 3 * 3);
+        // the rows are stored from the bottom
+        for (y, row) in values.chunks(7 * 3).enumerate() {
+            for (x, pixel) in row.chunks(3).enumerate() {
+                assert_eq!(pixel, img.get_pixel(x as u32, 2 - y as u32).0);
+            }
+        }
+    }
+
+    fn test_extinction(aerosol_extinction: f64, angstrom_exponent: f64) -> Extinction {
+        Extinction {
+            aerosol_extinction,
+            angstrom_exponent,
+            airlight: None,
+        }
+    }
+
+    /// Returns the transmittance of each channel along the given columns.
+    fn transmittance(
+        extinction_def: &Extinction,
+        air_column: f64,
+        aerosol_column: f64,
+    ) -> Vector3<f64> {
+        let mut point = TracePoint::test_point(0.0, 0.0, 10e3, Vector3::z());
+        point.air_column = air_column;
+        point.aerosol_column = aerosol_column;
+        extinction(
+            extinction_def,
+            &point,
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::zeros(),
+        )
+    }
+
+    /// Without any air or aerosols in the way, the color is left exactly as it was.
+    #[test]
+    fn test_extinction_zero_columns() {
+        let point = TracePoint::test_point(0.0, 0.0, 10e3, Vector3::z());
+        let color = Vector3::new(0.2, 0.5, 0.7);
+        let airlight = Vector3::new(0.8, 0.8, 0.8);
+        assert_eq!(
+            extinction(&test_extinction(2e-5, 1.3), &point, color, airlight),
+            color
+        );
+        assert_eq!(
+            transmittance(&test_extinction(2e-5, 1.3), 0.0, 0.0),
+            Vector3::new(1.0, 1.0, 1.0)
+        );
+    }
+
+    /// The Rayleigh scattering goes as the inverse fourth power of the wavelength, so blue is
+    /// attenuated more than red.
+    #[test]
+    fn test_extinction_rayleigh() {
+        let air_column = 50e3;
+        let transmittance = transmittance(&test_extinction(0.0, 1.3), air_column, 0.0);
+        assert!(transmittance[2] < transmittance[1]);
+        assert!(transmittance[1] < transmittance[0]);
+        for (i, wavelength) in CHANNEL_WAVELENGTHS.iter().enumerate() {
+            let expected = (-RAYLEIGH_550 * (550e-9 / wavelength).powi(4) * air_column).exp();
+            assert!((transmittance[i] - expected).abs() < 1e-12);
+        }
+        // the green channel is at the reference wavelength
+        assert!((transmittance[1] - (-RAYLEIGH_550 * air_column).exp()).abs() < 1e-12);
+    }
+
+    /// The optical depth of the aerosols scales with the wavelength according to the configured
+    /// Angstrom exponent.
+    #[test]
+    fn test_extinction_mie() {
+        let aerosol_column = 20e3;
+        for angstrom_exponent in [0.0, 1.3, 2.0] {
+            let transmittance = transmittance(
+                &test_extinction(2e-5, angstrom_exponent),
+                0.0,
+                aerosol_column,
+            );
+            let depths = transmittance.map(|t| -t.ln());
+            assert!((depths[1] - 2e-5 * aerosol_column).abs() < 1e-12);
+            let expected_ratio =
+                (CHANNEL_WAVELENGTHS[0] / CHANNEL_WAVELENGTHS[2]).powf(angstrom_exponent);
+            assert!((depths[2] / depths[0] - expected_ratio).abs() < 1e-9);
+        }
+    }
 
     #[test]
     fn test_decimals() {
@@ -0,0 +1,269 @@
+use std::{
+    fs,
+    io::{Read, Seek},
+};
+
+use atm_raytracer::{
+    coloring::Sky,
+    generator::{
+        metadata::MetadataReader,
+        params::{Coloring, ConfColoring, Extinction, OutputFormat, Params, Tick, VerticalTick},
+        PixelDirection, ResultPixel,
+    },
+    renderer::{self, LinearImage},
+    terrain::Terrain,
+};
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+pub const SUBCOMMAND: &str = "rerender";
+
+/// The settings that can be changed when rendering the image from a metadata file again; the
+/// ones that aren't set are kept from the file.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overrides {
+    coloring: Option<ConfColoring>,
+    fog_distance: Option<f64>,
+    extinction: Option<Extinction>,
+    sky: Option<Sky>,
+    ticks: Option<Vec<Tick>>,
+    vertical_ticks: Option<Vec<VerticalTick>>,
+    show_eye_level: Option<bool>,
+    show_flat_horizon: Option<bool>,
+    dither: Option<bool>,
+    format: Option<OutputFormat>,
+}
+
+impl Overrides {
+    fn apply(self, params: &mut Params) -> Result<(), String> {
+        if let Some(coloring) = self.coloring {
+            let coloring = coloring.into_coloring(
+                &params.view.frame,
+                &params.view.position,
+                &params.model,
+                params.view.sun,
+            )?;
+            warn_missing_data(&params.view.coloring, &coloring);
+            params.view.coloring = coloring;
+        }
+        if let Some(fog_distance) = self.fog_distance {
+            params.view.fog_distance = Some(fog_distance);
+        }
+        if let Some(extinction) = self.extinction {
+            params.view.extinction = Some(extinction);
+        }
+        if let Some(sky) = self.sky {
+            params.view.sky = Some(match params.view.sun {
+                Some(sun) => sky.with_sun(sun.azimuth, sun.elevation),
+                None => sky,
+            });
+        }
+        if let Some(ticks) = self.ticks {
+            params.output.ticks = ticks;
+        }
+        if let Some(vertical_ticks) = self.vertical_ticks {
+            params.output.vertical_ticks = vertical_ticks;
+        }
+        if let Some(show_eye_level) = self.show_eye_level {
+            params.output.show_eye_level = show_eye_level;
+        }
+        if let Some(show_flat_horizon) = self.show_flat_horizon {
+            params.output.show_flat_horizon = show_flat_horizon;
+        }
+        if let Some(dither) = self.dither {
+            params.output.dither = dither;
+        }
+        if let Some(format) = self.format {
+            params.output.format = Some(format);
+        }
+        Ok(())
+    }
+}
+
+/// Warns about the effects of the new coloring that need data which was only calculated during
+/// the generation if the original coloring used them.
+fn warn_missing_data(old: &Coloring, new: &Coloring) {
+    let (old_shadows, old_light_dir, old_occlusion) = match old {
+        Coloring::Shading {
+            shadows,
+            light_dir,
+            ambient_occlusion,
+            ..
+        } => (*shadows, Some(*light_dir), *ambient_occlusion),
+        _ => (false, None, false),
+    };
+    if let Coloring::Shading {
+        shadows,
+        light_dir,
+        ambient_occlusion,
+        ..
+    } = new
+    {
+        if *shadows && !old_shadows {
+            println!("Warning: the file contains no shadows, the terrain will not be shadowed");
+        } else if *shadows && old_light_dir != Some(*light_dir) {
+            println!("Warning: the shadows in the file were cast for a different light direction");
+        }
+        if *ambient_occlusion && !old_occlusion {
+            println!("Warning: the file contains no ambient occlusion data, it will be ignored");
+        }
+    }
+}
+
+/// Renders the pixels stored in the metadata file with the given params, and returns the image
+/// (without the overlays) along with the directions of the pixels.
+fn render<R: Read + Seek>(
+    data: &mut MetadataReader<R>,
+    params: &Params,
+) -> Result<(LinearImage, Vec<Vec<PixelDirection>>), String> {
+    let mut img = LinearImage::new(params.output.width as u32, params.output.height as u32);
+    let mut directions: Vec<Vec<PixelDirection>> = vec![];
+    data.for_each_chunk(|first_row, rows| {
+        renderer::render_rows(&mut img, first_row as u32, rows, params);
+        directions.extend(
+            rows.iter()
+                .map(|row| row.iter().map(ResultPixel::direction).collect()),
+        );
+    })?;
+    Ok((img, directions))
+}
+
+pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
+    let filename = matches
+        .value_of("input")
+        .expect("please provide an input file");
+    let output = matches
+        .value_of("output")
+        .expect("please provide an output file");
+
+    let overrides: Overrides = if let Some(config) = matches.value_of("config") {
+        let contents = fs::read_to_string(config)
+            .map_err(|err| format!("couldn't read the config file {:?}: {}", config, err))?;
+        serde_yaml::from_str(&contents)
+            .map_err(|err| format!("failed parsing the config file {:?}: {}", config, err))?
+    } else {
+        Default::default()
+    };
+
+    let mut data = MetadataReader::open(filename)
+        .map_err(|err| format!("couldn't read the metadata file {:?}: {}", filename, err))?;
+    if !data.is_complete() {
+        // the overlays need the directions of at least two rows
+        if data.rows_done() < 2 {
+            return Err(format!(
+                "the metadata file {:?} doesn't contain enough rows yet",
+                filename
+            ));
+        }
+        println!(
+            "Warning: the file is incomplete, only {} of {} rows will be rendered",
+            data.rows_done(),
+            data.params().output.height
+        );
+    }
+    let mut params = data.params().clone();
+    overrides.apply(&mut params)?;
+    if let Some(fog_distance) = matches.value_of("fog-distance") {
+        params.view.fog_distance = Some(fog_distance.parse().expect("invalid fog distance"));
+    }
+    if matches.is_present("no-fog") {
+        params.view.fog_distance = None;
+    }
+    if matches.is_present("show-eye-level") {
+        params.output.show_eye_level = true;
+    }
+    params.output.file = output.to_owned();
+
+    let (img, directions) = render(&mut data, &params)?;
+
+    // the terrain is only needed for the altitude of the observer for the flat horizon
+    let terrain = if params.output.show_flat_horizon {
+        Terrain::from_folder(&params.scene.terrain_folder)
+    } else {
+        Terrain::new()
+    };
+    renderer::output_image(img, &directions, &params, &terrain)?;
+
+    Ok(())
+}
+
+pub fn subcommand_def() -> App<'static, 'static> {
+    SubCommand::with_name(SUBCOMMAND)
+        .about("Render the image from a metadata file again, without tracing the rays")
+        .arg(
+            Arg::with_name("input")
+                .help("Path to the metadata file")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("File name to save the output image as")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Path to a config file with the settings to change")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fog-distance")
+                .long("fog-distance")
+                .value_name("METERS")
+                .help("The characteristic distance of the fog")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-fog")
+                .long("no-fog")
+                .help("Disable the fog")
+                .conflicts_with("fog-distance"),
+        )
+        .arg(
+            Arg::with_name("show-eye-level")
+                .long("show-eye-level")
+                .help("Draw the eye level line"),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, time::SystemTime};
+
+    use atm_raytracer::generator::{self, metadata::MetadataWriter, params::Config};
+
+    #[test]
+    fn test_rerender_metadata() {
+        let terrain = Terrain::new();
+        let mut config = Config::default();
+        config.output.width = 16;
+        config.output.height = 12;
+        config.view.fog_distance = Some(20_000.0);
+        let params = config.into_params(&terrain).unwrap();
+        let pixels = generator::generate_pixels(&params, &terrain, SystemTime::now()).unwrap();
+
+        let mut writer = MetadataWriter::new(Cursor::new(vec![]), &params).unwrap();
+        for rows in pixels.chunks(5) {
+            writer.write_rows(rows).unwrap();
+        }
+        let bytes = writer.finish().unwrap().into_inner();
+        let mut data = MetadataReader::new(Cursor::new(bytes)).unwrap();
+        let params = data.params().clone();
+
+        let (img, directions) = render(&mut data, &params).unwrap();
+        assert_eq!(img, renderer::render_image(&pixels, &params));
+        assert_eq!(directions.len(), 12);
+        assert_eq!(
+            directions[7][3].elevation_angle,
+            pixels[7][3].elevation_angle
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    io::{Read, Seek},
+    path::Path,
+};
+
+use tiff::{decoder::Decoder, tags::Tag};
+
+/// The mapping between the pixels of an image in geographic coordinates and latitude/longitude.
+#[derive(Clone, Copy, Debug)]
+pub struct Georeference {
+    /// The longitude and latitude of the outer corner of the pixel (0, 0).
+    origin: (f64, f64),
+    /// The size of a pixel in degrees of longitude and latitude.
+    scale: (f64, f64),
+}
+
+impl Georeference {
+    /// Reads the georeference from the model tiepoint and pixel scale tags of a GeoTIFF.
+    pub fn from_geotiff<R: Read + Seek>(decoder: &mut Decoder<R>) -> Option<Self> {
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag).ok()?;
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag).ok()?;
+        if scale.len() < 2 || tiepoint.len() < 5 {
+            return None;
+        }
+        Some(Self {
+            origin: (
+                tiepoint[3] - tiepoint[0] * scale[0],
+                tiepoint[4] + tiepoint[1] * scale[1],
+            ),
+            scale: (scale[0], scale[1]),
+        })
+    }
+
+    /// Reads the georeference from a world file accompanying an image: `image.pgw` for
+    /// `image.png`, `image.pngw` or `image.wld`. Returns `None` if there is no world file; rotated
+    /// images aren't supported.
+    pub fn from_world_file(image_path: &Path) -> Result<Option<Self>, String> {
+        let ext = match image_path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext,
+            None => return Ok(None),
+        };
+        let short_ext = match (ext.chars().next(), ext.chars().next_back()) {
+            (Some(first), Some(last)) => format!("{}{}w", first, last),
+            _ => return Ok(None),
+        };
+        let world_file = [short_ext, format!("{}w", ext), "wld".to_owned()]
+            .iter()
+            .map(|ext| image_path.with_extension(ext))
+            .find_map(|path| Some((fs::read_to_string(&path).ok()?, path)));
+        let (contents, path) = match world_file {
+            Some(world_file) => world_file,
+            None => return Ok(None),
+        };
+        Self::parse_world_file(&contents)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    fn parse_world_file(contents: &str) -> Result<Self, String> {
+        let values = contents
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|err| format!("invalid world file: {}", err))?;
+        let [a, d, b, e, c, f] = match values[..] {
+            [a, d, b, e, c, f] => [a, d, b, e, c, f],
+            _ => return Err("a world file must contain 6 numbers".to_owned()),
+        };
+        if d != 0.0 || b != 0.0 {
+            return Err("rotated images are not supported".to_owned());
+        }
+        // the world file gives the center of the top left pixel
+        Ok(Self {
+            origin: (c - a / 2.0, f - e / 2.0),
+            scale: (a, -e),
+        })
+    }
+
+    /// Returns the (fractional) column and row of the image at the given coordinates; the pixel
+    /// (i, j) spans the range [i, i+1) x [j, j+1).
+    pub fn pixel_at(&self, lat: f64, lon: f64) -> (f64, f64) {
+        (
+            (lon - self.origin.0) / self.scale.0,
+            (self.origin.1 - lat) / self.scale.1,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tiff::encoder::{colortype::Gray8, TiffEncoder};
+
+    fn assert_pixel(georeference: &Georeference, (lat, lon): (f64, f64), (col, row): (f64, f64)) {
+        let pixel = georeference.pixel_at(lat, lon);
+        assert!(
+            (pixel.0 - col).abs() < 1e-6 && (pixel.1 - row).abs() < 1e-6,
+            "{:?} != {:?}",
+            pixel,
+            (col, row)
+        );
+    }
+
+    #[test]
+    fn test_world_file() {
+        let georeference =
+            Georeference::parse_world_file("0.001\n0\n0\n-0.002\n20.0005\n50.999\n").unwrap();
+        assert_pixel(&georeference, (51.0, 20.0), (0.0, 0.0));
+        assert_pixel(&georeference, (50.99, 20.0025), (2.5, 5.0));
+
+        assert!(Georeference::parse_world_file("0.001 0 0 -0.002 20.0005").is_err());
+        assert!(Georeference::parse_world_file("0.001 0 0 -0.002 20.0005 x").is_err());
+        // rotated
+        assert!(Georeference::parse_world_file("0.001 0.0001 0 -0.002 20.0005 50.999").is_err());
+    }
+
+    #[test]
+    fn test_geotiff() {
+        let mut data = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        let mut image = encoder.new_image::<Gray8>(4, 4).unwrap();
+        image
+            .encoder()
+            .write_tag(Tag::ModelPixelScaleTag, &[0.25f64, 0.5, 0.0][..])
+            .unwrap();
+        // the pixel (1, 2) is at 49N 18E
+        image
+            .encoder()
+            .write_tag(
+                Tag::ModelTiepointTag,
+                &[1.0f64, 2.0, 0.0, 18.0, 49.0, 0.0][..],
+            )
+            .unwrap();
+        image.write_data(&[0; 16]).unwrap();
+        data.set_position(0);
+
+        let georeference = Georeference::from_geotiff(&mut Decoder::new(data).unwrap()).unwrap();
+        assert_pixel(&georeference, (49.0, 18.0), (1.0, 2.0));
+        assert_pixel(&georeference, (50.0, 17.75), (0.0, 0.0));
+    }
+}
@@ -1,15 +1,19 @@
-use std::{path::Path, str::FromStr};
+use std::{fs::File, io::BufReader, path::Path, str::FromStr};
 
-use geotiff_rs::GeoTiff;
 use lazy_static::lazy_static;
 use regex::Regex;
+use tiff::decoder::{Decoder, DecodingResult};
 
 use super::Tile;
 
+/// The number of samples along each side of a tile: one per arc second, including both edges.
+const GRID_SIZE: usize = 3601;
+
 pub struct GeoTiffWrapper {
     min_lat: f64,
     min_lon: f64,
-    data: GeoTiff,
+    /// The elevations, row by row from the north.
+    data: Vec<f32>,
 }
 
 impl GeoTiffWrapper {
@@ -30,15 +34,37 @@ impl GeoTiffWrapper {
         Some((lat, lon))
     }
 
+    /// Reads a tile covering the square degree given by the name of the file, which has to be
+    /// sampled every arc second (`GRID_SIZE` x `GRID_SIZE` samples).
     pub fn from_path(name: &Path) -> Option<Self> {
         let (lat, lon) = Self::coords_from_name(name)?;
-        let data = GeoTiff::from_file(name).ok()?;
+        let mut decoder = Decoder::new(BufReader::new(File::open(name).ok()?)).ok()?;
+        let (width, height) = decoder.dimensions().ok()?;
+        if (width as usize, height as usize) != (GRID_SIZE, GRID_SIZE) {
+            return None;
+        }
+        let data: Vec<f32> = match decoder.read_image().ok()? {
+            DecodingResult::I16(data) => data.into_iter().map(f32::from).collect(),
+            // the elevations are signed even in the files that don't specify the sample format
+            DecodingResult::U16(data) => data.into_iter().map(|elev| elev as i16 as f32).collect(),
+            DecodingResult::I32(data) => data.into_iter().map(|elev| elev as f32).collect(),
+            DecodingResult::F32(data) => data,
+            _ => return None,
+        };
+        if data.len() != GRID_SIZE * GRID_SIZE {
+            return None;
+        }
         Some(Self {
             min_lat: lat as f64,
             min_lon: lon as f64,
             data,
         })
     }
+
+    /// Returns the elevation at the given numbers of arc seconds from the south-west corner.
+    fn get_pixel(&self, lon: usize, lat: usize) -> f64 {
+        self.data[(GRID_SIZE - 1 - lat) * GRID_SIZE + lon] as f64
+    }
 }
 
 impl Tile for GeoTiffWrapper {
@@ -66,8 +92,8 @@ impl Tile for GeoTiffWrapper {
         {
             return None;
         }
-        let lat = (lat - self.min_lat) * 3600.0;
-        let lon = (lon - self.min_lon) * 3600.0;
+        let lat = (lat - self.min_lat) * (GRID_SIZE - 1) as f64;
+        let lon = (lon - self.min_lon) * (GRID_SIZE - 1) as f64;
 
         let mut lat_int = lat as usize;
         let mut lon_int = lon as usize;
@@ -76,20 +102,20 @@ impl Tile for GeoTiffWrapper {
         let mut lon_frac = lon - lon_int as f64;
 
         // handle the edge case of max lat/lon
-        if lat_int == 3600 {
+        if lat_int == GRID_SIZE - 1 {
             lat_int -= 1;
             lat_frac += 1.0;
         }
-        if lon_int == 3600 {
+        if lon_int == GRID_SIZE - 1 {
             lon_int -= 1;
             lon_frac += 1.0;
         }
 
         // get values to interpolate
-        let elev00 = self.data.get_pixel(lon_int, lat_int) as f64;
-        let elev01 = self.data.get_pixel(lon_int, lat_int + 1) as f64;
-        let elev10 = self.data.get_pixel(lon_int + 1, lat_int) as f64;
-        let elev11 = self.data.get_pixel(lon_int + 1, lat_int + 1) as f64;
+        let elev00 = self.get_pixel(lon_int, lat_int);
+        let elev01 = self.get_pixel(lon_int, lat_int + 1);
+        let elev10 = self.get_pixel(lon_int + 1, lat_int);
+        let elev11 = self.get_pixel(lon_int + 1, lat_int + 1);
 
         let result = elev00 * (1.0 - lon_frac) * (1.0 - lat_frac)
             + elev01 * (1.0 - lon_frac) * lat_frac
@@ -99,3 +125,74 @@ impl Tile for GeoTiffWrapper {
         Some(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    use super::*;
+
+    /// Writes a tile with the given samples (row by row from the north) into a temporary file
+    /// named after the square degree at 49N 20E, and reads it back.
+    fn round_trip<C: colortype::ColorType>(
+        name: &str,
+        size: usize,
+        data: &[C::Inner],
+    ) -> Option<GeoTiffWrapper>
+    where
+        [C::Inner]: tiff::encoder::TiffValue,
+    {
+        let mut path = env::temp_dir();
+        path.push(format!(
+            "atm-raytracer-test-{}-{}-N49E020.tif",
+            name,
+            std::process::id()
+        ));
+        let file = File::create(&path).unwrap();
+        TiffEncoder::new(file)
+            .unwrap()
+            .write_image::<C>(size as u32, size as u32, data)
+            .unwrap();
+        let tile = GeoTiffWrapper::from_path(&path);
+        fs::remove_file(&path).unwrap();
+        tile
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // the elevation is the number of arc seconds from the north edge plus the number of
+        // arc seconds from the west edge
+        let data: Vec<i16> = (0..GRID_SIZE * GRID_SIZE)
+            .map(|index| (index / GRID_SIZE + index % GRID_SIZE) as i16)
+            .collect();
+        let tile = round_trip::<colortype::GrayI16>("i16", GRID_SIZE, &data).unwrap();
+
+        let elev = |lat, lon| tile.get_elev(lat, lon).unwrap();
+        // the south-west corner is the last row
+        assert_eq!(elev(49.0, 20.0), 3600.0);
+        assert_eq!(elev(50.0, 21.0), 3600.0);
+        assert_eq!(elev(50.0, 20.0), 0.0);
+        assert_eq!(elev(49.0, 21.0), 7200.0);
+        assert!((elev(49.5, 20.5) - 3600.0).abs() < 1e-6);
+        // half an arc second to the south of the north-west corner
+        assert!((elev(50.0 - 0.5 / 3600.0, 20.0) - 0.5).abs() < 1e-6);
+        assert_eq!(tile.get_elev(48.9, 20.5), None);
+    }
+
+    /// The unsigned samples are interpreted as signed, like in the files that don't specify the
+    /// sample format.
+    #[test]
+    fn test_unsigned_samples() {
+        let data = vec![u16::MAX; GRID_SIZE * GRID_SIZE];
+        let tile = round_trip::<colortype::Gray16>("u16", GRID_SIZE, &data).unwrap();
+        assert_eq!(tile.get_elev(49.5, 20.5), Some(-1.0));
+    }
+
+    #[test]
+    fn test_wrong_grid() {
+        let data = vec![0i16; 1201 * 1201];
+        assert!(round_trip::<colortype::GrayI16>("grid", 1201, &data).is_none());
+    }
+}
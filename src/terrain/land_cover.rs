@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek},
+};
+
+use nalgebra::Vector3;
+use tiff::decoder::{Decoder, DecodingResult};
+
+use super::Georeference;
+
+/// The files defining the land cover: a GeoTIFF raster of class codes in geographic coordinates
+/// and a YAML table mapping the class codes to RGB colors.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LandCoverDef {
+    pub raster: String,
+    pub classes: String,
+}
+
+/// A raster of land cover classes.
+pub struct LandCover {
+    width: usize,
+    height: usize,
+    georeference: Georeference,
+    classes: Vec<i64>,
+    colors: HashMap<i64, Vector3<f64>>,
+}
+
+impl LandCover {
+    pub fn from_def(def: &LandCoverDef) -> Result<Self, String> {
+        let raster = File::open(&def.raster)
+            .map_err(|err| format!("couldn't open the land cover raster: {}", err))?;
+        let file = File::open(&def.classes)
+            .map_err(|err| format!("couldn't open the land cover class table: {}", err))?;
+        let colors: HashMap<i64, [u8; 3]> = serde_yaml::from_reader(file)
+            .map_err(|err| format!("couldn't parse the land cover class table: {}", err))?;
+        Self::from_raster(BufReader::new(raster), colors)
+    }
+
+    /// Reads the raster of class codes from a GeoTIFF and assigns the RGB colors to the classes.
+    fn from_raster<R: Read + Seek>(
+        raster: R,
+        colors: HashMap<i64, [u8; 3]>,
+    ) -> Result<Self, String> {
+        let mut decoder = Decoder::new(raster)
+            .map_err(|err| format!("couldn't open the land cover raster: {}", err))?;
+
+        let (width, height) = decoder.dimensions().map_err(|err| {
+            format!(
+                "couldn't read the dimensions of the land cover raster: {}",
+                err
+            )
+        })?;
+        let georeference = Georeference::from_geotiff(&mut decoder)
+            .ok_or("the land cover raster has no valid georeference")?;
+
+        let classes = match decoder
+            .read_image()
+            .map_err(|err| format!("couldn't read the land cover raster: {}", err))?
+        {
+            DecodingResult::U8(data) => data.into_iter().map(i64::from).collect(),
+            DecodingResult::U16(data) => data.into_iter().map(i64::from).collect(),
+            DecodingResult::U32(data) => data.into_iter().map(i64::from).collect(),
+            DecodingResult::I8(data) => data.into_iter().map(i64::from).collect(),
+            DecodingResult::I16(data) => data.into_iter().map(i64::from).collect(),
+            DecodingResult::I32(data) => data.into_iter().map(i64::from).collect(),
+            _ => return Err("the land cover raster must contain integer class codes".to_owned()),
+        };
+
+        Ok(Self {
+            width: width as usize,
+            height: height as usize,
+            georeference,
+            classes,
+            colors: colors
+                .into_iter()
+                .map(|(class, [r, g, b])| {
+                    let color = Vector3::new(r as f64, g as f64, b as f64) / 255.0;
+                    (class, color)
+                })
+                .collect(),
+        })
+    }
+
+    /// Returns the color of the land cover at the given coordinates, or `None` if they are
+    /// outside of the raster or the class there has no color.
+    pub fn color_at(&self, lat: f64, lon: f64) -> Option<Vector3<f64>> {
+        let (col, row) = self.georeference.pixel_at(lat, lon);
+        let (col, row) = (col.floor(), row.floor());
+        if col < 0.0 || row < 0.0 || col >= self.width as f64 || row >= self.height as f64 {
+            return None;
+        }
+        let class = self.classes[row as usize * self.width + col as usize];
+        self.colors.get(&class).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tiff::{
+        encoder::{colortype::Gray8, TiffEncoder},
+        tags::Tag,
+    };
+
+    /// A 4x2 raster of 0.1 degree pixels with the top left corner at 51N 20E.
+    fn raster(classes: &[u8]) -> Cursor<Vec<u8>> {
+        let mut data = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        let mut image = encoder.new_image::<Gray8>(4, 2).unwrap();
+        image
+            .encoder()
+            .write_tag(Tag::ModelPixelScaleTag, &[0.1f64, 0.1, 0.0][..])
+            .unwrap();
+        image
+            .encoder()
+            .write_tag(
+                Tag::ModelTiepointTag,
+                &[0.0f64, 0.0, 0.0, 20.0, 51.0, 0.0][..],
+            )
+            .unwrap();
+        image.write_data(classes).unwrap();
+        data.set_position(0);
+        data
+    }
+
+    #[test]
+    fn test_land_cover_colors() {
+        let colors = vec![(1, [255, 0, 0]), (2, [0, 255, 0])]
+            .into_iter()
+            .collect();
+        let land_cover = LandCover::from_raster(raster(&[1, 1, 2, 3, 2, 2, 1, 1]), colors).unwrap();
+
+        let red = Some(Vector3::new(1.0, 0.0, 0.0));
+        let green = Some(Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(land_cover.color_at(50.95, 20.05), red);
+        assert_eq!(land_cover.color_at(50.95, 20.25), green);
+        assert_eq!(land_cover.color_at(50.85, 20.05), green);
+        assert_eq!(land_cover.color_at(50.85, 20.35), red);
+        // a class without a color
+        assert_eq!(land_cover.color_at(50.95, 20.35), None);
+        // outside of the raster
+        assert_eq!(land_cover.color_at(51.05, 20.05), None);
+        assert_eq!(land_cover.color_at(50.95, 20.45), None);
+    }
+}
@@ -1,4 +1,7 @@
+mod georeference;
 mod geotiff;
+mod land_cover;
+mod texture;
 mod tile;
 
 use dted::{read_dted, read_dted_header};
@@ -10,7 +13,12 @@ use std::{
 };
 
 use self::geotiff::GeoTiffWrapper;
-pub use self::tile::Tile;
+pub use self::{
+    georeference::Georeference,
+    land_cover::{LandCover, LandCoverDef},
+    texture::{Texture, TextureDef},
+    tile::{FnTile, Tile},
+};
 
 type TileObj = Box<dyn Tile + Send + Sync>;
 
@@ -52,17 +60,21 @@ impl TerrainData {
     }
 }
 
+/// The elevations of the terrain, loaded lazily from the files in a folder.
+#[derive(Default)]
 pub struct Terrain {
     data: HashMap<(i16, i16), TerrainData>,
 }
 
 impl Terrain {
+    /// Creates an empty terrain, with the elevation undefined everywhere.
     pub fn new() -> Self {
         Terrain {
             data: HashMap::new(),
         }
     }
 
+    /// Registers the DTED and GeoTIFF files in the folder; they are only read when needed.
     pub fn from_folder<P: AsRef<Path>>(terrain_folder: P) -> Self {
         let mut terrain = Self::new();
         let mut files = 0;
@@ -110,6 +122,17 @@ impl Terrain {
         true
     }
 
+    /// Adds a tile of elevation data, covering the square degree starting at its minimum latitude
+    /// and longitude.
+    pub fn add_tile<T: Tile + Send + Sync + 'static>(&mut self, tile: T) {
+        let lat = tile.min_latitude().floor() as i16;
+        let lon = tile.min_longitude().floor() as i16;
+        let _ = self.data.insert(
+            (lat, lon),
+            TerrainData(RwLock::new(TerrainDataInner::Loaded(Box::new(tile)))),
+        );
+    }
+
     pub fn buffer_file(&mut self, path: PathBuf) {
         if self.buffer_dted(path.clone()) || self.buffer_geotiff(path.clone()) {
             return;
@@ -0,0 +1,72 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use image::RgbImage;
+use nalgebra::Vector3;
+use tiff::decoder::Decoder;
+
+use super::Georeference;
+
+/// A georeferenced RGB image (eg. an orthophoto) draped over the terrain. The image has to be in
+/// geographic coordinates and is georeferenced either by a world file or, for GeoTIFFs, by its
+/// tags.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextureDef {
+    pub image: String,
+    /// Whether the lighting of the coloring method is applied to the image.
+    #[serde(default = "default_shading")]
+    pub shading: bool,
+}
+
+fn default_shading() -> bool {
+    true
+}
+
+pub struct Texture {
+    image: RgbImage,
+    georeference: Georeference,
+}
+
+impl Texture {
+    pub fn from_def(def: &TextureDef) -> Result<Self, String> {
+        let path = Path::new(&def.image);
+        let image = image::open(path)
+            .map_err(|err| format!("couldn't open the texture image: {}", err))?
+            .to_rgb8();
+        let georeference = match Georeference::from_world_file(path)? {
+            Some(georeference) => Some(georeference),
+            None => File::open(path)
+                .ok()
+                .and_then(|file| Decoder::new(BufReader::new(file)).ok())
+                .and_then(|mut decoder| Georeference::from_geotiff(&mut decoder)),
+        }
+        .ok_or("the texture image has neither a world file nor a GeoTIFF georeference")?;
+        Ok(Self {
+            image,
+            georeference,
+        })
+    }
+
+    /// Returns the bilinearly filtered color of the image at the given coordinates, or `None`
+    /// if they are outside of the image.
+    pub fn color_at(&self, lat: f64, lon: f64) -> Option<Vector3<f64>> {
+        let (width, height) = self.image.dimensions();
+        let (col, row) = self.georeference.pixel_at(lat, lon);
+        if col < 0.0 || row < 0.0 || col >= width as f64 || row >= height as f64 {
+            return None;
+        }
+        // interpolate between the centers of the pixels, clamping at the edges
+        let x = (col - 0.5).clamp(0.0, (width - 1) as f64);
+        let y = (row - 0.5).clamp(0.0, (height - 1) as f64);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+        let pixel = |x, y| {
+            let [r, g, b] = self.image.get_pixel(x, y).0;
+            Vector3::new(r as f64, g as f64, b as f64) / 255.0
+        };
+        let top = pixel(x0, y0) * (1.0 - fx) + pixel(x1, y0) * fx;
+        let bottom = pixel(x0, y1) * (1.0 - fx) + pixel(x1, y1) * fx;
+        Some(top * (1.0 - fy) + bottom * fy)
+    }
+}
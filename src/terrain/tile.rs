@@ -29,3 +29,33 @@ impl Tile for DtedData {
         self.get_elev(lat, lon)
     }
 }
+
+/// A tile with the elevations given by a function of the latitude and the longitude, covering the
+/// square degree starting at `lat` and `lon`; useful for synthetic terrain.
+pub struct FnTile<F> {
+    pub lat: f64,
+    pub lon: f64,
+    pub elev: F,
+}
+
+impl<F: Fn(f64, f64) -> f64> Tile for FnTile<F> {
+    fn min_latitude(&self) -> f64 {
+        self.lat
+    }
+
+    fn max_latitude(&self) -> f64 {
+        self.lat + 1.0
+    }
+
+    fn min_longitude(&self) -> f64 {
+        self.lon
+    }
+
+    fn max_longitude(&self) -> f64 {
+        self.lon + 1.0
+    }
+
+    fn get_elev(&self, lat: f64, lon: f64) -> Option<f64> {
+        Some((self.elev)(lat, lon))
+    }
+}
@@ -0,0 +1,435 @@
+use std::{convert::TryFrom, f64::consts::PI, fmt, str::FromStr};
+
+use atm_refraction::{EarthShape, Environment, RayState};
+
+use crate::generator::{params::Integrator, ray_stepper};
+
+/// The step of the simulation of rays leaving the atmosphere, in meters.
+pub const EXIT_STEP: f64 = 100.0;
+/// The altitude above which the refraction is negligible.
+const ATMOSPHERE_TOP: f64 = 100_000.0;
+/// Rays longer than this are considered trapped in the atmosphere.
+const MAX_EXIT_PATH: f64 = 5_000_000.0;
+const APPARENT_ELEVATION_ITERATIONS: usize = 40;
+
+const EARTH_RADIUS_KM: f64 = 6378.14;
+const MOON_RADIUS_KM: f64 = 1737.4;
+
+/// The periodic terms of the Moon's longitude and distance: the multiples of D, M, M' and F, the
+/// coefficient of the sine of the argument for the longitude (in 1e-6 degrees) and of its cosine
+/// for the distance (in meters). The largest terms of the ELP-2000/82 theory, after Meeus.
+const MOON_LR_TERMS: [(f64, f64, f64, f64, f64, f64); 25] = [
+    (0.0, 0.0, 1.0, 0.0, 6288774.0, -20905355.0),
+    (2.0, 0.0, -1.0, 0.0, 1274027.0, -3699111.0),
+    (2.0, 0.0, 0.0, 0.0, 658314.0, -2955968.0),
+    (0.0, 0.0, 2.0, 0.0, 213618.0, -569925.0),
+    (0.0, 1.0, 0.0, 0.0, -185116.0, 48888.0),
+    (0.0, 0.0, 0.0, 2.0, -114332.0, -3149.0),
+    (2.0, 0.0, -2.0, 0.0, 58793.0, 246158.0),
+    (2.0, -1.0, -1.0, 0.0, 57066.0, -152138.0),
+    (2.0, 0.0, 1.0, 0.0, 53322.0, -170733.0),
+    (2.0, -1.0, 0.0, 0.0, 45758.0, -204586.0),
+    (0.0, 1.0, -1.0, 0.0, -40923.0, -129620.0),
+    (1.0, 0.0, 0.0, 0.0, -34720.0, 108743.0),
+    (0.0, 1.0, 1.0, 0.0, -30383.0, 104755.0),
+    (2.0, 0.0, 0.0, -2.0, 15327.0, 10321.0),
+    (0.0, 0.0, 1.0, 2.0, -12528.0, 0.0),
+    (0.0, 0.0, 1.0, -2.0, 10980.0, 79661.0),
+    (4.0, 0.0, -1.0, 0.0, 10675.0, -34782.0),
+    (0.0, 0.0, 3.0, 0.0, 10034.0, -23210.0),
+    (4.0, 0.0, -2.0, 0.0, 8548.0, -21636.0),
+    (2.0, 1.0, -1.0, 0.0, -7888.0, 24208.0),
+    (2.0, 1.0, 0.0, 0.0, -6766.0, 30824.0),
+    (1.0, 0.0, -1.0, 0.0, -5163.0, -8379.0),
+    (1.0, 1.0, 0.0, 0.0, 4987.0, -16675.0),
+    (2.0, -1.0, 1.0, 0.0, 4036.0, -12831.0),
+    (2.0, 0.0, 2.0, 0.0, 3994.0, -10445.0),
+];
+
+/// The periodic terms of the Moon's latitude: the multiples of D, M, M' and F and the coefficient
+/// of the sine of the argument (in 1e-6 degrees).
+const MOON_B_TERMS: [(f64, f64, f64, f64, f64); 13] = [
+    (0.0, 0.0, 0.0, 1.0, 5128122.0),
+    (0.0, 0.0, 1.0, 1.0, 280602.0),
+    (0.0, 0.0, 1.0, -1.0, 277693.0),
+    (2.0, 0.0, 0.0, -1.0, 173237.0),
+    (2.0, 0.0, -1.0, 1.0, 55413.0),
+    (2.0, 0.0, -1.0, -1.0, 46271.0),
+    (2.0, 0.0, 0.0, 1.0, 32573.0),
+    (0.0, 0.0, 2.0, 1.0, 17198.0),
+    (2.0, 0.0, 1.0, -1.0, 9266.0),
+    (0.0, 0.0, 2.0, -1.0, 8822.0),
+    (2.0, -1.0, 0.0, -1.0, 8216.0),
+    (2.0, 0.0, -2.0, -1.0, 4324.0),
+    (2.0, 0.0, 1.0, 1.0, 4200.0),
+];
+
+/// A moment in time, in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: f64,
+}
+
+impl FromStr for DateTime {
+    type Err = String;
+
+    /// Parses a date and time in the format `YYYY-MM-DDTHH:MM[:SS[.sss]][Z]` (a space can be used
+    /// instead of `T`).
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid UTC date and time {:?}", s);
+        let s = s.trim();
+        let s = s.strip_suffix('Z').unwrap_or(s);
+        let (date, time) = s.split_once(['T', ' ']).ok_or_else(invalid)?;
+
+        let mut date = date.splitn(3, '-');
+        let mut time = time.splitn(3, ':');
+        let next = |part: Option<&str>| -> Result<u32, String> {
+            part.ok_or_else(invalid)?.parse().map_err(|_| invalid())
+        };
+        let year = next(date.next())?;
+        let month = next(date.next())?;
+        let day = next(date.next())?;
+        let hour = next(time.next())?;
+        let minute = next(time.next())?;
+        let second = match time.next() {
+            Some(second) => second.parse().map_err(|_| invalid())?,
+            None => 0.0,
+        };
+
+        if !(1..=12).contains(&month)
+            || day < 1
+            || day > days_in_month(year as i32, month)
+            || hour > 23
+            || minute > 59
+            || !(0.0..61.0).contains(&second)
+        {
+            return Err(invalid());
+        }
+
+        Ok(DateTime {
+            year: year as i32,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+}
+
+/// The number of days in the given month (1-12) of the Gregorian calendar.
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:",
+            self.year, self.month, self.day, self.hour, self.minute
+        )?;
+        if self.second.fract() == 0.0 {
+            write!(f, "{:02}Z", self.second)
+        } else {
+            write!(f, "{:06.3}Z", self.second)
+        }
+    }
+}
+
+impl TryFrom<String> for DateTime {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<DateTime> for String {
+    fn from(datetime: DateTime) -> String {
+        datetime.to_string()
+    }
+}
+
+impl DateTime {
+    /// The Julian day number (with the fractional part).
+    pub fn julian_day(&self) -> f64 {
+        let (year, month) = if self.month <= 2 {
+            (self.year - 1, self.month + 12)
+        } else {
+            (self.year, self.month)
+        };
+        let (year, month) = (year as f64, month as f64);
+        let century = (year / 100.0).floor();
+        let gregorian_correction = 2.0 - century + (century / 4.0).floor();
+        let day = self.day as f64 + self.minutes_of_day() / 1440.0;
+
+        (365.25 * (year + 4716.0)).floor() + (30.6001 * (month + 1.0)).floor() + day - 1524.5
+            + gregorian_correction
+    }
+
+    fn minutes_of_day(&self) -> f64 {
+        self.hour as f64 * 60.0 + self.minute as f64 + self.second / 60.0
+    }
+
+    /// Julian centuries since J2000.0.
+    fn julian_centuries(&self) -> f64 {
+        (self.julian_day() - 2_451_545.0) / 36525.0
+    }
+}
+
+/// Converts the hour angle and the declination (in radians) into the azimuth and the elevation
+/// (in degrees) seen at the given latitude (in degrees).
+fn horizontal_coords(hour_angle: f64, declination: f64, lat: f64) -> (f64, f64) {
+    let lat = lat.to_radians();
+    let elevation =
+        (lat.sin() * declination.sin() + lat.cos() * declination.cos() * hour_angle.cos()).asin();
+    let azimuth = (-hour_angle.sin() * declination.cos())
+        .atan2(declination.sin() * lat.cos() - declination.cos() * lat.sin() * hour_angle.cos());
+
+    (
+        azimuth.rem_euclid(2.0 * PI).to_degrees(),
+        elevation.to_degrees(),
+    )
+}
+
+/// The mean obliquity of the ecliptic, in degrees.
+fn mean_obliquity(t: f64) -> f64 {
+    23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0
+}
+
+/// Calculates the geometric (unrefracted) azimuth and elevation of the center of the sun, in
+/// degrees, as seen from the given place at the given time.
+///
+/// Uses the algorithm from the NOAA solar calculator, which is accurate to a small fraction of a
+/// degree for dates between 1901 and 2099.
+pub fn sun_position(datetime: &DateTime, lat: f64, lon: f64) -> (f64, f64) {
+    let t = datetime.julian_centuries();
+
+    let mean_long = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = (357.52911 + t * (35999.05029 - 0.0001537 * t)).to_radians();
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+    let center = mean_anomaly.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * mean_anomaly).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * mean_anomaly).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_long = (mean_long + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+
+    let obliquity = (mean_obliquity(t) + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_long.sin()).asin();
+
+    // the equation of time, in minutes
+    let y = (obliquity / 2.0).tan().powi(2);
+    let mean_long = mean_long.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * mean_long).sin() - 2.0 * eccentricity * mean_anomaly.sin()
+            + 4.0 * eccentricity * y * mean_anomaly.sin() * (2.0 * mean_long).cos()
+            - 0.5 * y * y * (4.0 * mean_long).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * mean_anomaly).sin())
+        .to_degrees();
+
+    let true_solar_time = datetime.minutes_of_day() + equation_of_time + 4.0 * lon;
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+
+    horizontal_coords(hour_angle, declination, lat)
+}
+
+/// Calculates the geocentric ecliptic longitude and latitude (in degrees) and the distance (in
+/// km) of the Moon at the given Julian day.
+fn moon_ecliptic_coords(julian_day: f64) -> (f64, f64, f64) {
+    let t = (julian_day - 2_451_545.0) / 36525.0;
+    let mean_long = 218.3164477 + 481267.88123421 * t;
+    let elongation = (297.8501921 + 445267.1114034 * t).to_radians();
+    let sun_anomaly = (357.5291092 + 35999.0502909 * t).to_radians();
+    let moon_anomaly = (134.9633964 + 477198.8675055 * t).to_radians();
+    let latitude_arg = (93.2720950 + 483202.0175233 * t).to_radians();
+    // the decreasing eccentricity of the Earth's orbit
+    let e = 1.0 - 0.002516 * t - 0.0000074 * t * t;
+
+    let argument = |d: f64, m: f64, m1: f64, f: f64| {
+        d * elongation + m * sun_anomaly + m1 * moon_anomaly + f * latitude_arg
+    };
+    let eccentricity_factor = |m: f64| e.powi(m.abs() as i32);
+
+    let (mut sum_l, mut sum_r) = (0.0, 0.0);
+    for &(d, m, m1, f, l, r) in &MOON_LR_TERMS {
+        let arg = argument(d, m, m1, f);
+        sum_l += l * eccentricity_factor(m) * arg.sin();
+        sum_r += r * eccentricity_factor(m) * arg.cos();
+    }
+    let sum_b: f64 = MOON_B_TERMS
+        .iter()
+        .map(|&(d, m, m1, f, b)| b * eccentricity_factor(m) * argument(d, m, m1, f).sin())
+        .sum();
+
+    (
+        (mean_long + sum_l / 1e6).rem_euclid(360.0),
+        sum_b / 1e6,
+        385000.56 + sum_r / 1000.0,
+    )
+}
+
+/// Calculates the topocentric azimuth and elevation (geometric, without the refraction) of the
+/// center of the Moon and its angular radius, in degrees, as seen from the given place at the
+/// given time. The accuracy is about 0.01 degree.
+pub fn moon_position(datetime: &DateTime, lat: f64, lon: f64) -> (f64, f64, f64) {
+    let julian_day = datetime.julian_day();
+    let t = datetime.julian_centuries();
+    let (long, lat_ecl, distance) = moon_ecliptic_coords(julian_day);
+    let (long, lat_ecl) = (long.to_radians(), lat_ecl.to_radians());
+    let obliquity = mean_obliquity(t).to_radians();
+
+    let right_ascension =
+        (long.sin() * obliquity.cos() - lat_ecl.tan() * obliquity.sin()).atan2(long.cos());
+    let declination =
+        (lat_ecl.sin() * obliquity.cos() + lat_ecl.cos() * obliquity.sin() * long.sin()).asin();
+
+    let sidereal_time =
+        280.46061837 + 360.98564736629 * (julian_day - 2_451_545.0) + 0.000387933 * t * t
+            - t * t * t / 38_710_000.0;
+    let hour_angle = (sidereal_time + lon).to_radians() - right_ascension;
+    let (azimuth, elevation) = horizontal_coords(hour_angle, declination, lat);
+
+    // the Moon is close enough for the observer's position on the Earth to lower it noticeably
+    let parallax = (EARTH_RADIUS_KM / distance).asin();
+    let elevation = elevation
+        - (parallax.sin() * elevation.to_radians().cos())
+            .asin()
+            .to_degrees();
+    let angular_radius = (MOON_RADIUS_KM / distance).asin().to_degrees();
+
+    (azimuth, elevation, angular_radius)
+}
+
+/// The angle between two directions given by their azimuths and elevation angles, in degrees.
+pub fn angular_distance(azimuth1: f64, elevation1: f64, azimuth2: f64, elevation2: f64) -> f64 {
+    let (el1, el2) = (elevation1.to_radians(), elevation2.to_radians());
+    let cos_dist =
+        el1.sin() * el2.sin() + el1.cos() * el2.cos() * (azimuth1 - azimuth2).to_radians().cos();
+    cos_dist.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+/// Follows the path of a ray starting at the altitude `alt` out of the atmosphere and returns the
+/// elevation angle (in degrees, relative to the observer's horizon) of the direction in which it
+/// leaves. Returns `None` if the ray hits the sea level or doesn't leave the atmosphere.
+pub fn exit_elevation(
+    shape: &EarthShape,
+    alt: f64,
+    mut path: impl Iterator<Item = RayState>,
+) -> Option<f64> {
+    let ground = alt.min(0.0);
+    let state = path
+        .find(|state| state.h > ATMOSPHERE_TOP || state.h < ground || state.x > MAX_EXIT_PATH)
+        .filter(|state| state.h > ATMOSPHERE_TOP)?;
+
+    let exit_angle = match *shape {
+        EarthShape::Flat => state.dh.atan(),
+        EarthShape::Spherical { radius } => {
+            // the local elevation angle minus the angle between the local vertical and the
+            // observer's one
+            (state.dh * radius / (radius + state.h)).atan() - state.x / radius
+        }
+    };
+    Some(exit_angle.to_degrees())
+}
+
+/// Finds the elevation angle (in degrees) at which an observer at the altitude `alt` sees an
+/// object that would be seen at the elevation angle `elevation` without an atmosphere, tracing the
+/// rays with the given integrator. Returns `None` if no ray reaching the observer comes from this
+/// direction.
+pub fn apparent_elevation(
+    env: &Environment,
+    integrator: Integrator,
+    alt: f64,
+    elevation: f64,
+) -> Option<f64> {
+    let exit = |ang: f64| {
+        let stepper = ray_stepper(env, integrator, false, alt, ang.to_radians(), EXIT_STEP);
+        exit_elevation(&env.shape, alt, stepper).unwrap_or(f64::NEG_INFINITY)
+    };
+    // refraction lifts objects, but strong inversions can also lower them a bit
+    let mut low = (elevation - 1.0).max(-89.9);
+    let mut high = (elevation + 5.0).min(89.9);
+    if exit(low) > elevation || exit(high) < elevation {
+        return None;
+    }
+    for _ in 0..APPARENT_ELEVATION_ITERATIONS {
+        let mid = (low + high) / 2.0;
+        if exit(mid) < elevation {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    Some((low + high) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_datetime() {
+        let datetime: DateTime = "2024-06-20T12:30:15.5Z".parse().unwrap();
+        assert_eq!(
+            datetime,
+            DateTime {
+                year: 2024,
+                month: 6,
+                day: 20,
+                hour: 12,
+                minute: 30,
+                second: 15.5,
+            }
+        );
+        assert_eq!("2000-01-01 12:00".parse::<DateTime>().unwrap().second, 0.0);
+        assert!("2024-13-01T00:00".parse::<DateTime>().is_err());
+        assert!("2024-06-20".parse::<DateTime>().is_err());
+        assert!("2024-02-29T00:00".parse::<DateTime>().is_ok());
+        assert!("2024-02-31T00:00".parse::<DateTime>().is_err());
+        assert!("2023-02-29T00:00".parse::<DateTime>().is_err());
+        assert!("1900-02-29T00:00".parse::<DateTime>().is_err());
+        assert!("2000-02-29T00:00".parse::<DateTime>().is_ok());
+        assert!("2024-04-31T00:00".parse::<DateTime>().is_err());
+    }
+
+    #[test]
+    fn test_julian_day() {
+        let datetime: DateTime = "2000-01-01T12:00Z".parse().unwrap();
+        assert!((datetime.julian_day() - 2_451_545.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sun_position() {
+        // the June solstice, at noon on the Greenwich meridian the sun is close to the south, at
+        // an elevation of 90 - latitude + the obliquity of the ecliptic
+        let datetime: DateTime = "2024-06-20T12:00Z".parse().unwrap();
+        let (azimuth, elevation) = sun_position(&datetime, 50.0, 0.0);
+        assert!((azimuth - 180.0).abs() < 1.0);
+        assert!((elevation - 63.44).abs() < 0.05);
+
+        // the sun is below the horizon at night
+        let datetime: DateTime = "2024-06-20T00:00Z".parse().unwrap();
+        assert!(sun_position(&datetime, 50.0, 0.0).1 < 0.0);
+    }
+
+    #[test]
+    fn test_moon_ecliptic_coords() {
+        // example 47.a from Meeus' Astronomical Algorithms: 1992 April 12, 0h TD
+        let (long, lat, distance) = moon_ecliptic_coords(2_448_724.5);
+        assert!((long - 133.162655).abs() < 0.01);
+        assert!((lat + 3.229126).abs() < 0.01);
+        assert!((distance - 368409.7).abs() < 50.0);
+    }
+}
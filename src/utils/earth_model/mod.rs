@@ -143,6 +143,64 @@ impl EarthModel {
             .coords_at_dist_calc(start, dir),
         }
     }
+
+    /// Returns the azimuth (in degrees) and the distance (in meters) at which `target` lies as seen
+    /// from `start`, consistently with `coords_at_dist_calc`, or `None` if they couldn't be found.
+    /// The azimuth is 0 if `target` is the same point as `start`.
+    pub fn direction_to(&self, start: (f64, f64), target: (f64, f64)) -> Option<(f64, f64)> {
+        const EPSILON: f64 = 1e-3;
+        const D_AZ: f64 = 1e-6;
+        const D_DIST: f64 = 1.0;
+
+        let residual = |az: f64, dist: f64| {
+            let (lat, lon) = self.coords_at_dist_calc(start, az).coords_at_dist(dist);
+            let mut d_lon = lon - target.1;
+            if d_lon > 180.0 {
+                d_lon -= 360.0;
+            } else if d_lon < -180.0 {
+                d_lon += 360.0;
+            }
+            (
+                (lat - target.0) * DEGREE_DISTANCE,
+                d_lon * DEGREE_DISTANCE * target.0.to_radians().cos(),
+            )
+        };
+
+        // initial guess from the great circle on a sphere
+        let (lat1, lon1) = (start.0.to_radians(), start.1.to_radians());
+        let (lat2, lon2) = (target.0.to_radians(), target.1.to_radians());
+        let d_lon = lon2 - lon1;
+        let mut az = (d_lon.sin() * lat2.cos())
+            .atan2(lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos())
+            .to_degrees();
+        let hav = (0.5 * (lat2 - lat1)).sin().powi(2)
+            + lat1.cos() * lat2.cos() * (0.5 * d_lon).sin().powi(2);
+        let mut dist = 2.0 * hav.sqrt().min(1.0).asin() * EARTH_R;
+
+        // the azimuth is undefined at zero distance, and the Newton iteration below can't find it
+        if dist < EPSILON {
+            return Some((0.0, 0.0));
+        }
+
+        for _ in 0..50 {
+            let (rn, re) = residual(az, dist);
+            if (rn * rn + re * re).sqrt() < EPSILON {
+                return Some((az.rem_euclid(360.0), dist));
+            }
+            let (an, ae) = residual(az + D_AZ, dist);
+            let (dn, de) = residual(az, dist + D_DIST);
+            let (j11, j21) = ((an - rn) / D_AZ, (ae - re) / D_AZ);
+            let (j12, j22) = ((dn - rn) / D_DIST, (de - re) / D_DIST);
+            let det = j11 * j22 - j12 * j21;
+            if det.abs() < f64::EPSILON || !det.is_finite() {
+                return None;
+            }
+            az -= (j22 * rn - j12 * re) / det;
+            dist -= (j11 * re - j21 * rn) / det;
+        }
+
+        None
+    }
 }
 
 pub fn spherical_to_cartesian(r: f64, lat: f64, lon: f64) -> Vector3<f64> {
@@ -170,3 +228,52 @@ fn spherical_directions(lat: f64, lon: f64) -> (Vector3<f64>, Vector3<f64>, Vect
 
     (dirn, dire, dirup)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EarthModel;
+
+    #[test]
+    fn test_direction_to() {
+        let start = (49.5, 20.1);
+        for model in [
+            EarthModel::SimpleSphere,
+            EarthModel::Wgs84,
+            EarthModel::FlatDistorted,
+            EarthModel::AzimuthalEquidistant,
+        ] {
+            for (az, dist) in [(0.0, 10_000.0), (73.0, 120_000.0), (250.0, 45_000.0)] {
+                let target = model.coords_at_dist_calc(start, az).coords_at_dist(dist);
+                let (found_az, found_dist) = model.direction_to(start, target).unwrap();
+                let diff_az = (found_az - az + 180.0).rem_euclid(360.0) - 180.0;
+                assert!(diff_az.abs() < 1e-6, "{:?}: {} != {}", model, found_az, az);
+                assert!(
+                    (found_dist - dist).abs() < 1e-2,
+                    "{:?}: {} != {}",
+                    model,
+                    found_dist,
+                    dist
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_direction_to_start() {
+        for start in [(49.5, 20.1), (50.62, 20.5), (-33.9, 151.2)] {
+            for model in [
+                EarthModel::SimpleSphere,
+                EarthModel::Wgs84,
+                EarthModel::FlatDistorted,
+                EarthModel::AzimuthalEquidistant,
+            ] {
+                assert_eq!(
+                    model.direction_to(start, start),
+                    Some((0.0, 0.0)),
+                    "{:?}",
+                    model
+                );
+            }
+        }
+    }
+}
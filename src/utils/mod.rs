@@ -1,8 +1,13 @@
+mod astronomy;
 mod earth_model;
 
 use image::{Rgb, Rgba};
 use nalgebra::{Vector3, Vector4};
 
+pub use astronomy::{
+    angular_distance, apparent_elevation, exit_elevation, moon_position, sun_position, DateTime,
+    EXIT_STEP,
+};
 pub use earth_model::{DirectionalCalc, EarthModel};
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -22,12 +27,44 @@ pub fn rgb_to_vec3(rgb: Rgb<u8>) -> Vector3<f64> {
 
 #[allow(clippy::many_single_char_names)]
 pub fn vec3_to_rgb(v: Vector3<f64>) -> Rgb<u8> {
-    let r = (v[0] * 255.0) as u8;
-    let g = (v[1] * 255.0) as u8;
-    let b = (v[2] * 255.0) as u8;
+    let r = (v[0] * 255.0).round().clamp(0.0, 255.0) as u8;
+    let g = (v[1] * 255.0).round().clamp(0.0, 255.0) as u8;
+    let b = (v[2] * 255.0).round().clamp(0.0, 255.0) as u8;
     Rgb([r, g, b])
 }
 
+/// Converts an sRGB-encoded color component (0-1) into linear light.
+pub fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a color component in linear light into the sRGB encoding; values outside of 0-1 are
+/// clamped.
+pub fn linear_to_srgb(c: f64) -> f64 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes an 8-bit sRGB color into linear light.
+pub fn decode_srgb(rgb: [u8; 3]) -> Vector3<f64> {
+    rgb_to_vec3(Rgb(rgb)).map(srgb_to_linear)
+}
+
+/// Encodes a color in linear light as 8-bit sRGB. `dither` is added to the value before
+/// rounding, in units of the least significant bit (so it should be between -0.5 and 0.5).
+pub fn encode_srgb(color: Vector3<f64>, dither: f64) -> Rgb<u8> {
+    let encoded = color.map(|c| linear_to_srgb(c) + dither / 255.0);
+    vec3_to_rgb(encoded)
+}
+
 #[allow(clippy::many_single_char_names)]
 pub fn rgba_to_vec4(rgba: Rgba<u8>) -> Vector4<f64> {
     let r = rgba.0[0] as f64 / 255.0;
@@ -39,9 +76,37 @@ pub fn rgba_to_vec4(rgba: Rgba<u8>) -> Vector4<f64> {
 
 #[allow(clippy::many_single_char_names)]
 pub fn vec4_to_rgba(v: Vector4<f64>) -> Rgba<u8> {
-    let r = (v[0] * 255.0) as u8;
-    let g = (v[1] * 255.0) as u8;
-    let b = (v[2] * 255.0) as u8;
-    let a = (v[3] * 255.0) as u8;
+    let r = (v[0] * 255.0).round().clamp(0.0, 255.0) as u8;
+    let g = (v[1] * 255.0).round().clamp(0.0, 255.0) as u8;
+    let b = (v[2] * 255.0).round().clamp(0.0, 255.0) as u8;
+    let a = (v[3] * 255.0).round().clamp(0.0, 255.0) as u8;
     Rgba([r, g, b, a])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srgb_round_trip() {
+        for i in 0..=255u8 {
+            assert_eq!(encode_srgb(decode_srgb([i, i, i]), 0.0), Rgb([i, i, i]));
+        }
+        assert_eq!(
+            encode_srgb(Vector3::new(-1.0, 0.5, 2.0), 0.0),
+            Rgb([0, 188, 255])
+        );
+    }
+
+    #[test]
+    fn test_rgba_round_trip() {
+        for i in 0..=255u8 {
+            let rgba = Rgba([i, 255 - i, i / 2, 255]);
+            assert_eq!(vec4_to_rgba(rgba_to_vec4(rgba)), rgba);
+        }
+        assert_eq!(
+            vec4_to_rgba(Vector4::new(-0.5, 0.499 / 255.0, 0.501 / 255.0, 1.5)),
+            Rgba([0, 0, 1, 255])
+        );
+    }
+}
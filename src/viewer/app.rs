@@ -1,5 +1,9 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, fs::File, io::BufReader, rc::Rc};
 
+use atm_raytracer::{
+    generator::metadata::MetadataReader,
+    renderer::{self, LinearImage},
+};
 use fltk::{
     app,
     draw::{draw_arc, draw_line, draw_rectf, set_draw_color, Offscreen},
@@ -11,9 +15,8 @@ use fltk::{
     window::Window,
 };
 
-use crate::{generator::AllData, renderer};
+type Metadata = MetadataReader<BufReader<File>>;
 
-#[derive(Clone)]
 struct ViewState {
     mouse_x: i32,
     mouse_y: i32,
@@ -26,7 +29,7 @@ struct ViewState {
     frame_w: i32,
     frame_h: i32,
     image: RgbImage,
-    data: AllData,
+    data: Metadata,
 }
 
 const CURSOR_SIZE: i32 = 20;
@@ -44,7 +47,7 @@ fn as_dms(ang: f64) -> (usize, usize, usize) {
 }
 
 impl ViewState {
-    fn new(image: RgbImage, data: AllData, frame_w: i32, frame_h: i32) -> ViewState {
+    fn new(image: RgbImage, data: Metadata, frame_w: i32, frame_h: i32) -> ViewState {
         ViewState {
             mouse_x: 0,
             mouse_y: 0,
@@ -109,65 +112,68 @@ impl ViewState {
         offs.end();
     }
 
-    fn set_label(&self, frame: &mut Frame) {
+    fn set_label(&mut self, frame: &mut Frame) {
+        let width = self.data.params().output.width as i32;
+        let height = self.data.params().output.height as i32;
         let label = if let Some((cx, cy)) = self.cursor {
-            if cx < 0
-                || cx >= self.data.params.output.width as i32
-                || cy < 0
-                || cy >= self.data.params.output.height as i32
-            {
+            if cx < 0 || cx >= width || cy < 0 || cy >= height {
                 format!("{} {}", INFO_TITLE, INFO_NONE)
             } else {
                 let x = cx as usize;
                 let y = cy as usize;
-                let pixel = &self.data.result[y][x];
-                let elev_ang = pixel.elevation_angle;
-                let azim = pixel.azimuth;
-                let rest = if !pixel.trace_points.is_empty() {
-                    let blobs: Vec<_> = pixel
-                        .trace_points
-                        .iter()
-                        .enumerate()
-                        .map(|(index, tp)| {
-                            let lon = as_dms(tp.lon);
-                            let lat = as_dms(tp.lat);
-                            format!(
-                                "({}) Physical data:\n\
-                                Distance: {:.1} km ({:.1} mi)\n\
-                                Elevation: {:.1} m ({:.0} ft)\n\
-                                Latitude: {}°{}'{}\"{} ({:.6})\n\
-                                Longitude: {}°{}'{}\"{} ({:.6})",
-                                index,
-                                tp.distance / 1000.0,
-                                tp.distance / 1609.0,
-                                tp.elevation,
-                                tp.elevation / 0.304,
-                                lat.0,
-                                lat.1,
-                                lat.2,
-                                if tp.lat >= 0.0 { "N" } else { "S" },
-                                tp.lat,
-                                lon.0,
-                                lon.1,
-                                lon.2,
-                                if tp.lon >= 0.0 { "E" } else { "W" },
-                                tp.lon
-                            )
-                        })
-                        .collect();
-                    blobs.join("\n\n")
-                } else {
-                    format!("Physical data: {}", INFO_NONE)
-                };
-                format!(
-                    "{}\n\n\
-                    Pixel coordinates: ({}, {})\n\n\
-                    Viewing direction:\n\
-                    Elevation: {:.3}°\n\
-                    Azimuth: {:.3}°\n\n\
-                    {}",
-                    INFO_TITLE, x, y, elev_ang, azim, rest
-                )
+                match self.data.row(y) {
+                    Ok(row) => {
+                        let pixel = &row[x];
+                        let elev_ang = pixel.elevation_angle;
+                        let azim = pixel.azimuth;
+                        let rest = if !pixel.trace_points.is_empty() {
+                            let blobs: Vec<_> = pixel
+                                .trace_points
+                                .iter()
+                                .enumerate()
+                                .map(|(index, tp)| {
+                                    let lon = as_dms(tp.lon);
+                                    let lat = as_dms(tp.lat);
+                                    format!(
+                                        "({}) Physical data:\n\
+                                        Distance: {:.1} km ({:.1} mi)\n\
+                                        Elevation: {:.1} m ({:.0} ft)\n\
+                                        Latitude: {}°{}'{}\"{} ({:.6})\n\
+                                        Longitude: {}°{}'{}\"{} ({:.6})",
+                                        index,
+                                        tp.distance / 1000.0,
+                                        tp.distance / 1609.0,
+                                        tp.elevation,
+                                        tp.elevation / 0.304,
+                                        lat.0,
+                                        lat.1,
+                                        lat.2,
+                                        if tp.lat >= 0.0 { "N" } else { "S" },
+                                        tp.lat,
+                                        lon.0,
+                                        lon.1,
+                                        lon.2,
+                                        if tp.lon >= 0.0 { "E" } else { "W" },
+                                        tp.lon
+                                    )
+                                })
+                                .collect();
+                            blobs.join("\n\n")
+                        } else {
+                            format!("Physical data: {}", INFO_NONE)
+                        };
+                        format!(
+                            "{}\n\n\
+                            Pixel coordinates: ({}, {})\n\n\
+                            Viewing direction:\n\
+                            Elevation: {:.3}°\n\
+                            Azimuth: {:.3}°\n\n\
+                            {}",
+                            INFO_TITLE, x, y, elev_ang, azim, rest
+                        )
+                    }
+                    Err(err) => format!("{} {}", INFO_TITLE, err),
+                }
             }
         } else {
             format!("{} {}", INFO_TITLE, INFO_NONE)
@@ -211,7 +217,7 @@ impl ViewState {
 const WIDTH: i32 = 1280;
 const HEIGHT: i32 = 800;
 
-pub fn run(data: AllData) -> Result<(), String> {
+pub fn run(mut data: Metadata) -> Result<(), String> {
     let app = app::App::default().with_scheme(app::Scheme::Gtk);
     app::set_visual(Mode::Rgb8).unwrap();
 
@@ -241,11 +247,16 @@ pub fn run(data: AllData) -> Result<(), String> {
     wind.end();
     wind.show();
 
-    let image = renderer::draw_image(&data.result, &data.params).into_raw();
+    let params = data.params().clone();
+    let mut image = LinearImage::new(params.output.width as u32, params.output.height as u32);
+    data.for_each_chunk(|first_row, rows| {
+        renderer::render_rows(&mut image, first_row as u32, rows, &params)
+    })?;
+    let image = renderer::encode_image(&image, params.output.dither).into_raw();
     let image = RgbImage::new(
         &image,
-        data.params.output.width as i32,
-        data.params.output.height as i32,
+        params.output.width as i32,
+        params.output.height as i32,
         ColorDepth::Rgb8,
     )
     .unwrap();
@@ -307,7 +318,7 @@ pub fn run(data: AllData) -> Result<(), String> {
                 state.borrow_mut().clear_cursor();
                 state.borrow_mut().draw(&mut offs.borrow_mut());
                 frame.redraw();
-                state.borrow().set_label(&mut label);
+                state.borrow_mut().set_label(&mut label);
                 true
             }
             _ => {
@@ -317,7 +328,7 @@ pub fn run(data: AllData) -> Result<(), String> {
                     state.borrow_mut().set_cursor(coords.0, coords.1);
                     state.borrow_mut().draw(&mut offs.borrow_mut());
                     frame.redraw();
-                    state.borrow().set_label(&mut label);
+                    state.borrow_mut().set_label(&mut label);
                     true
                 } else {
                     false
@@ -1,11 +1,7 @@
 mod app;
 
-use std::{fs::File, io::Read};
-
+use atm_raytracer::generator::metadata::MetadataReader;
 use clap::{App, Arg, ArgMatches, SubCommand};
-use libflate::gzip::Decoder;
-
-use crate::generator::AllData;
 
 pub const SUBCOMMAND: &str = "view";
 
@@ -14,19 +10,15 @@ pub fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
         .value_of("input")
         .expect("please provide an input file");
 
-    let mut file = File::open(filename).expect("couldn't open the input file");
-    let mut zipped_data = vec![];
-    let _ = file
-        .read_to_end(&mut zipped_data)
-        .expect("couldn't read the file");
-
-    let mut decoder = Decoder::new(&zipped_data[..]).expect("couldn't create the decoder");
-    let mut data = vec![];
-    let _ = decoder
-        .read_to_end(&mut data)
-        .expect("couldn't inflate the data");
-
-    let data: AllData = bincode::deserialize(&data[..]).expect("couldn't deserialize the data");
+    let data = MetadataReader::open(filename)
+        .map_err(|err| format!("couldn't read the metadata file {:?}: {}", filename, err))?;
+    if !data.is_complete() {
+        println!(
+            "Warning: the file is incomplete, only {} of {} rows have been generated so far",
+            data.rows_done(),
+            data.params().output.height
+        );
+    }
 
     app::run(data)?;
 